@@ -5,9 +5,8 @@ const MAX_CONNECTION_ATTEMPTS: usize = 10;
 
 use clipboard::{ClipboardProvider, ClipboardContext};
 
-use std::env::Args;
 
-pub fn download(args: &mut std::iter::Skip<Args>) {
+pub fn download(args: &mut impl Iterator<Item = String>) {
     let mut should_download = true;
     let mut clipboard_string = String::new();
 
@@ -0,0 +1,301 @@
+use std::fmt;
+use std::io::Read;
+use serde_json::{Map, Value};
+
+// Requests to ComfyUI's HTTP API. Anything that goes wrong is returned as a ComfyError, for the command to report.
+
+#[derive(Debug, Clone)]
+pub enum ComfyError {
+    // Couldn't connect at all (ComfyUI isn't running, wrong address/port, ...)
+    Connection { url: String, message: String },
+    // ComfyUI answered with an error status
+    Status { url: String, status: u16, body: String },
+    // ComfyUI refused to queue a prompt
+    Validation { message: String, node_errors: Vec<NodeError> },
+    // ComfyUI answered with something that isn't what we expected
+    Json { url: String, message: String },
+}
+
+// Why ComfyUI refused one node of a prompt
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeError {
+    pub node_id: String,
+    pub class_type: String,
+    pub errors: Vec<NodeErrorDetail>,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeErrorDetail {
+    pub message: String,
+    pub details: String,
+    // The input the error is about, if ComfyUI says
+    pub input_name: Option<String>,
+}
+
+impl ComfyError {
+    // What yara exits with, so scripts can tell what went wrong (1 is left for everything else)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ComfyError::Connection { .. } => 2,
+            ComfyError::Status { .. } => 3,
+            ComfyError::Validation { .. } => 4,
+            ComfyError::Json { .. } => 5,
+        }
+    }
+}
+
+impl fmt::Display for ComfyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ComfyError::Connection { url, message } => write!(f, "couldn't connect to ComfyUI at {url} ({message}). Is ComfyUI running, and is the address/port in the config right?"),
+            ComfyError::Status { url, status, body } => write!(f, "ComfyUI answered {url} with status {status}: {}", body.trim()),
+            ComfyError::Validation { message, node_errors } => {
+                write!(f, "ComfyUI refused the prompt: {message}")?;
+                for node in node_errors {
+                    for error in &node.errors {
+                        write!(f, "\n    node {} ({})", node.node_id, node.class_type)?;
+                        if let Some(input) = &error.input_name {
+                            write!(f, ", input '{input}'")?;
+                        }
+                        write!(f, ": {}", error.message)?;
+                        if !error.details.is_empty() {
+                            write!(f, " - {}", error.details)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            ComfyError::Json { url, message } => write!(f, "couldn't read ComfyUI's answer to {url}: {message}"),
+        }
+    }
+}
+
+// The running and pending prompts
+pub fn get_queue(ip_port: &str) -> Result<Value, ComfyError> {
+    get_json(&(ip_port.to_string() + "queue"))
+}
+
+// One prompt in ComfyUI's queue, which sends each as [number, prompt_id, prompt, extra_data, outputs]
+pub struct QueueEntry {
+    pub number: i64,
+    pub prompt_id: String,
+    pub prompt: Map<String, Value>,
+    // What the prompt was queued with, e.g. extra_pnginfo and client_id
+    pub extra_data: Map<String, Value>,
+}
+
+impl QueueEntry {
+    // The workflow it was queued with, if any (the browser always sends one)
+    pub fn workflow(&self) -> Option<&Value> {
+        self.extra_data.get("extra_pnginfo")?.get("workflow")
+    }
+}
+
+pub struct QueueEntries {
+    pub running: Vec<QueueEntry>,
+    pub pending: Vec<QueueEntry>,
+}
+
+// The running and pending prompts, read into QueueEntry. Anything that isn't a queue entry is a ComfyError::Json.
+pub fn get_queue_entries(ip_port: &str) -> Result<QueueEntries, ComfyError> {
+    let url = ip_port.to_string() + "queue";
+    let json = get_json(&url)?;
+    parse_queue(&url, &json)
+}
+
+fn parse_queue(url: &str, json: &Value) -> Result<QueueEntries, ComfyError> {
+    let error = |message: String| ComfyError::Json { url: url.to_string(), message };
+    let entries = |key: &str| -> Result<Vec<QueueEntry>, ComfyError> {
+        let Some(list) = json.get(key) else { return Ok(Vec::new()); };
+        let list = list.as_array().ok_or_else(|| error(format!("{key} isn't a list")))?;
+        list.iter().map(|entry| {
+            let bad = |what: &str| error(format!("queue entry without {what}: {entry}"));
+            Ok(QueueEntry {
+                number: entry.get(0).and_then(|x| x.as_i64()).ok_or_else(|| bad("a number"))?,
+                prompt_id: entry.get(1).and_then(|x| x.as_str()).ok_or_else(|| bad("a prompt id"))?.to_string(),
+                prompt: entry.get(2).and_then(|x| x.as_object()).ok_or_else(|| bad("a prompt"))?.clone(),
+                extra_data: entry.get(3).and_then(|x| x.as_object()).cloned().unwrap_or_default(),
+            })
+        }).collect()
+    };
+    Ok(QueueEntries { running: entries("queue_running")?, pending: entries("queue_pending")? })
+}
+
+// The history entry of a prompt, or None if it hasn't finished yet
+pub fn get_history_entry(ip_port: &str, prompt_id: &str) -> Result<Option<Value>, ComfyError> {
+    let url = ip_port.to_string() + "history/" + prompt_id;
+    let json = get_json(&url)?;
+    let Value::Object(mut entries) = json else { return Err(ComfyError::Json { url, message: "expected an object".to_string() }); };
+    Ok(entries.remove(prompt_id))
+}
+
+// Every node definition, as JSON text
+pub fn get_object_info(ip_port: &str) -> Result<String, ComfyError> {
+    let url = ip_port.to_string() + "object_info";
+    let (status, body) = request(isahc::get(&url), &url)?;
+    check_status(&url, status, body)
+}
+
+// Queue a prompt (the full /prompt request body), returning its prompt id
+pub fn queue_prompt(ip_port: &str, body: &Value) -> Result<String, ComfyError> {
+    let url = ip_port.to_string() + "prompt";
+    let (status, text) = request(isahc::post(&url, body.to_string()), &url)?;
+    if status == 400 {
+        if let Some(error) = parse_validation_error(&text) {
+            return Err(error);
+        }
+    }
+    let text = check_status(&url, status, text)?;
+    let json = parse_json(&url, &text)?;
+    match json.get("prompt_id").and_then(|x| x.as_str()) {
+        Some(id) => Ok(id.to_string()),
+        None => Err(ComfyError::Json { url, message: format!("no prompt_id in {}", text.trim()) }),
+    }
+}
+
+// Remove pending prompts from the queue, by prompt id
+pub fn delete_from_queue(ip_port: &str, prompt_ids: Vec<String>) -> Result<(), ComfyError> {
+    let url = ip_port.to_string() + "queue";
+    let body = serde_json::json!({ "delete": prompt_ids }).to_string();
+    let (status, text) = request(isahc::post(&url, body), &url)?;
+    check_status(&url, status, text).map(|_| ())
+}
+
+// Stop the running prompt
+pub fn interrupt(ip_port: &str) -> Result<(), ComfyError> {
+    let url = ip_port.to_string() + "interrupt";
+    let (status, text) = request(isahc::post(&url, "x"), &url)?;
+    check_status(&url, status, text).map(|_| ())
+}
+
+fn get_json(url: &str) -> Result<Value, ComfyError> {
+    let (status, text) = request(isahc::get(url), url)?;
+    let text = check_status(url, status, text)?;
+    parse_json(url, &text)
+}
+
+fn request(response: Result<isahc::Response<isahc::Body>, isahc::Error>, url: &str) -> Result<(u16, String), ComfyError> {
+    let mut response = response.map_err(|e| ComfyError::Connection { url: url.to_string(), message: e.to_string() })?;
+    let mut text = String::new();
+    response.body_mut().read_to_string(&mut text).map_err(|e| ComfyError::Connection { url: url.to_string(), message: e.to_string() })?;
+    Ok((response.status().as_u16(), text))
+}
+
+fn check_status(url: &str, status: u16, text: String) -> Result<String, ComfyError> {
+    if (200..300).contains(&status) {
+        Ok(text)
+    } else {
+        Err(ComfyError::Status { url: url.to_string(), status, body: text })
+    }
+}
+
+fn parse_json(url: &str, text: &str) -> Result<Value, ComfyError> {
+    serde_json::from_str(text).map_err(|e| ComfyError::Json { url: url.to_string(), message: e.to_string() })
+}
+
+// ComfyUI's answer when it won't queue a prompt:
+// {"error": {"type", "message", "details", "extra_info"}, "node_errors": {"4": {"errors": [{"message", "details", "extra_info": {"input_name"}}], "class_type"}}}
+// Older versions send the error as a plain string.
+pub fn parse_validation_error(text: &str) -> Option<ComfyError> {
+    let json: Value = serde_json::from_str(text).ok()?;
+    let error = json.get("error")?;
+    let message = match error {
+        Value::String(x) => x.clone(),
+        x => {
+            let message = x.get("message").and_then(|x| x.as_str()).unwrap_or("unknown error").to_string();
+            match x.get("details").and_then(|x| x.as_str()) {
+                Some(details) if !details.is_empty() => format!("{message} ({details})"),
+                _ => message,
+            }
+        }
+    };
+
+    let mut node_errors = Vec::new();
+    if let Some(nodes) = json.get("node_errors").and_then(|x| x.as_object()) {
+        for (node_id, node) in nodes {
+            let errors = node.get("errors").and_then(|x| x.as_array()).map(|errors| errors.iter().map(|error| {
+                let text = |key: &str| error.get(key).and_then(|x| x.as_str()).unwrap_or("").to_string();
+                NodeErrorDetail {
+                    message: text("message"),
+                    details: text("details"),
+                    input_name: error["extra_info"].get("input_name").and_then(|x| x.as_str()).map(|x| x.to_string()),
+                }
+            }).collect()).unwrap_or_default();
+            node_errors.push(NodeError {
+                node_id: node_id.clone(),
+                class_type: node.get("class_type").and_then(|x| x.as_str()).unwrap_or("unknown node").to_string(),
+                errors,
+            });
+        }
+    }
+    // Node ids are numbers, and serde_json sorts them as text
+    node_errors.sort_by_key(|x| (x.node_id.parse::<u64>().unwrap_or(u64::MAX), x.node_id.clone()));
+    Some(ComfyError::Validation { message, node_errors })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_node_errors() {
+        let text = r#"{
+            "error": {"type": "prompt_outputs_failed_validation", "message": "Prompt outputs failed validation", "details": "", "extra_info": {}},
+            "node_errors": {
+                "12": {"errors": [{"type": "value_not_in_list", "message": "Value not in list", "details": "lora_name: 'gone.safetensors' not in []",
+                    "extra_info": {"input_name": "lora_name"}}], "dependent_outputs": ["9"], "class_type": "LoraLoader"},
+                "4": {"errors": [{"type": "value_not_in_list", "message": "Value not in list", "details": "ckpt_name: 'old.safetensors' not in []",
+                    "extra_info": {"input_name": "ckpt_name"}}], "dependent_outputs": ["9"], "class_type": "CheckpointLoaderSimple"}
+            }
+        }"#;
+        let Some(ComfyError::Validation { message, node_errors }) = parse_validation_error(text) else { panic!("not a validation error"); };
+        assert_eq!(message, "Prompt outputs failed validation");
+        assert_eq!(node_errors.iter().map(|x| x.node_id.as_str()).collect::<Vec<_>>(), ["4", "12"]);
+        assert_eq!(node_errors[0].class_type, "CheckpointLoaderSimple");
+        assert_eq!(node_errors[0].errors, [NodeErrorDetail {
+            message: "Value not in list".to_string(),
+            details: "ckpt_name: 'old.safetensors' not in []".to_string(),
+            input_name: Some("ckpt_name".to_string()),
+        }]);
+    }
+
+    #[test]
+    fn reads_queue_entries() {
+        let json = serde_json::json!({
+            "queue_running": [[3, "abc", { "4": { "class_type": "CheckpointLoaderSimple", "inputs": {} } }, { "extra_pnginfo": { "workflow": { "nodes": [] } } }, ["9"]]],
+            "queue_pending": [[5, "def", {}, {}, []], [4, "ghi", {}, { "client_id": "x" }, []]],
+        });
+        let queue = parse_queue("http://127.0.0.1:8188/queue", &json).unwrap();
+        assert_eq!((queue.running[0].number, queue.running[0].prompt_id.as_str()), (3, "abc"));
+        assert!(queue.running[0].prompt.contains_key("4"));
+        assert_eq!(queue.running[0].workflow(), Some(&serde_json::json!({ "nodes": [] })));
+        assert_eq!(queue.pending.iter().map(|x| x.number).collect::<Vec<_>>(), [5, 4]);
+        assert!(queue.pending.iter().all(|x| x.workflow().is_none()));
+
+        // Entries that aren't what ComfyUI sends are an error, not a crash
+        for json in [
+            serde_json::json!({ "queue_running": [], "queue_pending": [["5", "def", {}, {}, []]] }),
+            serde_json::json!({ "queue_running": [[5, 6, {}, {}, []]] }),
+            serde_json::json!({ "queue_running": [[5, "def"]] }),
+            serde_json::json!({ "queue_pending": {} }),
+        ] {
+            assert!(matches!(parse_queue("http://127.0.0.1:8188/queue", &json), Err(ComfyError::Json { .. })));
+        }
+    }
+
+    #[test]
+    fn reads_plain_errors() {
+        let error = parse_validation_error(r#"{"error": "no prompt", "node_errors": []}"#).unwrap();
+        assert_eq!(error.to_string(), "ComfyUI refused the prompt: no prompt");
+        assert_eq!(error.exit_code(), 4);
+        assert!(parse_validation_error("<html>Bad Request</html>").is_none());
+        assert!(parse_validation_error(r#"{"prompt_id": "x"}"#).is_none());
+    }
+
+    #[test]
+    fn reports_unreachable_servers() {
+        // Nothing listens on port 9 (discard) here
+        let error = get_queue("http://127.0.0.1:9/").unwrap_err();
+        assert!(matches!(error, ComfyError::Connection { .. }));
+        assert_eq!(error.exit_code(), 2);
+    }
+}
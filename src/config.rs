@@ -1,7 +1,7 @@
 use std::fs;
 use std::path::PathBuf;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use native_dialog::FileDialog;
 
@@ -22,8 +22,8 @@ pub struct Config {
     /// If no folder is specified, the default path is ComfyUI/output/regen
     regen_directory: Option<PathBuf>,
 
-    /// After generating images through yara (such as with 'yara load'), the workflow data is not automatically embedded into the image by ComfyUI.
-    /// Yara needs to actively track ComfyUI output and manually embed the workflow data. 
+    /// After generating images through yara (such as with 'yara load'), ComfyUI embeds the workflow data into the image itself.
+    /// If it doesn't (e.g. an older ComfyUI version, or a custom save node), yara tracks ComfyUI output and embeds the workflow data manually.
     /// If Yara is closed or crashes during this process, the workflow will not be added. You can try adding workflows post-generation with 'yara fix'.
     /// By default, yara only checks the ComfyUI output folder for images missing workflows. You may add additional folders to be checked here. 
     workflow_recovery_directories: Option<Vec<PathBuf>>,
@@ -31,6 +31,11 @@ pub struct Config {
     pub comfyui_port: Option<String>,
     pub comfyui_address: Option<String>,
 
+    /// Other ComfyUI servers (e.g. on other machines), by name. Use one with '--server NAME' on any command.
+    /// Each can have its own "address", "port", "output_directory" and "input_directory" - anything left out is taken from the settings above.
+    /// Without '--server', yara uses the settings above.
+    servers: Option<BTreeMap<String, ServerProfile>>,
+
     /// The default window position for 'yara preview'. For multiple monitors, you can include negative coordinates/numbers to move to the left.
     pub default_window_position: (i32, i32),
 
@@ -53,8 +58,36 @@ pub struct Config {
     /// The framerate cap. A higher cap increases CPU usage. Framerate should only be relevant when you are moving or resizing the window.
     /// I keep this low (default is 6) to minimize CPU usage, since I'm rarely moving or resizing the window.
     pub framerate_cap: u8,
+
+    /// Set to true to show the model, loras, seed and start of the prompt of each image over 'yara preview'. Press I in the window to toggle it.
+    pub preview_overlay: Option<bool>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerProfile {
+    address: Option<String>,
+    port: Option<String>,
+    output_directory: Option<PathBuf>,
+    input_directory: Option<PathBuf>,
+}
+
+// A ComfyUI server, with its profile resolved against the defaults
+#[derive(Debug, Clone)]
+pub struct Server {
+    // None for the default server
+    pub name: Option<String>,
+    pub ip_port: String,
+    pub output_directory: PathBuf,
+    pub input_directory: PathBuf,
+}
+impl Server {
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{name} ({})", self.ip_port),
+            None => format!("default ({})", self.ip_port),
+        }
+    }
+}
 
 impl Config {
     pub fn get_input_dir(&mut self) -> PathBuf {
@@ -88,31 +121,82 @@ impl Config {
         }
     }
     pub fn get_ip_port(&self) -> String {
-        let mut ip_port = "http://".to_string();
-        match &self.comfyui_address {
-            Some(x) => { ip_port += &x; }
-            None => { ip_port += &"localhost"; }
+        let ip_port = resolve_ip_port(&self.comfyui_address, &self.comfyui_port);
+        if (None != self.comfyui_address) || (None != self.comfyui_port) {
+            // (on stderr, so it doesn't get mixed into output meant for scripts, like 'yara image --json')
+            eprintln!("Using non-default address/port for ComfyUI: {ip_port}");
         }
-        ip_port += &":";
-        match &self.comfyui_port {
-            Some(x) => { ip_port += &x; }
-            None => {
-                // It seems ComfyUI has different default ports depending on whether you're using the portable version or not?
-                // Github lists it as 8188, which it normally has been, but the wiki and a user had their default port at 8000
-                // So if the user has no custom port, test 8188 and switch to 8000 if 8188 fails
-                if isahc::get(ip_port.clone() + &"8188/queue").is_ok() {
-                    ip_port += &"8188"
-                } else {
-                    ip_port += &"8000"
-                }
+        ip_port
+    }
+    // The server named by '--server', or the default one without it
+    pub fn get_server(&mut self, name: Option<&str>) -> Option<Server> {
+        let Some(name) = name else {
+            return Some(Server {
+                name: None,
+                ip_port: self.get_ip_port(),
+                output_directory: self.comfyui_output_directory.clone(),
+                input_directory: self.get_input_dir(),
+            });
+        };
+        let Some(profile) = self.servers.as_ref().and_then(|x| x.get(name)).cloned() else {
+            let names: Vec<&String> = self.servers.iter().flat_map(|x| x.keys()).collect();
+            println!("\x1b[31merror\x1b[0m:// there's no server named '{name}' in the config (servers: {names:?}).");
+            return None;
+        };
+        let address = profile.address.or(self.comfyui_address.clone());
+        let port = profile.port.or(self.comfyui_port.clone());
+        let ip_port = resolve_ip_port(&address, &port);
+        eprintln!("Using ComfyUI server '{name}': {ip_port}");
+
+        // Without its own input folder, it's next to the server's output folder
+        let input_directory = match (profile.input_directory, &profile.output_directory) {
+            (Some(x), _) => x,
+            (None, Some(output)) => derive_input_path_from_output_path(output),
+            (None, None) => self.get_input_dir(),
+        };
+        Some(Server {
+            name: Some(name.to_string()),
+            ip_port,
+            output_directory: profile.output_directory.unwrap_or(self.comfyui_output_directory.clone()),
+            input_directory,
+        })
+    }
+    // The default server and every named one. Profiles pointing at the same server are only listed once.
+    pub fn get_all_servers(&mut self) -> Vec<Server> {
+        let mut servers = vec![self.get_server(None).unwrap()];
+        let names: Vec<String> = self.servers.iter().flat_map(|x| x.keys().cloned()).collect();
+        for name in names {
+            let server = self.get_server(Some(&name)).unwrap();
+            if servers.iter().all(|x| x.ip_port != server.ip_port) {
+                servers.push(server);
             }
         }
-        ip_port += &"/";
-        if (None != self.comfyui_address) || (None != self.comfyui_port) {
-            println!("Using non-default address/port for ComfyUI: {ip_port}");
+        servers
+    }
+}
+
+fn resolve_ip_port(address: &Option<String>, port: &Option<String>) -> String {
+    let mut ip_port = "http://".to_string();
+    match address {
+        Some(x) => { ip_port += x; }
+        None => { ip_port += "localhost"; }
+    }
+    ip_port += ":";
+    match port {
+        Some(x) => { ip_port += x; }
+        None => {
+            // It seems ComfyUI has different default ports depending on whether you're using the portable version or not?
+            // Github lists it as 8188, which it normally has been, but the wiki and a user had their default port at 8000
+            // So if the user has no custom port, test 8188 and switch to 8000 if 8188 fails
+            if isahc::get(ip_port.clone() + "8188/queue").is_ok() {
+                ip_port += "8188"
+            } else {
+                ip_port += "8000"
+            }
         }
-        ip_port
     }
+    ip_port += "/";
+    ip_port
 }
 
 
@@ -146,6 +230,7 @@ pub fn create_new_config() {
         workflow_recovery_directories: Some(workflow_recovery_directories),
         comfyui_port: None,
         comfyui_address: None,
+        servers: None,
         default_window_position: (0, 0),
         default_window_size: (750, 750),
         base_image: None,
@@ -153,6 +238,7 @@ pub fn create_new_config() {
         mouse_passthrough: true,
         always_on_top: true,
         framerate_cap: 6,
+        preview_overlay: Some(false),
     };
     match fs::create_dir(config_root.clone()) {
         Ok(_) => println!("    Created 'yara' directory in {}", config_root),
@@ -177,26 +263,3 @@ fn derive_regen_path_from_output_path(comfyui_output_path: &PathBuf) -> PathBuf
     regen_path.push("regen");
     regen_path 
 }
-
-
-
-
-
-
-
-
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct WorkflowStorage {
-    pub workflows: HashMap<String, serde_json::Value>,
-}
-
-pub fn create_new_workflow_storage() {
-    let mut workflow_storage_root = get_appdata();
-    workflow_storage_root += "/yara";
-
-    let workflows = WorkflowStorage {
-        workflows: HashMap::new(),
-    };
-    fs::write(workflow_storage_root + &"/workflow_storage.json", serde_json::to_string_pretty(&workflows).unwrap()).unwrap();
-}
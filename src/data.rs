@@ -1,7 +1,10 @@
-use std::io::Read;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 use serde_json::Value;
 use serde::{Serialize, Deserialize};
 
+use crate::comfyui::{self, ComfyError};
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct YaraPrompt {
@@ -22,33 +25,43 @@ impl YaraPrompt {
             ip_port: ip_port.to_string(),
         }
     }
-    pub fn generate(&self) -> String {
-        let prompt_string = serde_json::to_string(&self.prompt).unwrap();
-
-        let mut response = isahc::post(self.ip_port.clone() + &"prompt", prompt_string).unwrap();
-        let mut buf = String::new();
-        response.body_mut().read_to_string(&mut buf).unwrap();
-        if !(response.status().is_informational() || response.status().is_success()) {
-            println!("\nCritical error when communicating with ComfyUI's server");
-            println!("Could be that ComfyUI isn't running, the config has incorrect address/ports, does not have necessary custom nodes, etc.");
-            // I could handle this so that it keeps attempting to generate, but I'm OK with it just crashing
-            println!("{response:#?}\n{buf}");
+    // Queue the prompt on the given server (not necessarily the one it was saved from), returning its prompt id.
+    // With `front`, ComfyUI puts the prompt at the front of its queue instead of the back
+    pub fn generate(&self, ip_port: &str, front: bool) -> Result<String, ComfyError> {
+        // Send the workflow along as extra_pnginfo, so ComfyUI embeds it into the image itself (like it does when queueing from the browser).
+        // The client_id is the one this process listens with, so ComfyUI sends the prompt's progress to us and nobody else.
+        let mut body = self.prompt.clone();
+        body.insert("client_id".to_string(), Value::String(client_id().to_string()));
+        body.insert("extra_data".to_string(), serde_json::json!({ "extra_pnginfo": { "workflow": self.workflow } }));
+        if front {
+            body.insert("front".to_string(), Value::Bool(true));
         }
 
-        let json: Value = serde_json::from_str(&buf).unwrap();
-        let id = json.as_object().unwrap().get("prompt_id").unwrap().as_str().unwrap();
+        let id = comfyui::queue_prompt(ip_port, &Value::Object(body))?;
         println!("// Generating prompt // {id}");
-        id.to_string()
+        Ok(id)
     }
 }
 
-
+// ComfyUI tells websocket connections apart by their client_id (the browser uses a random UUID), and only keeps one per ID.
+// One ID per yara process; it doesn't need to be cryptographically random, just unlikely to collide.
+pub fn client_id() -> &'static str {
+    static CLIENT_ID: OnceLock<String> = OnceLock::new();
+    CLIENT_ID.get_or_init(|| {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&std::process::id().to_be_bytes());
+        hasher.update(&SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_be_bytes());
+        let hex = hasher.finalize().to_hex();
+        format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+    })
+}
 
 
 
 #[derive(Debug)]
 struct Node {
-    id: u64,
+    // Node ids are normally numbers, but nodes inside group nodes have ids like "5:0"
+    id: String,
     contents: Value,
 }
 
@@ -71,12 +84,17 @@ impl Node {
     // Note that we can't just hash it directly, because it seems ComfyUI randomly
     // changes parts of the prompt (e.g. node ordering, or turning a float into an integer)
     fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::from(self.id.to_be_bytes());
+        let mut bytes: Vec<u8> = match self.id.parse::<u64>() {
+            Ok(id) => Vec::from(id.to_be_bytes()),
+            Err(_) => Vec::from(self.id.as_bytes()),
+        };
 
-        let class_type = self.contents.get("class_type").unwrap().as_str().unwrap();
+        // Anything that isn't a proper node (e.g. from an image made by something other than ComfyUI) is hashed as empty, rather than failing
+        let class_type = self.contents.get("class_type").and_then(|x| x.as_str()).unwrap_or("");
         bytes.extend(class_type.as_bytes());
 
-        let inputs = self.contents.get("inputs").unwrap().as_object().unwrap();
+        let empty = serde_json::Map::new();
+        let inputs = self.contents.get("inputs").and_then(|x| x.as_object()).unwrap_or(&empty);
         let mut keys: Vec<String> = inputs.keys().map(|x| x.to_string()).collect(); // sort this alphabetically
         keys.sort();
         for key in keys {
@@ -91,13 +109,14 @@ impl Node {
 pub fn hash_nodemap(nodemap: &serde_json::Map<String, Value>) -> String {
 
     let mut nodes: Vec<Node> = Vec::new();
-    for id in nodemap.keys() {
+    for (id, contents) in nodemap {
         nodes.push(Node {
-            id: id.parse::<u64>().unwrap(),
-            contents: nodemap.get(id).unwrap().clone(),
+            id: id.clone(),
+            contents: contents.clone(),
         });
     }
-    nodes.sort_by(|a, b| a.id.partial_cmp(&b.id).unwrap()); 
+    // By number, with any ids that aren't numbers after them
+    nodes.sort_by_key(|x| (x.id.parse::<u64>().ok().unwrap_or(u64::MAX), x.id.clone()));
 
     let mut hasher = blake3::Hasher::new();
     for node in nodes {
@@ -0,0 +1,207 @@
+use serde_json::{Value, Map};
+
+// The differences between two API prompts, for 'yara diff'. Nodes are matched up by id; a node whose class_type changed
+// is reported as replaced rather than compared input by input, as its inputs mean something else.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added { id: String, label: String },
+    Removed { id: String, label: String },
+    Replaced { id: String, old_label: String, new_label: String },
+    Input { id: String, label: String, input: String, old: Option<Value>, new: Option<Value> },
+}
+
+// In node id order, and input name order within a node
+pub fn diff_prompts(old: &Map<String, Value>, new: &Map<String, Value>) -> Vec<Change> {
+    let mut ids: Vec<&String> = old.keys().chain(new.keys()).collect();
+    ids.sort_by_key(|x| (x.parse::<u64>().unwrap_or(u64::MAX), x.to_string()));
+    ids.dedup();
+
+    let mut changes = Vec::new();
+    for id in ids {
+        let (old_node, new_node) = match (old.get(id), new.get(id)) {
+            (Some(old_node), Some(new_node)) => (old_node, new_node),
+            (Some(node), None) => { changes.push(Change::Removed { id: id.clone(), label: node_label(node) }); continue; }
+            (None, Some(node)) => { changes.push(Change::Added { id: id.clone(), label: node_label(node) }); continue; }
+            (None, None) => continue,
+        };
+        if old_node["class_type"] != new_node["class_type"] {
+            changes.push(Change::Replaced { id: id.clone(), old_label: node_label(old_node), new_label: node_label(new_node) });
+            continue;
+        }
+        let empty = Map::new();
+        let old_inputs = old_node["inputs"].as_object().unwrap_or(&empty);
+        let new_inputs = new_node["inputs"].as_object().unwrap_or(&empty);
+        let mut names: Vec<&String> = old_inputs.keys().chain(new_inputs.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let (old_value, new_value) = (old_inputs.get(name), new_inputs.get(name));
+            if old_value != new_value {
+                changes.push(Change::Input {
+                    id: id.clone(),
+                    label: node_label(new_node),
+                    input: name.clone(),
+                    old: old_value.cloned(),
+                    new: new_value.cloned(),
+                });
+            }
+        }
+    }
+    changes
+}
+
+// "LoraLoader", or "LoraLoader detail.safetensors" for nodes that load a file
+fn node_label(node: &Value) -> String {
+    let class_type = node["class_type"].as_str().unwrap_or("unknown node");
+    let file = ["ckpt_name", "lora_name", "unet_name", "vae_name", "control_net_name"].iter()
+        .find_map(|x| node["inputs"].get(*x).and_then(|x| x.as_str()));
+    match file {
+        Some(file) => format!("{class_type} {file}"),
+        None => class_type.to_string(),
+    }
+}
+
+pub fn print_changes(changes: &[Change]) {
+    if changes.is_empty() {
+        println!("No differences.");
+        return;
+    }
+    let mut last_node: Option<&String> = None;
+    for change in changes {
+        match change {
+            Change::Added { id, label } => println!("\x1b[32m+ node {id}\x1b[0m ({label})"),
+            Change::Removed { id, label } => println!("\x1b[31m- node {id}\x1b[0m ({label})"),
+            Change::Replaced { id, old_label, new_label } => println!("\x1b[33m~ node {id}\x1b[0m ({old_label} -> {new_label})"),
+            Change::Input { id, label, input, old, new } => {
+                if last_node != Some(id) {
+                    println!("\x1b[33m~ node {id}\x1b[0m ({label})");
+                }
+                match (old, new) {
+                    (Some(Value::String(old)), Some(Value::String(new))) if old.contains(' ') || new.contains(' ') => {
+                        println!("    {input}: {}", word_diff(old, new));
+                    }
+                    _ => println!("    {input}: \x1b[31m{}\x1b[0m -> \x1b[32m{}\x1b[0m", format_value(old.as_ref()), format_value(new.as_ref())),
+                }
+            }
+        }
+        last_node = match change {
+            Change::Input { id, .. } => Some(id),
+            _ => None,
+        };
+    }
+}
+
+fn format_value(value: Option<&Value>) -> String {
+    match value {
+        None => "(none)".to_string(),
+        // Links are [node id, output slot]
+        Some(Value::Array(x)) if x.len() == 2 && x[1].is_u64() && (x[0].is_string() || x[0].is_u64()) => {
+            format!("node {}", x[0].as_str().map(|x| x.to_string()).unwrap_or(x[0].to_string()))
+        }
+        Some(Value::String(x)) => format!("\"{x}\""),
+        Some(x) => x.to_string(),
+    }
+}
+
+// The words removed from a text in red, and those added in green. Unchanged words are kept, so the change reads in place.
+pub fn word_diff(old: &str, new: &str) -> String {
+    let mut out: Vec<String> = Vec::new();
+    for (kind, word) in diff_words(old, new) {
+        out.push(match kind {
+            WordChange::Same => word.to_string(),
+            WordChange::Removed => format!("\x1b[31m[-{word}-]\x1b[0m"),
+            WordChange::Added => format!("\x1b[32m{{+{word}+}}\x1b[0m"),
+        });
+    }
+    out.join(" ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WordChange {
+    Same,
+    Removed,
+    Added,
+}
+
+// The longest common subsequence of the two texts' words, walked from the start
+fn diff_words<'a>(old: &'a str, new: &'a str) -> Vec<(WordChange, &'a str)> {
+    let old: Vec<&str> = old.split_whitespace().collect();
+    let new: Vec<&str> = new.split_whitespace().collect();
+    // lengths[i][j]: the LCS of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] { lengths[i + 1][j + 1] + 1 } else { lengths[i + 1][j].max(lengths[i][j + 1]) };
+        }
+    }
+    let mut words = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            words.push((WordChange::Same, old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            // (removed words first, so a replaced word reads as "[-old-] {+new+}")
+            words.push((WordChange::Removed, old[i]));
+            i += 1;
+        } else {
+            words.push((WordChange::Added, new[j]));
+            j += 1;
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(json: Value) -> Map<String, Value> {
+        json.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn finds_changed_inputs() {
+        let old = nodes(serde_json::json!({
+            "3": {"class_type": "KSampler", "inputs": {"seed": 1, "steps": 20, "model": ["10", 0], "positive": ["6", 0]}},
+            "10": {"class_type": "LoraLoader", "inputs": {"lora_name": "detail.safetensors", "strength_model": 0.8, "model": ["4", 0]}},
+            "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a cat on a chair"}},
+            "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "blurry"}},
+            "11": {"class_type": "VAELoader", "inputs": {"vae_name": "a.safetensors"}}
+        }));
+        let new = nodes(serde_json::json!({
+            "3": {"class_type": "KSampler", "inputs": {"seed": 2, "steps": 20, "model": ["10", 0], "positive": ["6", 0], "denoise": 0.5}},
+            "10": {"class_type": "LoraLoader", "inputs": {"lora_name": "detail.safetensors", "strength_model": 0.6, "model": ["4", 0]}},
+            "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a black cat on a chair"}},
+            "7": {"class_type": "PromptText", "inputs": {"text": "blurry"}},
+            "12": {"class_type": "UpscaleModelLoader", "inputs": {"model_name": "4x.pth"}}
+        }));
+        let input = |id: &str, label: &str, input: &str, old: Option<Value>, new: Option<Value>| Change::Input {
+            id: id.to_string(), label: label.to_string(), input: input.to_string(), old, new,
+        };
+        assert_eq!(diff_prompts(&old, &new), [
+            input("3", "KSampler", "denoise", None, Some(serde_json::json!(0.5))),
+            input("3", "KSampler", "seed", Some(serde_json::json!(1)), Some(serde_json::json!(2))),
+            input("6", "CLIPTextEncode", "text", Some(serde_json::json!("a cat on a chair")), Some(serde_json::json!("a black cat on a chair"))),
+            Change::Replaced { id: "7".to_string(), old_label: "CLIPTextEncode".to_string(), new_label: "PromptText".to_string() },
+            input("10", "LoraLoader detail.safetensors", "strength_model", Some(serde_json::json!(0.8)), Some(serde_json::json!(0.6))),
+            Change::Removed { id: "11".to_string(), label: "VAELoader a.safetensors".to_string() },
+            Change::Added { id: "12".to_string(), label: "UpscaleModelLoader".to_string() },
+        ]);
+        assert!(diff_prompts(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn diffs_words() {
+        let words = diff_words("a cat on a red chair", "a black cat on a chair, night");
+        assert_eq!(words, [
+            (WordChange::Same, "a"), (WordChange::Added, "black"), (WordChange::Same, "cat"), (WordChange::Same, "on"), (WordChange::Same, "a"),
+            (WordChange::Removed, "red"), (WordChange::Removed, "chair"), (WordChange::Added, "chair,"), (WordChange::Added, "night"),
+        ]);
+        assert_eq!(word_diff("same words", "same words"), "same words");
+        assert_eq!(format_value(Some(&serde_json::json!(["4", 0]))), "node 4");
+        assert_eq!(format_value(None), "(none)");
+    }
+}
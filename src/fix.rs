@@ -1,52 +1,125 @@
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::io::{BufReader, Read, Write};
+use std::io::Write;
 use serde_json::Value;
-use std::collections::HashMap;
-use std::time::Instant;
-
-use crate::{WorkflowStorage, count_queue, get_queue, path_is_png_file};
-use crate::data::{YaraPrompt, hash_nodemap};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::{count_queue, path_is_image_file};
+use crate::storage::{WorkflowStorage, StorageEntry, format_age};
+use crate::config::Server;
+use crate::comfyui::{self, ComfyError};
+use crate::data::{YaraPrompt, hash_nodemap, client_id};
+use crate::websocket::{EventClient, Event};
 use crate::{STATUS, format_seconds};
+use crate::metadata;
 
-const API_DATA_MARKER: [u8; 10] = [116, 69, 88, 116, 112, 114, 111, 109, 112, 116]; // "tEXtprompt"
 
+fn save_hash_and_workflow(x: &YaraPrompt, queue: Option<&str>, storage: &WorkflowStorage) {
+    if let Err(e) = storage.insert(&x.hash, &x.workflow, queue) {
+        println!("\x1b[33mwarning\x1b[0m:// couldn't store the workflow of prompt {}, 'yara fix' won't be able to recover it: {e}", x.hash);
+    }
+}
 
-fn save_hash_and_workflow(x: &YaraPrompt, workflow_file: &str, storage: &mut WorkflowStorage) {
-    storage.workflows.insert(x.hash.clone(), x.workflow.clone());
-    fs::write(workflow_file, serde_json::to_string_pretty(&storage).unwrap()).unwrap();
+fn remove_workflow_from_storage(hash: &str, storage: &WorkflowStorage) {
+    if let Err(e) = storage.remove(hash) {
+        println!("\x1b[33mwarning\x1b[0m:// couldn't remove stored workflow {hash}: {e}");
+    }
 }
 
-fn remove_workflow_from_storage(hash: &str, workflow_file: &str, storage: &mut WorkflowStorage) {
-    storage.workflows.remove(hash);
-    fs::write(workflow_file, serde_json::to_string_pretty(&storage).unwrap()).unwrap();
+// What 'yara fix' found, for the summary at the end
+#[derive(Default, Debug)]
+pub struct FixSummary {
+    // Images with a stored workflow, and that workflow's hash
+    pub matched: Vec<(PathBuf, String)>,
+    // Images without one. The flag is whether the image is missing a workflow at all.
+    pub unmatched: Vec<(PathBuf, bool)>,
+    pub unreadable: Vec<(PathBuf, String)>,
 }
 
-pub fn fix_workflows_in_folders(mut storage: &mut WorkflowStorage, workflow_file: &str, dirs: Vec<PathBuf>) {
-    for dir in dirs {
-        for entry in std::fs::read_dir(dir).unwrap() {
-            let path = entry.unwrap().path();
-            if path_is_png_file(path.as_path()) {
-                if let Ok(hash) = get_api_hash_from_image_file(path.as_path()) {
-                    if let Some(workflow) = storage.workflows.get(&hash) {
-                        inject_workflow_into_image(&path, &workflow);
-                        remove_workflow_from_storage(&hash, workflow_file, &mut storage);
-                        println!("       Workflow has been embedded into image {:?}", path.file_name().unwrap());
-                    }
-                } else { println!("Failed to get api info and hash from file: {:?}", path.file_name().unwrap()); }
+// Embed stored workflows into the given images, and the images in the given folders (and their subfolders, with `recursive`).
+// With `dry_run`, only report what would be embedded where.
+pub fn fix_workflows(storage: &WorkflowStorage, paths: Vec<PathBuf>, recursive: bool, dry_run: bool) -> FixSummary {
+    let mut summary = FixSummary::default();
+    let mut images: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            find_image_files(&path, recursive, &mut images, &mut summary.unreadable);
+        } else if path_is_image_file(&path) {
+            images.push(path);
+        } else {
+            summary.unreadable.push((path, "not a PNG, JPEG or WebP image".to_string()));
+        }
+    }
+
+    // Workflows that couldn't be embedded into one of their images, which must stay stored
+    let mut failed: HashSet<String> = HashSet::new();
+    for path in images {
+        let hash = match get_api_hash_from_image_file(&path) {
+            Ok(x) => x,
+            Err(e) => { summary.unreadable.push((path, e.to_string())); continue; }
+        };
+        let Some(stored) = storage.get(&hash) else {
+            let missing_workflow = !image_has_workflow(&path);
+            summary.unmatched.push((path, missing_workflow));
+            continue;
+        };
+        if dry_run {
+            let queue = stored.queue.map(|x| format!(", from '{x}'")).unwrap_or_default();
+            println!("       Would embed stored workflow {hash} ({} old{queue}) into {}", format_age(stored.created), path.display());
+        } else {
+            if let Err(e) = inject_workflow_into_image(&path, &stored.workflow) {
+                summary.unreadable.push((path, format!("couldn't write the workflow: {e}")));
+                failed.insert(hash);
+                continue;
             }
+            println!("       Workflow has been embedded into image {}", path.display());
         }
+        summary.matched.push((path, hash));
     }
+
+    // Several images can share a workflow (batches, several save nodes), so only release them once they've all been done
+    if !dry_run {
+        let hashes: HashSet<&String> = summary.matched.iter().map(|x| &x.1).filter(|x| !failed.contains(*x)).collect();
+        for hash in hashes {
+            remove_workflow_from_storage(hash, storage);
+        }
+    }
+    summary
 }
 
+impl FixSummary {
+    pub fn print(&self, dry_run: bool) {
+        let missing: Vec<&PathBuf> = self.unmatched.iter().filter(|x| x.1).map(|x| &x.0).collect();
+        println!("\n\x1b[36mSummary:\x1b[0m");
+        println!("  {} images {} a stored workflow", self.matched.len(), if dry_run { "would get" } else { "got" });
+        println!("  {} images have no stored workflow ({} of them don't have a workflow at all)", self.unmatched.len(), missing.len());
+        for path in missing {
+            println!("      {}", path.display());
+        }
+        println!("  {} files couldn't be read", self.unreadable.len());
+        for (path, reason) in &self.unreadable {
+            println!("      {}: {reason}", path.display());
+        }
+    }
+}
 
-pub fn fix_workflow_in_file(mut storage: &mut WorkflowStorage, workflow_file: &str, path: PathBuf) {
-    if path_is_png_file(path.as_path()) {
-        let hash = get_api_hash_from_image_file(path.as_path()).unwrap();
-        if let Some(workflow) = storage.workflows.get(&hash) {
-            inject_workflow_into_image(&path, &workflow);
-            remove_workflow_from_storage(&hash, workflow_file, &mut storage);
-            println!("       Workflow has been embedded into image {:?}", path.file_name().unwrap());
+// Adds every image (PNG, JPEG or WebP) in a folder (and its subfolders, with `recursive`) to `images`
+pub fn find_image_files(dir: &Path, recursive: bool, images: &mut Vec<PathBuf>, unreadable: &mut Vec<(PathBuf, String)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) => { unreadable.push((dir.to_path_buf(), e.to_string())); return; }
+    };
+    let mut paths: Vec<(PathBuf, bool)> = entries.flatten().map(|x| (x.path(), x.file_type().is_ok_and(|x| x.is_dir()))).collect();
+    paths.sort();
+    for (path, is_dir) in paths {
+        // (symlinked folders aren't followed, so there's no going round in circles)
+        if is_dir {
+            if recursive {
+                find_image_files(&path, recursive, images, unreadable);
+            }
+        } else if path_is_image_file(&path) {
+            images.push(path);
         }
     }
 }
@@ -54,193 +127,735 @@ pub fn fix_workflow_in_file(mut storage: &mut WorkflowStorage, workflow_file: &s
 
 
 
+// Stored workflows that 'yara fix' has no use for: no image in the folders (or their subfolders) is missing them. Either the
+// image was never generated (or hasn't been yet), it was deleted or moved, or it already has its workflow.
+pub fn find_orphaned_workflows(storage: &WorkflowStorage, dirs: &[PathBuf]) -> Vec<StorageEntry> {
+    let mut images = Vec::new();
+    let mut unreadable = Vec::new();
+    for dir in dirs {
+        find_image_files(dir, true, &mut images, &mut unreadable);
+    }
+    for (path, reason) in unreadable {
+        println!("\x1b[33mwarning\x1b[0m:// couldn't read {}: {reason}", path.display());
+    }
+    let needed: HashSet<String> = images.iter()
+        .filter(|x| !image_has_workflow(x))
+        .filter_map(|x| get_api_hash_from_image_file(x).ok())
+        .collect();
+    storage.entries().into_iter().filter(|x| !needed.contains(&x.hash)).collect()
+}
+
 #[derive(PartialEq)]
-enum PIDStatus {
-    Existing,
+pub enum PIDStatus {
     Queued,
     Finished,
+    // Its server stopped answering, so it's no longer waited on
+    Unfinished,
 }
 
 pub fn generate_yara_prompts(
     yara_prompts: Vec<YaraPrompt>,
-    mut storage: &mut WorkflowStorage,
-    workflow_file: &str,
-    comfyui_output_directory: PathBuf,
-    ip_port: String,
-) {
-    // get and store history
-    let mut stored_history = get_history(&ip_port);
+    storage: &WorkflowStorage,
+    server: &Server,
+) -> Vec<FailedPrompt> {
+    let mut queued = vec![queue_yara_prompts(&yara_prompts, storage, None, server, false)];
+    let failed = take_failed_prompts(&mut queued);
+    wait_for_yara_prompts(queued, storage);
+    failed
+}
+
+// Prompts that have been sent to one ComfyUI server, which yara still needs to wait on
+pub struct QueuedPrompts {
+    server: Server,
+    events: Option<EventClient>,
+    prompt_ids: HashMap<String, PIDStatus>,
+    // The hash of each prompt (by prompt id), to find its stored workflow
+    hashes: HashMap<String, String>,
+    // Prompts in the server's queue (ours and anyone else's)
+    count: usize,
+    failed: Vec<FailedPrompt>,
+}
+
+// A prompt ComfyUI refused to queue (e.g. it uses a missing custom node or model), or that was never sent because the server
+// stopped working. The rest of the batch is still queued.
+pub struct FailedPrompt {
+    pub prompt: YaraPrompt,
+    pub error: ComfyError,
+}
+
+pub fn take_failed_prompts(queued: &mut [QueuedPrompts]) -> Vec<FailedPrompt> {
+    queued.iter_mut().flat_map(|x| x.failed.drain(..)).collect()
+}
+
+// `queue` is the saved queue the prompts came from, if any (it's kept with their stored workflows).
+// With `front`, the prompts go ahead of everything already in ComfyUI's queue (in the same order as given).
+// Prompts that don't make it into the queue are in the result's failed prompts, and the ones that did are still waited on.
+pub fn queue_yara_prompts(yara_prompts: &[YaraPrompt], storage: &WorkflowStorage, queue: Option<&str>, server: &Server, front: bool) -> QueuedPrompts {
+    // ComfyUI embeds the workflow itself, since it's sent along with the prompt (see YaraPrompt::generate).
+    // The stored copy is only a fallback, for older ComfyUI versions or save nodes that ignore extra_pnginfo - 'yara fix' can recover from it.
+    for yara_prompt in yara_prompts {
+        save_hash_and_workflow(yara_prompt, queue, storage);
+    }
+
+    // Connect before queueing anything, so we can't miss messages about our prompts
+    let events = match EventClient::connect(&server.ip_port, client_id()) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            println!("\x1b[33mwarning\x1b[0m:// couldn't connect to ComfyUI's websocket ({e}), polling for finished prompts instead.");
+            None
+        }
+    };
+
+    println!("Sending prompts to ComfyUI for generation...");
     let mut prompt_ids: HashMap<String, PIDStatus> = HashMap::new();
-    for id in stored_history.as_object().unwrap().keys() {
-        prompt_ids.insert(id.to_string(), PIDStatus::Existing);
+    let mut hashes: HashMap<String, String> = HashMap::new();
+    let mut failed: Vec<FailedPrompt> = Vec::new();
+    // Once ComfyUI itself is unusable, the rest aren't sent
+    let mut stopped: Option<ComfyError> = None;
+    // Each prompt sent to the front goes ahead of the last one, so send them backwards to keep their order
+    let ordered: Box<dyn Iterator<Item = &YaraPrompt>> = if front { Box::new(yara_prompts.iter().rev()) } else { Box::new(yara_prompts.iter()) };
+    for yara_prompt in ordered {
+        let result = match &stopped {
+            Some(error) => Err(error.clone()),
+            None => yara_prompt.generate(&server.ip_port, front),
+        };
+        match result {
+            Ok(id) => {
+                hashes.insert(id.clone(), yara_prompt.hash.clone());
+                prompt_ids.insert(id, PIDStatus::Queued);
+                continue;
+            }
+            // Only this prompt is wrong, so carry on with the rest. Anything else means ComfyUI itself is unusable.
+            Err(error @ ComfyError::Validation { .. }) => {
+                println!("\x1b[31merror\x1b[0m:// {error}\n    Skipping this prompt.");
+                failed.push(FailedPrompt { prompt: yara_prompt.clone(), error });
+            }
+            Err(error) => {
+                if stopped.is_none() {
+                    println!("\x1b[31merror\x1b[0m:// {error}\n    Not sending the rest of the prompts to {}.", server.label());
+                    stopped = Some(error.clone());
+                }
+                failed.push(FailedPrompt { prompt: yara_prompt.clone(), error });
+            }
+        }
+        remove_workflow_from_storage(&yara_prompt.hash, storage);
     }
+    if front {
+        failed.reverse();
+    }
+    println!("Prompts have been sent to ComfyUI.\n");
+    // (only shown while waiting, which asks again)
+    let count = comfyui::get_queue(&server.ip_port).map(count_queue).unwrap_or(0);
+    QueuedPrompts { server: server.clone(), events, prompt_ids, hashes, count, failed }
+}
 
-    for yara_prompt in &yara_prompts {
-        save_hash_and_workflow(yara_prompt, &workflow_file, &mut storage);
+// Which server each prompt goes to: whichever has the shortest queue, counting the prompts already given to it.
+// Ties go to the server listed first.
+pub fn spread_by_queue_depth(depths: &[usize], prompt_count: usize) -> Vec<usize> {
+    let mut depths = depths.to_vec();
+    let mut assigned = Vec::new();
+    for _ in 0..prompt_count {
+        let Some((i, _)) = depths.iter().enumerate().min_by_key(|x| *x.1) else { break; };
+        depths[i] += 1;
+        assigned.push(i);
     }
+    assigned
+}
 
-    println!("Sending prompts to ComfyUI for generation...");
-    for yara_prompt in &yara_prompts {
-        let id = yara_prompt.generate();
-        prompt_ids.insert(id, PIDStatus::Queued);
+// Spread the prompts across several servers by queue depth (keeping their order on each server).
+// A server that isn't answering is left out; it's only an error if none of them are.
+pub fn queue_yara_prompts_on_servers(yara_prompts: &[YaraPrompt], storage: &WorkflowStorage, queue: Option<&str>, servers: &[Server], front: bool) -> Result<Vec<QueuedPrompts>, ComfyError> {
+    let mut available: Vec<&Server> = Vec::new();
+    let mut depths = Vec::new();
+    let mut error = None;
+    for server in servers {
+        match comfyui::get_queue(&server.ip_port) {
+            Ok(queue) => {
+                available.push(server);
+                depths.push(count_queue(queue));
+            }
+            Err(e) => {
+                println!("\x1b[33mwarning\x1b[0m:// leaving out {}: {e}", server.label());
+                error = Some(e);
+            }
+        }
+    }
+    if available.is_empty() {
+        if let Some(e) = error { return Err(e); }
     }
+    let servers = available;
+    let assigned = spread_by_queue_depth(&depths, yara_prompts.len());
+
+    let mut queued = Vec::new();
+    for (i, server) in servers.iter().enumerate() {
+        let batch: Vec<YaraPrompt> = yara_prompts.iter().zip(&assigned).filter(|x| *x.1 == i).map(|x| x.0.clone()).collect();
+        if batch.is_empty() { continue; }
+        if servers.len() > 1 {
+            println!("{STATUS}{} prompts for {} ({} already queued)", batch.len(), server.label(), depths[i]);
+        }
+        queued.push(queue_yara_prompts(&batch, storage, queue, server, front));
+    }
+    Ok(queued)
+}
 
-    // begin watching history to see when a prompt has finished
-    println!("Prompts have been sent to ComfyUI.\n");
-    println!("Yara will now wait for images to generate, before manually embedding workflow data into them.");
+impl QueuedPrompts {
+    fn finished(&self) -> bool {
+        !self.prompt_ids.values().any(|x| x == &PIDStatus::Queued)
+    }
+
+    // Stop waiting on a server that isn't answering. Its prompts may still generate, and their stored workflows are kept for 'yara fix'.
+    fn give_up(&mut self, error: ComfyError) {
+        let ids: Vec<String> = self.waiting().collect();
+        println!("\n\x1b[33mwarning\x1b[0m:// stopped waiting on {}'s {} prompts: {error}", self.server.label(), ids.len());
+        for id in ids {
+            self.prompt_ids.insert(id, PIDStatus::Unfinished);
+        }
+        self.events = None;
+        self.count = 0;
+    }
+
+    // Listen to the server for up to `timeout` (or poll it, without a websocket), and embed workflows for any of our prompts that finished.
+    // Returns the number of images with a workflow. If the server stops answering, it's given up on.
+    fn wait_step(&mut self, timeout: Duration, progress: &mut String, storage: &WorkflowStorage) -> usize {
+        // A prompt has finished once ComfyUI says it's done executing it, and only then is its history fetched.
+        // Once the queue is empty, any of our prompts still waiting are checked as well: they finished without us being told,
+        // or were cancelled (they're not in the history). Without a websocket, the prompts that have left the queue are checked.
+        let mut finished: Vec<String> = Vec::new();
+        let mut missing_means_cancelled = false;
+        match &mut self.events {
+            Some(client) => match client.next_event(timeout) {
+                Ok(Some(Event::Executing { prompt_id: Some(id), node: None })) | Ok(Some(Event::ExecutionSuccess { prompt_id: id })) => {
+                    finished.push(id);
+                }
+                Ok(Some(Event::Progress { value, max, .. })) => { *progress = format!(" [step {value}/{max}]"); }
+                Ok(Some(Event::ExecutionError { prompt_id, node_type, message, .. })) => {
+                    if self.prompt_ids.get(&prompt_id) == Some(&PIDStatus::Queued) {
+                        println!("\n\x1b[31merror\x1b[0m:// prompt {prompt_id} failed ({}): {message}", node_type.unwrap_or("unknown node".to_string()));
+                        self.prompt_ids.insert(prompt_id, PIDStatus::Finished);
+                    }
+                }
+                Ok(Some(Event::Status { queue_remaining })) => {
+                    self.count = queue_remaining as usize;
+                    if self.count == 0 {
+                        finished.extend(self.waiting());
+                        missing_means_cancelled = true;
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    println!("\n\x1b[33mwarning\x1b[0m:// lost connection to ComfyUI's websocket at {} ({e}), polling for finished prompts instead.", self.server.ip_port);
+                    self.events = None;
+                }
+            },
+            None => {
+                let queue = match comfyui::get_queue(&self.server.ip_port) {
+                    Ok(x) => x,
+                    Err(e) => { self.give_up(e); return 0; }
+                };
+                let in_queue = queued_prompt_ids(&queue);
+                self.count = count_queue(queue);
+                finished.extend(self.waiting().filter(|x| !in_queue.contains(x)));
+                missing_means_cancelled = true;
+            }
+        }
+
+        let mut workflows_embedded = 0;
+        for id in finished {
+            if self.prompt_ids.get(&id) != Some(&PIDStatus::Queued) { continue; } // not one of ours, or already handled
+            let entry = match comfyui::get_history_entry(&self.server.ip_port, &id) {
+                Ok(x) => x,
+                Err(e) => { self.give_up(e); break; }
+            };
+            match entry {
+                Some(entry) => {
+                    progress.clear();
+                    if embed_workflow_for_finished_prompt(&entry, &self.hashes[&id], &self.server.output_directory, storage) {
+                        workflows_embedded += 1;
+                    }
+                }
+                None if missing_means_cancelled => (),
+                None => continue, // not written to the history yet, it'll be checked once the queue is empty
+            }
+            self.prompt_ids.insert(id, PIDStatus::Finished);
+        }
+        workflows_embedded
+    }
+
+    // Our prompts that haven't finished yet
+    fn waiting(&self) -> impl Iterator<Item = String> + '_ {
+        self.prompt_ids.iter().filter(|x| x.1 == &PIDStatus::Queued).map(|x| x.0.clone())
+    }
+}
+
+// The id of every prompt running or waiting in a server's queue. Each entry is [number, prompt_id, prompt, extra_data, outputs].
+fn queued_prompt_ids(queue: &Value) -> HashSet<String> {
+    ["queue_running", "queue_pending"].iter()
+        .filter_map(|x| queue[*x].as_array())
+        .flatten()
+        .filter_map(|x| x.get(1).and_then(|x| x.as_str()).map(|x| x.to_string()))
+        .collect()
+}
+
+pub fn wait_for_yara_prompts(
+    mut queued: Vec<QueuedPrompts>,
+    storage: &WorkflowStorage,
+) {
+    // begin watching for our prompts to finish
+    println!("Yara will now wait for images to generate, to make sure workflow data was embedded into them.");
     let mut workflows_embedded = 0;
+    let prompt_count: usize = queued.iter().map(|x| x.prompt_ids.len()).sum();
+    let mut progress = String::new();
     let timer = Instant::now();
     loop {
 
-        // Exit if all prompts are either Finished (or already existed).
-        let finished = prompt_ids.iter().fold(true, |acc, x| if x.1 == &PIDStatus::Queued { false } else { acc });
-        if finished { break; }
-        // Exit if there's no more prompts in the queue
-        let count = count_queue(get_queue(&ip_port));
-        if count == 0 { break; }
+        // Exit if all prompts are Finished
+        if queued.iter().all(|x| x.finished()) { break; }
 
-        print!("\r{STATUS}waiting... [ {} ] ({count} prompts in queue)               ", format_seconds(timer.elapsed().as_secs()));
+        let count: usize = queued.iter().map(|x| x.count).sum();
+        print!("\r{STATUS}waiting... [ {} ] ({count} prompts in queue){progress}               ", format_seconds(timer.elapsed().as_secs()));
         std::io::stdout().flush().unwrap();
 
-        std::thread::sleep(std::time::Duration::from_secs(3));
-
-        let new_history = get_history(&ip_port);
-
-        if stored_history != new_history {
-            stored_history = new_history.clone();
-
-            for gpid in new_history.as_object().unwrap().keys() {
-                if let Some(status) = prompt_ids.get_mut(gpid) {
-                    if status == &PIDStatus::Queued { // one of our images, finished generating.
-
-                        // Find out the file path
-                        let outputs = new_history.as_object().unwrap().get(gpid).unwrap().get("outputs").unwrap().as_object().unwrap();
-                        for k in outputs.keys() {
-                            let info = outputs.get(k).unwrap().as_object().unwrap().get("images").unwrap().as_array().unwrap()[0].as_object().unwrap();
-                            if info.get("type").unwrap() == "output" {
-                                let filename = info.get("filename").unwrap().as_str().unwrap();
-                                let subfolder = info.get("subfolder").unwrap().as_str().unwrap();
-                                print!("\n{STATUS}generated image needs a workflow... ");
-                                std::io::stdout().flush().unwrap();
-                                let mut path = comfyui_output_directory.clone();
-                                if subfolder != "" {
-                                    path.push(subfolder);
-                                }
-                                path.push(filename);
-
-                                // Hash the API data, inject workflow if it matches
-                                let hash = get_api_hash_from_image_file(path.as_path()).unwrap();
-                                if let Some(workflow) = storage.workflows.get(&hash) {
-                                    inject_workflow_into_image(&path, &workflow);
-                                    remove_workflow_from_storage(&hash, workflow_file, &mut storage);
-                                    println!(" embedded workflow into {subfolder}/{filename}");
-                                    workflows_embedded += 1;
-                                } else {
-                                    println!("//\x1b[31m WARNING\x1b[0m // Hash doesn't match (unexpected) // hash {hash}, file {filename}");
-                                }
-                                break;
-                            }
-                        }
-                        *status = PIDStatus::Finished;
-                    }
-                }
+        // Servers with a websocket share a second of listening between them. The rest are polled every few seconds.
+        let connected = queued.iter().filter(|x| x.events.is_some()).count();
+        if connected == 0 {
+            std::thread::sleep(Duration::from_secs(3));
+        }
+        let timeout = Duration::from_millis(1000 / connected.max(1) as u64);
+        for server in queued.iter_mut().filter(|x| !x.finished()) {
+            workflows_embedded += server.wait_step(timeout, &mut progress, storage);
+        }
+    }
+    let unfinished = queued.iter().flat_map(|x| x.prompt_ids.values()).filter(|x| *x == &PIDStatus::Unfinished).count();
+    println!("{STATUS}\x1b[32mfinished\x1b[0m //. embedded workflows into {workflows_embedded} of {} prompts generated", prompt_count - unfinished);
+    if unfinished > 0 {
+        println!("{unfinished} prompts weren't waited on, since their server stopped answering. Their workflows are still stored for 'yara fix'.");
+    }
+}
+
+// The images a finished prompt saved (not temporary previews), from its history entry.
+// Every output node can save several images (e.g. with a batch size over 1).
+fn saved_images(entry: &Value, comfyui_output_directory: &Path) -> Vec<(PathBuf, String)> {
+    let Some(outputs) = entry.get("outputs").and_then(|x| x.as_object()) else { return Vec::new(); };
+    outputs.values().flat_map(|x| output_images(x, comfyui_output_directory)).collect()
+}
+
+// The images one node saved, from its output (as in the history, or an 'executed' message)
+pub fn output_images(output: &Value, comfyui_output_directory: &Path) -> Vec<(PathBuf, String)> {
+    let mut images = Vec::new();
+    let Some(infos) = output.get("images").and_then(|x| x.as_array()) else { return images; };
+    for info in infos {
+        if info.get("type").and_then(|x| x.as_str()) != Some("output") { continue; }
+        let Some(filename) = info.get("filename").and_then(|x| x.as_str()) else { continue; };
+        let subfolder = info.get("subfolder").and_then(|x| x.as_str()).unwrap_or("");
+        let mut path = comfyui_output_directory.to_path_buf();
+        if !subfolder.is_empty() {
+            path.push(subfolder);
+        }
+        path.push(filename);
+        images.push((path, format!("{subfolder}/{filename}")));
+    }
+    images
+}
+
+// Make sure every image a finished prompt saved has its workflow. The stored workflow is only released once they all do,
+// so 'yara fix' can still recover any that are missed. Returns whether they all do.
+fn embed_workflow_for_finished_prompt(entry: &Value, hash: &str, comfyui_output_directory: &Path, storage: &WorkflowStorage) -> bool {
+    let mut all_embedded = true;
+    for (path, name) in saved_images(entry, comfyui_output_directory) {
+        // ComfyUI normally embeds the workflow we sent as extra_pnginfo. Only fall back to injecting it ourselves if it didn't.
+        if image_has_workflow(&path) {
+            println!("\n{STATUS}generated {name} (workflow embedded by ComfyUI)");
+            continue;
+        }
+        print!("\n{STATUS}generated image needs a workflow... ");
+        std::io::stdout().flush().unwrap();
+
+        // Only inject it if the image really is from this prompt
+        match get_api_hash_from_image_file(&path) {
+            Ok(x) if x == hash => (),
+            Ok(x) => {
+                println!("//\x1b[31m WARNING\x1b[0m // Hash doesn't match (unexpected) // hash {x}, file {name}");
+                all_embedded = false;
+                continue;
+            }
+            Err(e) => {
+                println!("\x1b[31mfailed\x1b[0m to read {name}: {e}");
+                all_embedded = false;
+                continue;
             }
         }
+        let Some(workflow) = storage.get_workflow(hash) else {
+            println!("\x1b[31mfailed\x1b[0m, the workflow for {name} isn't stored");
+            all_embedded = false;
+            continue;
+        };
+        match inject_workflow_into_image(&path, &workflow) {
+            Ok(()) => println!(" embedded workflow into {name}"),
+            Err(e) => {
+                println!(" \x1b[31mfailed\x1b[0m to write it into {name}: {e}");
+                all_embedded = false;
+            }
+        }
+    }
+    if all_embedded {
+        remove_workflow_from_storage(hash, storage);
     }
-    println!("{STATUS}\x1b[32mfinished\x1b[0m //. embedded workflows into {workflows_embedded} of {} prompts generated", yara_prompts.len());
+    all_embedded
 }
 
 
 fn get_api_hash_from_image_file(path: &Path) -> Result<String, std::io::Error> {
-    let file = fs::File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let bytes = crate::regen::match_header_string_and_read_data(&mut reader, API_DATA_MARKER)?;
-    let x: serde_json::Map<String, Value> = serde_json::from_slice(&bytes)?;
+    let texts = metadata::read_texts_from_file(path)?;
+    let Some(text) = metadata::find_text(&texts, "prompt")
+        else { return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "image has no embedded prompt")); };
+    let x: serde_json::Map<String, Value> = serde_json::from_str(text)?;
     let hash = hash_nodemap(&x);
     Ok(hash)
 }
-fn get_history(ip_port: &str) -> Value {
-    let mut response = isahc::get(ip_port.to_string() + &"history").unwrap();
-    let mut buf = String::new();
-    response.body_mut().read_to_string(&mut buf).unwrap();
-    serde_json::from_str(&buf).unwrap()
-}
-fn inject_workflow_into_image(image_path: &PathBuf, workflow: &Value) {
-    let file = fs::File::open(image_path).unwrap();
-    let mut reader = BufReader::new(file);
-
-    let mut bytes: Vec<u8> = Vec::new();
-
-    // look for textprompt header chars
-    let mut buf = [0u8; 5];
-    'search_for_marker: loop {
-        reader.read_exact(&mut buf).unwrap(); // UnexpectedEof: no header found
-        bytes.extend(buf);
-        for i in 0..(API_DATA_MARKER.len()-4) {
-            if API_DATA_MARKER[i..(i+5)] == buf {
-                break 'search_for_marker;
+fn image_has_workflow(path: &Path) -> bool {
+    match metadata::read_texts_from_file(path) {
+        Ok(texts) => metadata::find_text(&texts, "workflow").is_some(),
+        Err(_) => false,
+    }
+}
+fn inject_workflow_into_image(image_path: &Path, workflow: &Value) -> std::io::Result<()> {
+    let text = serde_json::to_string(&workflow)?;
+    metadata::write_text_to_file(image_path, "workflow", &text)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png;
+    use crate::test_util::{temp_dir, fixture_path};
+    use std::io::{BufRead, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // A stand-in for a ComfyUI server that doesn't embed workflows itself: every prompt "generates" a PNG in the server's
+    // output folder, with only the prompt embedded, and shows up in the history straight away. There's no websocket.
+    // A prompt with a missing model is refused, and one with a "crash" model breaks the server for every prompt after it.
+    // One with a "slow" model stays in the queue.
+    struct MockComfyUI {
+        ip_port: String,
+        // The prompt of every /prompt request received
+        received: Arc<Mutex<Vec<Value>>>,
+        // How many times the history was asked for
+        history_requests: Arc<Mutex<usize>>,
+        // Once set, every connection is closed without an answer
+        down: Arc<AtomicBool>,
+    }
+
+    fn mock_comfyui(already_queued: usize, output_directory: PathBuf) -> MockComfyUI {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ip_port = format!("http://{}/", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let prompts = received.clone();
+        let history_requests = Arc::new(Mutex::new(0));
+        let history_count = history_requests.clone();
+        let down = Arc::new(AtomicBool::new(false));
+        let stopped = down.clone();
+        thread::spawn(move || {
+            let mut history = serde_json::Map::new();
+            let mut slow: Vec<Value> = Vec::new();
+            let mut crashed = false;
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return; };
+                let (method, path, body) = read_request(&stream);
+                if stopped.load(Ordering::SeqCst) { continue; }
+                let response = match (method.as_str(), path.as_str()) {
+                    ("GET", "/queue") => {
+                        let mut pending: Vec<Value> = (0..already_queued).map(|i| serde_json::json!([i, format!("other-{i}"), {}, {}, []])).collect();
+                        pending.extend(slow.iter().cloned());
+                        serde_json::json!({ "queue_running": [], "queue_pending": pending })
+                    }
+                    ("POST", "/prompt") => {
+                        let request: Value = serde_json::from_slice(&body).unwrap();
+                        crashed |= request["prompt"]["4"]["inputs"]["ckpt_name"] == "crash.safetensors";
+                        if crashed {
+                            write_response(stream, "500 Internal Server Error", "{}");
+                            continue;
+                        }
+                        if request["prompt"]["4"]["inputs"]["ckpt_name"] == "missing.safetensors" {
+                            let error = serde_json::json!({
+                                "error": { "type": "prompt_outputs_failed_validation", "message": "Prompt outputs failed validation", "details": "", "extra_info": {} },
+                                "node_errors": { "4": { "errors": [{ "type": "value_not_in_list", "message": "Value not in list", "details": "ckpt_name: 'missing.safetensors' not in ['model.safetensors']", "extra_info": { "input_name": "ckpt_name" } }], "dependent_outputs": ["9"], "class_type": "CheckpointLoaderSimple" } },
+                            });
+                            write_response(stream, "400 Bad Request", &error.to_string());
+                            continue;
+                        }
+                        let mut prompts = prompts.lock().unwrap();
+                        let id = format!("prompt-{}", prompts.len());
+                        if request["prompt"]["4"]["inputs"]["ckpt_name"] == "slow.safetensors" {
+                            slow.push(serde_json::json!([prompts.len(), id, request["prompt"], {}, []]));
+                            prompts.push(request["prompt"].clone());
+                            write_response(stream, "200 OK", &serde_json::json!({ "prompt_id": id, "number": prompts.len(), "node_errors": {} }).to_string());
+                            continue;
+                        }
+                        let filename = format!("{id}.png");
+                        // Two saved images (a batch, one of them in a subfolder), and a preview that isn't saved
+                        fs::create_dir_all(output_directory.join("batch")).unwrap();
+                        image_with_prompt(&output_directory.join(&filename), &request["prompt"]);
+                        image_with_prompt(&output_directory.join("batch").join(&filename), &request["prompt"]);
+                        history.insert(id.clone(), serde_json::json!({ "outputs": {
+                            "9": { "images": [{ "filename": filename, "subfolder": "", "type": "output" }, { "filename": filename, "subfolder": "batch", "type": "output" }] },
+                            "10": { "images": [{ "filename": "preview.png", "subfolder": "", "type": "temp" }] },
+                        } }));
+                        prompts.push(request["prompt"].clone());
+                        serde_json::json!({ "prompt_id": id, "number": prompts.len(), "node_errors": {} })
+                    }
+                    ("GET", x) if x.starts_with("/history/") => {
+                        *history_count.lock().unwrap() += 1;
+                        let id = &x["/history/".len()..];
+                        let mut found = serde_json::Map::new();
+                        if let Some(entry) = history.get(id) {
+                            found.insert(id.to_string(), entry.clone());
+                        }
+                        Value::Object(found)
+                    }
+                    _ => {
+                        write_response(stream, "404 Not Found", "{}");
+                        continue;
+                    }
+                };
+                write_response(stream, "200 OK", &response.to_string());
+            }
+        });
+        MockComfyUI { ip_port, received, history_requests, down }
+    }
+
+    fn read_request(stream: &TcpStream) -> (String, String, Vec<u8>) {
+        let mut reader = std::io::BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let mut parts = line.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or("").to_string());
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() { break; }
+            if let Some((key, value)) = header.split_once(':') {
+                if key.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
             }
         }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        (method, path, body)
     }
 
-    // read textprompt data
-    let mut byte = [0u8; 1];
-    loop {
-        reader.read_exact(&mut byte).unwrap();
-        bytes.extend(byte);
-        if byte == [b'{'] {
-            break;
-        }
-    }
-    let mut opening_bracket_count = 1;
-    while opening_bracket_count > 0 {
-        reader.read_exact(&mut byte).unwrap();
-        bytes.extend(byte);
-        match byte {
-            [b'{'] => opening_bracket_count += 1,
-            [b'}'] => opening_bracket_count -= 1,
-            _ => (),
-        }
-    }
-
-    // read textprompt CRC bytes
-    for _ in 0..4 {
-        reader.read_exact(&mut byte).unwrap();
-        bytes.extend(byte);
-    }
-
-
-    // add a new tEXt metadata chunk, for the workflow data
-    let png_chunk_name: [u8; 4] = [116, 69, 88, 116]; // tEXt
-    let keyword: [u8; 8]  = [119, 111, 114, 107, 102, 108, 111, 119]; // "tEXtworkflow"
-    let null_separator: [u8; 1] = [0x00];
-    let text_data: Vec<u8> = serde_json::to_vec(&workflow).unwrap();
-
-    let mut hasher = crc32fast::Hasher::new();
-    hasher.update(&png_chunk_name);
-    hasher.update(&keyword);
-    hasher.update(&null_separator);
-    hasher.update(&text_data);
-    let checksum: u32 = hasher.finalize();
-    let length: u32 = (8 + 1 + text_data.len() ).try_into().unwrap();
-
-    bytes.extend(length.to_be_bytes());
-    bytes.extend(png_chunk_name);
-    bytes.extend(keyword);
-    bytes.extend(null_separator);
-    bytes.extend(text_data);
-    bytes.extend(checksum.to_be_bytes());
-
-    // Read the rest of the file
-    let mut buf: Vec<u8> = Vec::new();
-    reader.read_to_end(&mut buf).unwrap();
-    bytes.extend(buf);
-
-    // Write the file, overwriting previous image
-    let mut file = fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(image_path)
-        .unwrap();
-    file.write_all(&bytes).unwrap();
-}
\ No newline at end of file
+    fn write_response(mut stream: TcpStream, status: &str, body: &str) {
+        let _ = write!(stream, "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+    }
+
+    // A PNG with the prompt embedded, but no workflow
+    fn image_with_prompt(path: &Path, prompt: &Value) {
+        fs::copy(fixture_path("no_text.png"), path).unwrap();
+        png::write_text_to_file(path, "prompt", &prompt.to_string()).unwrap();
+    }
+
+    fn prompt(seed: u64) -> YaraPrompt {
+        prompt_with_model(seed, "model.safetensors")
+    }
+    fn prompt_with_model(seed: u64, model: &str) -> YaraPrompt {
+        let nodes = serde_json::json!({
+            "3": { "class_type": "KSampler", "inputs": { "seed": seed, "steps": 20, "model": ["4", 0] } },
+            "4": { "class_type": "CheckpointLoaderSimple", "inputs": { "ckpt_name": model } },
+        });
+        let workflow = serde_json::json!({ "last_node_id": 4, "seed": seed });
+        YaraPrompt::new(nodes.as_object().unwrap().clone(), workflow, "http://saved-from/")
+    }
+
+    #[test]
+    fn spreads_prompts_by_queue_depth() {
+        assert_eq!(spread_by_queue_depth(&[2, 0], 4), [1, 1, 0, 1]);
+        assert_eq!(spread_by_queue_depth(&[0, 0, 0], 5), [0, 1, 2, 0, 1]);
+        assert_eq!(spread_by_queue_depth(&[5, 1, 3], 3), [1, 1, 1]);
+        assert!(spread_by_queue_depth(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn loads_across_several_servers() {
+        let dir = temp_dir("load-servers");
+        let mut servers = Vec::new();
+        let mut mocks = Vec::new();
+        for (name, already_queued) in [("busy", 2), ("idle", 0)] {
+            let output_directory = dir.join(name);
+            fs::create_dir(&output_directory).unwrap();
+            let mock = mock_comfyui(already_queued, output_directory.clone());
+            servers.push(Server { name: Some(name.to_string()), ip_port: mock.ip_port.clone(), input_directory: output_directory.clone(), output_directory });
+            mocks.push(mock);
+        }
+        let storage = WorkflowStorage::open_in(&dir.join("workflow_storage"));
+
+        let prompts: Vec<YaraPrompt> = (0..4).map(prompt).collect();
+        let queued = queue_yara_prompts_on_servers(&prompts, &storage, Some("batch"), &servers, false).unwrap();
+        wait_for_yara_prompts(queued, &storage);
+
+        // The idle server gets prompts until it's as busy as the other one, in order
+        let seeds = |mock: &MockComfyUI| -> Vec<u64> { mock.received.lock().unwrap().iter().map(|x| x["3"]["inputs"]["seed"].as_u64().unwrap()).collect() };
+        assert_eq!(seeds(&mocks[0]), [2]);
+        assert_eq!(seeds(&mocks[1]), [0, 1, 3]);
+        // Only prompts that have left the queue have their history checked, once each
+        assert_eq!(mocks.iter().map(|x| *x.history_requests.lock().unwrap()).collect::<Vec<_>>(), [1, 3]);
+
+        // Each image gets its own workflow, in its own server's output folder, and nothing is left in storage
+        for (server, expected) in servers.iter().zip([vec![2], vec![0, 1, 3]]) {
+            for (i, seed) in expected.into_iter().enumerate() {
+                for folder in [server.output_directory.clone(), server.output_directory.join("batch")] {
+                    let texts = png::read_text_chunks_from_file(&folder.join(format!("prompt-{i}.png"))).unwrap();
+                    let workflow: Value = serde_json::from_str(png::find_text(&texts, "workflow").unwrap()).unwrap();
+                    assert_eq!(workflow["seed"], seed);
+                }
+            }
+        }
+        assert!(storage.entries().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_waiting_when_a_server_goes_down() {
+        let dir = temp_dir("load-down");
+        let mut servers = Vec::new();
+        let mut mocks = Vec::new();
+        for name in ["up", "down"] {
+            let output_directory = dir.join(name);
+            fs::create_dir(&output_directory).unwrap();
+            let mock = mock_comfyui(0, output_directory.clone());
+            servers.push(Server { name: Some(name.to_string()), ip_port: mock.ip_port.clone(), input_directory: output_directory.clone(), output_directory });
+            mocks.push(mock);
+        }
+        let storage = WorkflowStorage::open_in(&dir.join("workflow_storage"));
+
+        // The second server's prompts are still in its queue when it goes down
+        let prompts = vec![prompt(0), prompt_with_model(1, "slow.safetensors"), prompt(2), prompt_with_model(3, "slow.safetensors")];
+        let queued = queue_yara_prompts_on_servers(&prompts, &storage, None, &servers, false).unwrap();
+        mocks[1].down.store(true, Ordering::SeqCst);
+        wait_for_yara_prompts(queued, &storage);
+
+        // The other server's prompts still get their workflows, and the lost ones are kept for 'yara fix'
+        for i in 0..2 {
+            let texts = png::read_text_chunks_from_file(&servers[0].output_directory.join(format!("prompt-{i}.png"))).unwrap();
+            assert!(png::find_text(&texts, "workflow").is_some());
+        }
+        let mut stored: Vec<String> = storage.entries().into_iter().map(|x| x.hash).collect();
+        stored.sort();
+        let mut expected = vec![prompts[1].hash.clone(), prompts[3].hash.clone()];
+        expected.sort();
+        assert_eq!(stored, expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn skips_refused_prompts() {
+        let dir = temp_dir("refused");
+        let mock = mock_comfyui(0, dir.clone());
+        let server = Server { name: None, ip_port: mock.ip_port.clone(), input_directory: dir.clone(), output_directory: dir.clone() };
+        let storage = WorkflowStorage::open_in(&dir.join("workflow_storage"));
+
+        let prompts = vec![prompt(0), prompt_with_model(1, "missing.safetensors"), prompt(2)];
+        let failed = generate_yara_prompts(prompts, &storage, &server);
+
+        // The rest of the batch still goes through
+        let seeds: Vec<u64> = mock.received.lock().unwrap().iter().map(|x| x["3"]["inputs"]["seed"].as_u64().unwrap()).collect();
+        assert_eq!(seeds, [0, 2]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].prompt.prompt["prompt"]["3"]["inputs"]["seed"], 1);
+        let ComfyError::Validation { node_errors, .. } = &failed[0].error else { panic!("not a validation error"); };
+        assert_eq!((node_errors[0].node_id.as_str(), node_errors[0].class_type.as_str()), ("4", "CheckpointLoaderSimple"));
+        assert_eq!(node_errors[0].errors[0].input_name.as_deref(), Some("ckpt_name"));
+        assert!(storage.entries().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_what_was_queued_when_a_server_stops() {
+        let dir = temp_dir("stopped");
+        let mock = mock_comfyui(0, dir.clone());
+        let server = Server { name: Some("up".to_string()), ip_port: mock.ip_port.clone(), input_directory: dir.clone(), output_directory: dir.clone() };
+        // Nothing is listening on a port that was just given back
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let down = Server { name: Some("down".to_string()), ip_port: format!("http://{closed}/"), ..server.clone() };
+        let storage = WorkflowStorage::open_in(&dir.join("workflow_storage"));
+
+        // The server that's down is left out, and the one that breaks halfway still has its first prompt waited on
+        let prompts = vec![prompt(0), prompt_with_model(1, "crash.safetensors"), prompt(2)];
+        let mut queued = queue_yara_prompts_on_servers(&prompts, &storage, None, &[down.clone(), server], false).unwrap();
+        let failed = take_failed_prompts(&mut queued);
+        assert_eq!(failed.iter().map(|x| x.prompt.prompt["prompt"]["3"]["inputs"]["seed"].as_u64().unwrap()).collect::<Vec<_>>(), [1, 2]);
+        assert!(failed.iter().all(|x| matches!(x.error, ComfyError::Status { status: 500, .. })));
+        wait_for_yara_prompts(queued, &storage);
+        let texts = png::read_text_chunks_from_file(&dir.join("prompt-0.png")).unwrap();
+        assert!(png::find_text(&texts, "workflow").is_some());
+        assert!(storage.entries().is_empty());
+
+        assert!(queue_yara_prompts_on_servers(&prompts, &storage, None, &[down], false).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn fixes_folders_recursively() {
+        let dir = temp_dir("fix");
+        let storage = WorkflowStorage::open_in(&dir.join("workflow_storage"));
+        let day = dir.join("output").join("2024-01-01");
+        fs::create_dir_all(&day).unwrap();
+
+        // Two images from one batch share a stored workflow; another has none stored
+        let batch = prompt(1);
+        storage.insert(&batch.hash, &batch.workflow, Some("overnight")).unwrap();
+        image_with_prompt(&day.join("batch_1.png"), &batch.prompt["prompt"]);
+        image_with_prompt(&day.join("batch_2.png"), &batch.prompt["prompt"]);
+        image_with_prompt(&dir.join("output").join("other.png"), &prompt(2).prompt["prompt"]);
+        fs::copy(fixture_path("no_text.png"), day.join("no_prompt.png")).unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        // Without --recursive, the date folder isn't looked in
+        let summary = fix_workflows(&storage, vec![dir.join("output")], false, true);
+        assert!(summary.matched.is_empty());
+        assert_eq!(summary.unmatched, [(dir.join("output").join("other.png"), true)]);
+
+        // A dry run changes nothing
+        let summary = fix_workflows(&storage, vec![dir.join("output"), dir.join("notes.txt")], true, true);
+        assert_eq!(summary.matched.iter().map(|x| x.0.clone()).collect::<Vec<_>>(), [day.join("batch_1.png"), day.join("batch_2.png")]);
+        assert_eq!(summary.unreadable.iter().map(|x| x.0.clone()).collect::<Vec<_>>(), [dir.join("notes.txt"), day.join("no_prompt.png")]);
+        assert!(!image_has_workflow(&day.join("batch_1.png")));
+        assert_eq!(storage.entries().len(), 1);
+
+        // Every image of the batch gets the workflow before it's released
+        let summary = fix_workflows(&storage, vec![dir.join("output")], true, false);
+        assert_eq!(summary.matched.len(), 2);
+        for name in ["batch_1.png", "batch_2.png"] {
+            let texts = png::read_text_chunks_from_file(&day.join(name)).unwrap();
+            let workflow: Value = serde_json::from_str(png::find_text(&texts, "workflow").unwrap()).unwrap();
+            assert_eq!(workflow, batch.workflow);
+        }
+        assert!(storage.entries().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_workflows_that_couldnt_be_embedded_everywhere() {
+        let dir = temp_dir("fix-failed");
+        let storage = WorkflowStorage::open_in(&dir.join("workflow_storage"));
+        let batch = prompt(1);
+        storage.insert(&batch.hash, &batch.workflow, None).unwrap();
+        image_with_prompt(&dir.join("batch_1.png"), &batch.prompt["prompt"]);
+        image_with_prompt(&dir.join("batch_2.png"), &batch.prompt["prompt"]);
+        // Damaged image data: the prompt can still be read, but the image can't be rewritten
+        let mut bytes = fs::read(dir.join("batch_2.png")).unwrap();
+        let idat = bytes.windows(4).position(|x| x == b"IDAT").unwrap();
+        bytes[idat + 4] ^= 0xFF;
+        fs::write(dir.join("batch_2.png"), bytes).unwrap();
+
+        let summary = fix_workflows(&storage, vec![dir.clone()], false, false);
+        assert_eq!(summary.matched, [(dir.join("batch_1.png"), batch.hash.clone())]);
+        assert_eq!(summary.unreadable.len(), 1);
+        assert!(storage.get(&batch.hash).is_some());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
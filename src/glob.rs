@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Wildcards in file arguments, for shells that don't expand them (Windows' cmd and PowerShell):
+//     *    any run of characters within one file/folder name
+//     ?    any one character
+//     **   any number of folders (including none), as a whole path component
+// Matching is case sensitive, and hidden files are only matched by a pattern starting with '.'.
+
+pub fn has_wildcards(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+// Every existing path matching the pattern, sorted
+pub fn expand(pattern: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let Component::Normal(name) = component else {
+            // Roots, prefixes, "." and ".." are taken as they are
+            for path in &mut paths { path.push(component.as_os_str()); }
+            continue;
+        };
+        let name = name.to_string_lossy();
+        if name == "**" {
+            let mut expanded = Vec::new();
+            for path in paths {
+                add_folders(&path, &mut expanded);
+            }
+            paths = expanded;
+        } else if has_wildcards(&name) {
+            let mut expanded = Vec::new();
+            for path in paths {
+                let dir = if path.as_os_str().is_empty() { Path::new(".") } else { path.as_path() };
+                let Ok(entries) = fs::read_dir(dir) else { continue; };
+                for entry in entries.flatten() {
+                    let file_name = entry.file_name().to_string_lossy().to_string();
+                    if matches(&name, &file_name) {
+                        expanded.push(path.join(file_name));
+                    }
+                }
+            }
+            paths = expanded;
+        } else {
+            for path in &mut paths { path.push(name.as_ref()); }
+        }
+    }
+    let mut paths: Vec<PathBuf> = paths.into_iter().filter(|x| !x.as_os_str().is_empty() && x.exists()).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+// A folder and every folder under it (symlinked folders aren't followed)
+fn add_folders(path: &Path, folders: &mut Vec<PathBuf>) {
+    folders.push(path.to_path_buf());
+    let dir = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    let Ok(entries) = fs::read_dir(dir) else { return; };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|x| x.is_dir()) && !entry.file_name().to_string_lossy().starts_with('.') {
+            add_folders(&path.join(entry.file_name()), folders);
+        }
+    }
+}
+
+// Whether a file name matches a pattern of one path component
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to go back to after a mismatch: just past the last '*', and the name position it's matched up to
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => { star = Some((p + 1, n)); p += 1; }
+            Some(x) if *x == '?' || *x == name[n] => { p += 1; n += 1; }
+            _ => match star {
+                // Let the '*' take one more character
+                Some((after_star, matched)) => { p = after_star; n = matched + 1; star = Some((after_star, matched + 1)); }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("*.png", "ComfyUI_00001_.png"));
+        assert!(!matches("*.png", "ComfyUI_00001_.webp"));
+        assert!(matches("ComfyUI_0000?_*", "ComfyUI_00007_.png"));
+        assert!(!matches("ComfyUI_0000?_*", "ComfyUI_00017_.png"));
+        assert!(matches("*a*b*c", "xxaxxbxxbxc"));
+        assert!(!matches("*a*b*c", "xxaxxbxxbx"));
+        assert!(matches("*", ""));
+        assert!(!matches("*", ".hidden.png"));
+        assert!(matches(".*", ".hidden.png"));
+    }
+
+    #[test]
+    fn expands_paths() {
+        let dir = temp_dir("glob");
+        for folder in ["2024-10-01", "2024-10-02/upscaled", "other"] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        for file in ["a.png", "2024-10-01/b.png", "2024-10-01/b.json", "2024-10-02/c.webp", "2024-10-02/upscaled/d.png", "other/e.png"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let expand = |pattern: &str| -> Vec<String> {
+            expand(&dir.join(pattern).to_string_lossy()).iter()
+                .map(|x| x.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/")).collect()
+        };
+        assert_eq!(expand("2024-*/*.png"), ["2024-10-01/b.png"]);
+        assert_eq!(expand("2024-10-0?/*"), ["2024-10-01/b.json", "2024-10-01/b.png", "2024-10-02/c.webp", "2024-10-02/upscaled"]);
+        assert_eq!(expand("**/*.png"), ["2024-10-01/b.png", "2024-10-02/upscaled/d.png", "a.png", "other/e.png"]);
+        assert_eq!(expand("2024-10-02/**/*"), ["2024-10-02/c.webp", "2024-10-02/upscaled", "2024-10-02/upscaled/d.png"]);
+        assert!(expand("*.jpg").is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashSet;
+use serde::Serialize;
+use serde_json::{Value, Map};
+
+// What an API prompt does, found by walking its node graph back from each sampler:
+//     model        sampler <- LoRAs <- checkpoint
+//     positive     sampler <- conditioning nodes (combine, concat, controlnets, ...) <- text encoders <- string nodes
+//     negative     the same
+//     settings     seed/steps/cfg on the sampler itself, or on the nodes SamplerCustom(Advanced) takes them from
+//     size         sampler <- latent nodes <- EmptyLatentImage
+//
+// An input is either a value, or a link to another node's output, written as ["4", 0] (node id, output slot).
+// Custom nodes name things their own way, so nodes are mostly recognised by their inputs rather than their class_type.
+// Nothing here panics on a prompt it doesn't understand; whatever can't be found is just left out.
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct PromptSummary {
+    pub checkpoints: Vec<String>,
+    pub loras: Vec<Lora>,
+    pub controlnets: Vec<String>,
+    pub vaes: Vec<String>,
+    // In node id order
+    pub samplers: Vec<Sampler>,
+    pub positive: Vec<String>,
+    pub negative: Vec<String>,
+    // Width and height of the first sampler's latent, if it starts from an empty one
+    pub size: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Lora {
+    pub name: String,
+    pub strength_model: Option<f64>,
+    pub strength_clip: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Sampler {
+    pub node_id: String,
+    pub class_type: String,
+    pub seed: Option<u64>,
+    pub steps: Option<u64>,
+    pub cfg: Option<f64>,
+    pub sampler_name: Option<String>,
+    pub scheduler: Option<String>,
+    pub denoise: Option<f64>,
+}
+
+const SAMPLERS: [&str; 4] = ["KSampler", "KSamplerAdvanced", "SamplerCustom", "SamplerCustomAdvanced"];
+// Where a model file's name is kept, on the different loaders
+const CHECKPOINT_INPUTS: [&str; 3] = ["ckpt_name", "unet_name", "model_name"];
+// What string nodes call their output
+const STRING_INPUTS: [&str; 5] = ["text", "string", "value", "prompt", "populated_text"];
+// Links are followed at most this far, in case of a malformed prompt that loops
+const MAX_DEPTH: usize = 256;
+
+pub fn analyze(nodes: &Map<String, Value>) -> PromptSummary {
+    let graph = Graph { nodes };
+    let mut summary = PromptSummary::default();
+
+    let mut sampler_ids: Vec<&String> = nodes.iter().filter(|(_, node)| graph.is_sampler(node)).map(|(id, _)| id).collect();
+    sampler_ids.sort_by_key(|x| (x.parse::<u64>().unwrap_or(u64::MAX), x.to_string()));
+
+    for id in sampler_ids {
+        let node = &nodes[id];
+        // SamplerCustomAdvanced takes its model and conditioning from a guider
+        let guider = graph.linked_node(node, "guider").map(|(_, x)| x);
+        let conditioning_node = guider.unwrap_or(node);
+
+        if let Some((source, _)) = graph.link(conditioning_node, "model") {
+            graph.walk_model(source, &mut summary, 0);
+        }
+        let mut visited = HashSet::new();
+        if let Some(link) = graph.link(conditioning_node, "positive").or(graph.link(conditioning_node, "conditioning")) {
+            graph.walk_conditioning(link, &mut summary.positive, &mut summary.controlnets, &mut visited, 0);
+        }
+        let mut visited = HashSet::new();
+        if let Some(link) = graph.link(conditioning_node, "negative") {
+            graph.walk_conditioning(link, &mut summary.negative, &mut summary.controlnets, &mut visited, 0);
+        }
+        if summary.size.is_none() {
+            summary.size = graph.latent_size(node);
+        }
+        summary.samplers.push(graph.sampler(id, node, guider));
+    }
+
+    // VAEs loaded on their own (rather than the checkpoint's own), for whatever uses them
+    for node in nodes.values() {
+        if let Some((_, loader)) = graph.linked_node(node, "vae") {
+            if let Some(name) = graph.string(loader, "vae_name") {
+                summary.vaes.push(name);
+            }
+        }
+    }
+
+    for list in [&mut summary.checkpoints, &mut summary.controlnets, &mut summary.vaes, &mut summary.positive, &mut summary.negative] {
+        dedup(list);
+    }
+    let mut seen = HashSet::new();
+    summary.loras.retain(|x| seen.insert(x.name.clone()));
+    summary
+}
+
+// Remove repeats, keeping the first of each
+fn dedup(list: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    list.retain(|x| seen.insert(x.clone()));
+}
+
+struct Graph<'a> {
+    nodes: &'a Map<String, Value>,
+}
+
+impl<'a> Graph<'a> {
+    fn is_sampler(&self, node: &'a Value) -> bool {
+        let class_type = node["class_type"].as_str().unwrap_or("");
+        SAMPLERS.contains(&class_type)
+            // Custom samplers: anything that takes a model and both kinds of conditioning and makes a latent from them
+            || (node["inputs"].get("model").is_some() && node["inputs"].get("positive").is_some()
+                && node["inputs"].get("negative").is_some() && node["inputs"].get("latent_image").is_some())
+    }
+
+    // The node (id) and output slot an input is linked to
+    fn link(&self, node: &'a Value, input: &str) -> Option<(&'a str, u64)> {
+        let value = node["inputs"].get(input)?.as_array()?;
+        let source = match value.first()? {
+            Value::String(x) => self.nodes.get_key_value(x)?.0.as_str(),
+            Value::Number(x) => self.nodes.get_key_value(&x.to_string())?.0.as_str(),
+            _ => return None,
+        };
+        Some((source, value.get(1).and_then(|x| x.as_u64()).unwrap_or(0)))
+    }
+    fn linked_node(&self, node: &'a Value, input: &str) -> Option<(&'a str, &'a Value)> {
+        let (id, _) = self.link(node, input)?;
+        Some((id, &self.nodes[id]))
+    }
+
+    // An input's value, following links to primitive nodes
+    fn value(&self, node: &'a Value, input: &str) -> Option<&'a Value> {
+        self.value_at_depth(node, input, 0)
+    }
+    fn value_at_depth(&self, node: &'a Value, input: &str, depth: usize) -> Option<&'a Value> {
+        if depth > MAX_DEPTH { return None; }
+        match self.linked_node(node, input) {
+            Some((_, source)) => {
+                // A primitive passes on its one value, whatever it calls it
+                let name = [input].into_iter().chain(["value", "seed", "noise_seed", "int", "float", "number"]).chain(STRING_INPUTS)
+                    .find(|x| source["inputs"].get(*x).is_some())?;
+                self.value_at_depth(source, name, depth + 1)
+            }
+            None => node["inputs"].get(input),
+        }
+    }
+    fn u64(&self, node: &'a Value, input: &str) -> Option<u64> {
+        self.value(node, input)?.as_u64()
+    }
+    fn f64(&self, node: &'a Value, input: &str) -> Option<f64> {
+        self.value(node, input)?.as_f64()
+    }
+
+    // Text, following links through string nodes (primitives, multiline text, concatenation...)
+    fn string(&self, node: &'a Value, input: &str) -> Option<String> {
+        self.string_at_depth(node, input, 0)
+    }
+    fn string_at_depth(&self, node: &'a Value, input: &str, depth: usize) -> Option<String> {
+        if depth > MAX_DEPTH { return None; }
+        let Some((_, source)) = self.linked_node(node, input) else {
+            return node["inputs"].get(input)?.as_str().map(|x| x.to_string());
+        };
+        if let Some(name) = STRING_INPUTS.iter().find(|x| source["inputs"].get(**x).is_some()) {
+            return self.string_at_depth(source, name, depth + 1);
+        }
+        // Concatenation nodes: text_a/text_b, string_a/string_b, text1/text2..., joined with their delimiter if they have one
+        let inputs = source["inputs"].as_object()?;
+        let mut parts: Vec<&String> = inputs.keys().filter(|x| x.starts_with("text") || x.starts_with("string")).collect();
+        parts.sort();
+        let parts: Vec<String> = parts.into_iter().filter_map(|x| self.string_at_depth(source, x, depth + 1)).filter(|x| !x.is_empty()).collect();
+        if parts.is_empty() { return None; }
+        let delimiter = inputs.get("delimiter").or(inputs.get("separator")).and_then(|x| x.as_str()).unwrap_or(" ");
+        Some(parts.join(delimiter))
+    }
+
+    // From a sampler's model input back to the checkpoint, collecting the LoRAs on the way
+    fn walk_model(&self, id: &str, summary: &mut PromptSummary, depth: usize) {
+        if depth > MAX_DEPTH { return; }
+        let node = &self.nodes[id];
+        if let Some(name) = CHECKPOINT_INPUTS.iter().find_map(|x| self.string(node, x)) {
+            summary.checkpoints.push(name);
+            return;
+        }
+        if let Some(name) = self.string(node, "lora_name") {
+            summary.loras.push(Lora {
+                name,
+                strength_model: self.f64(node, "strength_model").or(self.f64(node, "strength")),
+                strength_clip: self.f64(node, "strength_clip"),
+            });
+        }
+        if let Some((source, _)) = self.link(node, "model") {
+            self.walk_model(source, summary, depth + 1);
+        }
+    }
+
+    // From a sampler's positive or negative input back to the text, through every branch of combine/concat/average nodes
+    fn walk_conditioning(&self, (id, slot): (&'a str, u64), texts: &mut Vec<String>, controlnets: &mut Vec<String>,
+        visited: &mut HashSet<(&'a str, u64)>, depth: usize)
+    {
+        if depth > MAX_DEPTH || !visited.insert((id, slot)) { return; }
+        let node = &self.nodes[id];
+        let inputs = node["inputs"].as_object();
+
+        // Text encoders (CLIPTextEncode, SDXL's text_g/text_l, Flux's clip_l/t5xxl, ...)
+        let encoded: Vec<String> = ["text", "text_g", "text_l", "clip_l", "t5xxl"].iter()
+            .filter(|x| inputs.is_some_and(|inputs| inputs.contains_key(**x)))
+            .filter_map(|x| self.string(node, x)).collect();
+        if !encoded.is_empty() && self.link(node, "conditioning").is_none() {
+            texts.extend(encoded);
+            return;
+        }
+
+        if let Some((_, loader)) = self.linked_node(node, "control_net") {
+            if let Some(name) = self.string(loader, "control_net_name") {
+                controlnets.push(name);
+            }
+        }
+
+        // Nodes that pass both positive and negative through (ControlNetApplyAdvanced, ...): follow the side this came out of
+        if self.link(node, "positive").is_some() && self.link(node, "negative").is_some() {
+            let input = if slot == 1 { "negative" } else { "positive" };
+            if let Some(link) = self.link(node, input) {
+                self.walk_conditioning(link, texts, controlnets, visited, depth + 1);
+            }
+            return;
+        }
+
+        // Anything else: every conditioning input (conditioning, conditioning_1/_2, conditioning_to/_from, ...)
+        let Some(inputs) = inputs else { return; };
+        let mut names: Vec<&String> = inputs.keys().filter(|x| x.starts_with("conditioning")).collect();
+        names.sort_by_key(|x| (!x.ends_with("_to"), x.to_string())); // concat/average: the one added "to" first
+        for name in names {
+            if let Some(link) = self.link(node, name) {
+                self.walk_conditioning(link, texts, controlnets, visited, depth + 1);
+            }
+        }
+    }
+
+    // The size of the empty latent a sampler starts from, through any latent nodes in between
+    fn latent_size(&self, sampler: &'a Value) -> Option<(u64, u64)> {
+        let mut node = sampler;
+        let mut input = "latent_image";
+        for _ in 0..MAX_DEPTH {
+            let (_, source) = self.linked_node(node, input)?;
+            if let (Some(width), Some(height)) = (self.u64(source, "width"), self.u64(source, "height")) {
+                return Some((width, height));
+            }
+            node = source;
+            input = ["samples", "latent", "latent_image", "samples_to"].into_iter().find(|x| self.link(source, x).is_some())?;
+        }
+        None
+    }
+
+    fn sampler(&self, id: &str, node: &'a Value, guider: Option<&'a Value>) -> Sampler {
+        // SamplerCustom(Advanced) take these from other nodes: RandomNoise, CFGGuider, KSamplerSelect, BasicScheduler...
+        let noise = self.linked_node(node, "noise").map(|x| x.1);
+        let sampler = self.linked_node(node, "sampler").map(|x| x.1);
+        let sigmas = self.linked_node(node, "sigmas").map(|x| x.1);
+        let from = |nodes: &[Option<&'a Value>], inputs: &[&str]| -> Option<&'a Value> {
+            nodes.iter().flatten().find_map(|x| inputs.iter().find_map(|input| self.value(x, input)))
+        };
+        Sampler {
+            node_id: id.to_string(),
+            class_type: node["class_type"].as_str().unwrap_or("").to_string(),
+            seed: from(&[Some(node), noise], &["seed", "noise_seed"]).and_then(|x| x.as_u64()),
+            steps: from(&[Some(node), sigmas], &["steps"]).and_then(|x| x.as_u64()),
+            cfg: from(&[Some(node), guider], &["cfg"]).and_then(|x| x.as_f64()),
+            sampler_name: from(&[Some(node), sampler], &["sampler_name"]).and_then(|x| x.as_str()).map(|x| x.to_string()),
+            scheduler: from(&[Some(node), sigmas], &["scheduler"]).and_then(|x| x.as_str()).map(|x| x.to_string()),
+            denoise: from(&[Some(node), sigmas], &["denoise"]).and_then(|x| x.as_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(json: Value) -> Map<String, Value> {
+        json.as_object().unwrap().clone()
+    }
+
+    // Checkpoint -> two LoRAs -> KSampler, with a separate VAE
+    fn txt2img() -> Value {
+        serde_json::json!({
+            "4": {"class_type": "CheckpointLoaderSimple", "inputs": {"ckpt_name": "sdxl_base.safetensors"}},
+            "10": {"class_type": "LoraLoader", "inputs": {"lora_name": "detail.safetensors", "strength_model": 0.8, "strength_clip": 1.0, "model": ["4", 0], "clip": ["4", 1]}},
+            "11": {"class_type": "LoraLoaderModelOnly", "inputs": {"lora_name": "style.safetensors", "strength_model": 0.5, "model": ["10", 0]}},
+            "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a cat on a {weird} chair", "clip": ["10", 1]}},
+            "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "blurry", "clip": ["10", 1]}},
+            "5": {"class_type": "EmptyLatentImage", "inputs": {"width": 832, "height": 1216, "batch_size": 1}},
+            "3": {"class_type": "KSampler", "inputs": {"seed": 42, "steps": 30, "cfg": 6.5, "sampler_name": "euler", "scheduler": "normal", "denoise": 1.0,
+                "model": ["11", 0], "positive": ["6", 0], "negative": ["7", 0], "latent_image": ["5", 0]}},
+            "12": {"class_type": "VAELoader", "inputs": {"vae_name": "sdxl_vae.safetensors"}},
+            "8": {"class_type": "VAEDecode", "inputs": {"samples": ["3", 0], "vae": ["12", 0]}},
+            "9": {"class_type": "SaveImage", "inputs": {"filename_prefix": "ComfyUI", "images": ["8", 0]}}
+        })
+    }
+
+    #[test]
+    fn reads_a_basic_prompt() {
+        let summary = analyze(&nodes(txt2img()));
+        assert_eq!(summary.checkpoints, ["sdxl_base.safetensors"]);
+        assert_eq!(summary.loras, [
+            Lora { name: "style.safetensors".to_string(), strength_model: Some(0.5), strength_clip: None },
+            Lora { name: "detail.safetensors".to_string(), strength_model: Some(0.8), strength_clip: Some(1.0) },
+        ]);
+        assert_eq!(summary.vaes, ["sdxl_vae.safetensors"]);
+        assert_eq!(summary.positive, ["a cat on a {weird} chair"]);
+        assert_eq!(summary.negative, ["blurry"]);
+        assert_eq!(summary.size, Some((832, 1216)));
+        assert_eq!(summary.samplers, [Sampler {
+            node_id: "3".to_string(), class_type: "KSampler".to_string(), seed: Some(42), steps: Some(30), cfg: Some(6.5),
+            sampler_name: Some("euler".to_string()), scheduler: Some("normal".to_string()), denoise: Some(1.0),
+        }]);
+    }
+
+    #[test]
+    fn follows_every_sampler() {
+        // A hires pass: a second sampler on the upscaled latent, with its own prompt and a different checkpoint
+        let mut prompt = txt2img();
+        let extra = serde_json::json!({
+            "20": {"class_type": "CheckpointLoader", "inputs": {"config_name": "v1.yaml", "ckpt_name": "refiner.ckpt"}},
+            "21": {"class_type": "LatentUpscale", "inputs": {"samples": ["3", 0], "width": 1664, "height": 2432, "upscale_method": "nearest-exact", "crop": "disabled"}},
+            "22": {"class_type": "CLIPTextEncode", "inputs": {"text": "sharp details", "clip": ["20", 1]}},
+            "23": {"class_type": "KSamplerAdvanced", "inputs": {"noise_seed": ["24", 0], "steps": 15, "cfg": 4, "sampler_name": "dpmpp_2m", "scheduler": "karras",
+                "model": ["20", 0], "positive": ["22", 0], "negative": ["7", 0], "latent_image": ["21", 0], "add_noise": "enable", "start_at_step": 5, "end_at_step": 15}},
+            "24": {"class_type": "Seed (rgthree)", "inputs": {"seed": 1234}}
+        });
+        prompt.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        let summary = analyze(&nodes(prompt));
+        assert_eq!(summary.checkpoints, ["sdxl_base.safetensors", "refiner.ckpt"]);
+        assert_eq!(summary.positive, ["a cat on a {weird} chair", "sharp details"]);
+        assert_eq!(summary.negative, ["blurry"]);
+        assert_eq!(summary.samplers.iter().map(|x| x.node_id.as_str()).collect::<Vec<_>>(), ["3", "23"]);
+        assert_eq!(summary.samplers[1].seed, Some(1234));
+        assert_eq!(summary.samplers[1].cfg, Some(4.0));
+        // The size it was generated at, not upscaled to
+        assert_eq!(summary.size, Some((832, 1216)));
+    }
+
+    #[test]
+    fn reads_sampler_custom_and_conditioning_nodes() {
+        let summary = analyze(&nodes(serde_json::json!({
+            "1": {"class_type": "UNETLoader", "inputs": {"unet_name": "flux1-dev.safetensors", "weight_dtype": "default"}},
+            "2": {"class_type": "PrimitiveString", "inputs": {"value": "a lighthouse"}},
+            "3": {"class_type": "StringConcatenate", "inputs": {"string_a": ["2", 0], "string_b": "at dusk", "delimiter": ", "}},
+            "4": {"class_type": "CLIPTextEncode", "inputs": {"text": ["3", 0], "clip": ["9", 0]}},
+            "5": {"class_type": "CLIPTextEncode", "inputs": {"text": "oil painting", "clip": ["9", 0]}},
+            "6": {"class_type": "ConditioningCombine", "inputs": {"conditioning_1": ["4", 0], "conditioning_2": ["5", 0]}},
+            "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "text, watermark", "clip": ["9", 0]}},
+            "8": {"class_type": "ConditioningConcat", "inputs": {"conditioning_from": ["7", 0], "conditioning_to": ["13", 0]}},
+            "13": {"class_type": "CLIPTextEncode", "inputs": {"text": "lowres", "clip": ["9", 0]}},
+            "9": {"class_type": "DualCLIPLoader", "inputs": {"clip_name1": "t5xxl.safetensors", "clip_name2": "clip_l.safetensors", "type": "flux"}},
+            "10": {"class_type": "ControlNetLoader", "inputs": {"control_net_name": "canny.safetensors"}},
+            "11": {"class_type": "ControlNetApplyAdvanced", "inputs": {"positive": ["6", 0], "negative": ["8", 0], "control_net": ["10", 0], "image": ["12", 0], "strength": 0.7}},
+            "12": {"class_type": "LoadImage", "inputs": {"image": "edges.png"}},
+            "14": {"class_type": "KSamplerSelect", "inputs": {"sampler_name": "euler"}},
+            "15": {"class_type": "BasicScheduler", "inputs": {"scheduler": "simple", "steps": 20, "denoise": 1.0, "model": ["1", 0]}},
+            "16": {"class_type": "EmptySD3LatentImage", "inputs": {"width": 1024, "height": 768, "batch_size": 1}},
+            "17": {"class_type": "SamplerCustom", "inputs": {"model": ["1", 0], "add_noise": true, "noise_seed": 7, "cfg": 3.5,
+                "positive": ["11", 0], "negative": ["11", 1], "sampler": ["14", 0], "sigmas": ["15", 0], "latent_image": ["16", 0]}}
+        })));
+        assert_eq!(summary.checkpoints, ["flux1-dev.safetensors"]);
+        assert_eq!(summary.positive, ["a lighthouse, at dusk", "oil painting"]);
+        assert_eq!(summary.negative, ["lowres", "text, watermark"]);
+        assert_eq!(summary.controlnets, ["canny.safetensors"]);
+        assert_eq!(summary.size, Some((1024, 768)));
+        let sampler = &summary.samplers[0];
+        assert_eq!((sampler.seed, sampler.steps, sampler.cfg), (Some(7), Some(20), Some(3.5)));
+        assert_eq!((sampler.sampler_name.as_deref(), sampler.scheduler.as_deref()), (Some("euler"), Some("simple")));
+    }
+
+    #[test]
+    fn reads_sampler_custom_advanced() {
+        let summary = analyze(&nodes(serde_json::json!({
+            "1": {"class_type": "CheckpointLoaderSimple", "inputs": {"ckpt_name": "model.safetensors"}},
+            "2": {"class_type": "CLIPTextEncode", "inputs": {"text": "a forest", "clip": ["1", 1]}},
+            "3": {"class_type": "FluxGuidance", "inputs": {"conditioning": ["2", 0], "guidance": 3.5}},
+            "4": {"class_type": "BasicGuider", "inputs": {"model": ["1", 0], "conditioning": ["3", 0]}},
+            "5": {"class_type": "RandomNoise", "inputs": {"noise_seed": 99}},
+            "6": {"class_type": "SamplerCustomAdvanced", "inputs": {"noise": ["5", 0], "guider": ["4", 0], "sampler": ["7", 0], "sigmas": ["8", 0], "latent_image": ["9", 0]}},
+            "7": {"class_type": "KSamplerSelect", "inputs": {"sampler_name": "euler"}},
+            "8": {"class_type": "BasicScheduler", "inputs": {"scheduler": "beta", "steps": 28, "denoise": 1.0, "model": ["1", 0]}},
+            "9": {"class_type": "EmptyLatentImage", "inputs": {"width": 512, "height": 512, "batch_size": 1}}
+        })));
+        assert_eq!(summary.checkpoints, ["model.safetensors"]);
+        assert_eq!(summary.positive, ["a forest"]);
+        assert!(summary.negative.is_empty());
+        assert_eq!((summary.samplers[0].seed, summary.samplers[0].steps), (Some(99), Some(28)));
+        assert_eq!(summary.size, Some((512, 512)));
+    }
+
+    #[test]
+    fn leaves_out_what_it_cant_find() {
+        // Broken links, a conditioning node with no conditioning input, and a loop
+        let summary = analyze(&nodes(serde_json::json!({
+            "1": {"class_type": "KSampler", "inputs": {"seed": 1, "model": ["404", 0], "positive": ["2", 0], "negative": ["3", 0], "latent_image": ["4", 0]}},
+            "2": {"class_type": "ConditioningSetArea", "inputs": {"width": 64, "height": 64, "strength": 1.0}},
+            "3": {"class_type": "ConditioningCombine", "inputs": {"conditioning_1": ["3", 0], "conditioning_2": ["5", 0]}},
+            "4": {"class_type": "VAEEncode", "inputs": {"pixels": ["6", 0]}},
+            "5": {"class_type": "CLIPTextEncode", "inputs": {"text": ["7", 0]}},
+            "7": {"class_type": "Note", "inputs": {}}
+        })));
+        assert!(summary.checkpoints.is_empty());
+        assert!(summary.positive.is_empty());
+        assert!(summary.negative.is_empty());
+        assert_eq!(summary.size, None);
+        assert_eq!(summary.samplers[0].seed, Some(1));
+        assert!(analyze(&Map::new()).samplers.is_empty());
+    }
+}
@@ -1,11 +1,21 @@
-use std::path::PathBuf;
-use std::fs;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use std::io::{BufReader, Read};
+use std::sync::{mpsc, OnceLock};
+use std::fs;
+use std::thread;
+use std::time::Duration;
 
 use notan::draw::*;
 use notan::prelude::*;
 
-use crate::config::{Config, get_appdata};
+use crate::config::{Config, Server, get_appdata};
+use crate::data::client_id;
+use crate::fix::output_images;
+use crate::graph::PromptSummary;
+use crate::watch::{self, OutputEvent};
+use crate::websocket::{self, EventClient};
+use crate::{read_image_info, lora_strength, short_model_name};
 
 #[derive(AppState)]
 struct State {
@@ -20,9 +30,47 @@ struct State {
     iw: u32,
     base_image: Vec<u8>,
     update_size: bool,
+    output_events: mpsc::Receiver<OutputEvent>,
+    // Recent outputs, oldest first. The arrow keys step through them.
+    history: VecDeque<PathBuf>,
+    // While pinned, new outputs are added to the history but not shown
+    pinned: bool,
+    image_size: Option<(u32, u32)>,
+    // 1 is the image fitted to the window. The pan is how far the image's centre is moved from the window's, in pixels.
+    zoom: f32,
+    pan: (f32, f32),
+    // Show the image's pixels 1:1 instead of fitted to the window
+    pixel_view: bool,
+    last_mouse: (f32, f32),
+    regen_directory: PathBuf,
+    title: String,
+    font: Font,
+    // The generation settings of the image shown, drawn over it while `show_overlay` is on
+    overlay: Vec<String>,
+    show_overlay: bool,
+    // With '--live': ComfyUI's previews of the image being sampled, shown until it's saved
+    live_events: Option<mpsc::Receiver<websocket::Event>>,
+    live_frame: Option<Vec<u8>>,
+    showing_live_frame: bool,
+    // (step, steps) of the sampler running
+    progress: Option<(u64, u64)>,
 }
 
-pub fn notan_main(cfg: &Config) -> Result<(), String> {
+const HISTORY_LENGTH: usize = 100;
+const ZOOM_STEP: f32 = 1.25;
+const PAN_STEP: f32 = 50.;
+const OVERLAY_TEXT_SIZE: f32 = 14.;
+// The positive prompt's first line is cut down to this many characters
+const OVERLAY_PROMPT_LENGTH: usize = 100;
+const PROGRESS_BAR_HEIGHT: f32 = 4.;
+
+// The server being previewed ('--server'), and whether to show live previews ('--live'). notan's init function can't be given them directly.
+static SERVER: OnceLock<Server> = OnceLock::new();
+static LIVE: OnceLock<bool> = OnceLock::new();
+
+pub fn notan_main(cfg: &Config, server: Server, live: bool) -> Result<(), String> {
+    let _ = SERVER.set(server);
+    let _ = LIVE.set(live);
     notan::init_with(init)
         .add_plugin(notan::extra::FpsLimit::new(cfg.framerate_cap))
         .add_config(WindowConfig::new()
@@ -53,10 +101,10 @@ fn init(_app: &mut App, gfx: &mut Graphics) -> State {
         Err(e) => { panic!("Error while loading config file within notan init function\n{e}"); }
     };
     let reader = BufReader::new(file);
-    let cfg: Config = serde_json::from_reader(reader).unwrap();
+    let mut cfg: Config = serde_json::from_reader(reader).unwrap();
 
     let default_base_image = include_bytes!("assets/default_base_image.png").to_vec();
-    let base_image = match cfg.base_image {
+    let base_image = match &cfg.base_image {
         None => default_base_image,
         Some(path) => {
             let mut buf: Vec<u8> = Vec::new();
@@ -70,15 +118,32 @@ fn init(_app: &mut App, gfx: &mut Graphics) -> State {
     };
 
 
-    let comfyui_output_directory = cfg.comfyui_output_directory;
+    let server = SERVER.get().unwrap();
+    let comfyui_output_directory = server.output_directory.clone();
+
+    // Watch the output folder in the background, so it only needs looking through once, now
+    let output_events = watch::watch_output(comfyui_output_directory.clone());
+    let history: VecDeque<PathBuf> = watch::recent_images(&comfyui_output_directory, HISTORY_LENGTH).into();
+    let image = history.back().cloned();
+
+    let live_events = match LIVE.get() {
+        Some(true) => {
+            let (tx, rx) = mpsc::channel();
+            let ip_port = server.ip_port.clone();
+            thread::spawn(move || watch_live_previews(ip_port, tx));
+            Some(rx)
+        }
+        _ => None,
+    };
 
     let texture = gfx
         .create_texture()
         .from_image(&base_image)
         .build()
         .unwrap();
+    let font = gfx.create_font(include_bytes!("assets/Hack-Regular.ttf")).unwrap();
 
-    State {
+    let mut state = State {
         comfyui_output_directory,
         tex: texture,
         image: None,
@@ -90,37 +155,51 @@ fn init(_app: &mut App, gfx: &mut Graphics) -> State {
         iw:  500,
         base_image,
         update_size: true,
-    }
+        output_events,
+        history,
+        pinned: false,
+        image_size: None,
+        zoom: 1.,
+        pan: (0., 0.),
+        pixel_view: false,
+        last_mouse: (0., 0.),
+        regen_directory: cfg.get_regen_dir(),
+        title: String::new(),
+        font,
+        overlay: Vec::new(),
+        show_overlay: cfg.preview_overlay.unwrap_or(false),
+        live_events,
+        live_frame: None,
+        showing_live_frame: false,
+        progress: None,
+    };
+    show_image(&mut state, image);
+    state
 }
 
 fn draw(gfx: &mut Graphics, state: &mut State) {
     let mut draw = gfx.create_draw();
     draw.clear(Color::TRANSPARENT);
 
-    // Check if image exists
-    if let Some(image) = &state.image {
+    if let Some(frame) = state.live_frame.take() {
+        // (a frame that can't be decoded is skipped, the next one will be along shortly)
+        if let Ok(texture) = gfx.create_texture().from_image(&frame).build() {
+            state.tex = texture;
+            state.showing_live_frame = true;
+        }
+    } else if state.showing_live_frame {
+        // Keep showing the last frame until the image is saved
+    } else if let Some(image) = &state.image {
         // Check that image is not already drawn (viz. it's a new image)
         if image != &state.last_image {
-            let bytes = match std::fs::read(&image) {
-                Ok(x) => x,
-                Err(e) => {
-                    if Some(32) == e.raw_os_error() {
-                        // File is already in access elsewhere. We assume it'll be freed later. 
-                        // Probably a better way to handle this - panic after N attempts, maybe?
-                        return;
-                    }
-                    else {panic!("{e}");}
-                }
-            };
-            let texture = match gfx
-                .create_texture()
-                .from_image(&bytes)
-                .build()
-                {
-                    Ok(x) => x,
-                    Err(_) => {return;} // ehh maybe should handle this differently, who cares lol
-                };
-            state.tex = texture;
+            // The watcher only reports finished images, so if this one can't be read (e.g. it was removed straight away),
+            // keep showing the previous one
+            let texture = std::fs::read(image).map_err(|e| e.to_string())
+                .and_then(|bytes| gfx.create_texture().from_image(&bytes).build());
+            match texture {
+                Ok(x) => state.tex = x,
+                Err(e) => println!("\x1b[33mwarning\x1b[0m:// couldn't show {}: {e}", image.display()),
+            }
             state.last_image = image.to_path_buf();
         }
     } else { // No image; draw base image
@@ -133,7 +212,35 @@ fn draw(gfx: &mut Graphics, state: &mut State) {
         state.last_image = PathBuf::new();
     }
 
-    draw.image(&state.tex).size(state.iw as f32, state.ih as f32);
+    // Fitted to the window (or 1:1), zoomed around the middle of the fitted image, then panned.
+    // Live frames are smaller than the image, so they're always fitted.
+    let (w, h) = match (state.pixel_view && !state.showing_live_frame, state.image_size) {
+        (true, Some((w, h))) => (w as f32, h as f32),
+        _ => (state.iw as f32, state.ih as f32),
+    };
+    let (w, h) = (w * state.zoom, h * state.zoom);
+    let x = (state.iw as f32 - w) / 2. + state.pan.0;
+    let y = (state.ih as f32 - h) / 2. + state.pan.1;
+    draw.image(&state.tex).position(x, y).size(w, h);
+
+    if let Some((step, steps)) = state.progress {
+        let done = step.min(steps) as f32 / steps.max(1) as f32;
+        let y = state.h as f32 - PROGRESS_BAR_HEIGHT;
+        draw.rect((0., y), (state.w as f32, PROGRESS_BAR_HEIGHT)).fill_color(Color::new(0., 0., 0., 0.6));
+        draw.rect((0., y), (state.w as f32 * done, PROGRESS_BAR_HEIGHT)).fill_color(Color::new(0.3, 0.8, 0.4, 1.));
+    }
+
+    if state.show_overlay && !state.overlay.is_empty() {
+        let line_height = OVERLAY_TEXT_SIZE * 1.3;
+        let height = line_height * state.overlay.len() as f32 + 8.;
+        draw.rect((0., 0.), (state.w as f32, height)).fill_color(Color::new(0., 0., 0., 0.6));
+        for (i, line) in state.overlay.iter().enumerate() {
+            draw.text(&state.font, line)
+                .position(6., 4. + line_height * i as f32)
+                .size(OVERLAY_TEXT_SIZE)
+                .color(Color::WHITE);
+        }
+    }
     gfx.render(&draw);
 }
 
@@ -159,23 +266,287 @@ fn update(app: &mut App, state: &mut State) {
 
 
 
-    // Check if the ComfyUI output directory has a new image
-    let dir_search = fs::read_dir(&state.comfyui_output_directory)
-        .expect("Couldn't access ComfyUI directory")
-        .flatten() // Remove failed
-        .filter(|f| f.metadata().unwrap().is_file()) // Filter out folders
-        .max_by_key(|x| x.metadata().unwrap().modified().unwrap()); // Sort by last modified
+    // Keep every new output in the history, and show the newest unless an image is pinned.
+    // If the image being shown was removed, show the one before it.
+    let mut newest = None;
+    let events: Vec<OutputEvent> = state.output_events.try_iter().collect();
+    for event in events {
+        match event {
+            OutputEvent::Added(path) => {
+                state.history.retain(|x| x != &path);
+                state.history.push_back(path.clone());
+                if state.history.len() > HISTORY_LENGTH { state.history.pop_front(); }
+                newest = Some(path);
+            }
+            OutputEvent::Removed(path) => {
+                let position = state.history.iter().position(|x| x == &path);
+                if let Some(i) = position { state.history.remove(i); }
+                if state.image.as_ref() == Some(&path) {
+                    let previous = match position {
+                        Some(i) => state.history.get(i.saturating_sub(1)).cloned(),
+                        None => state.history.back().cloned(),
+                    };
+                    show_image(state, previous.or_else(|| watch::latest_image(&state.comfyui_output_directory)));
+                }
+            }
+        }
+    }
+    if newest.is_some() && !state.pinned {
+        show_image(state, newest);
+    }
 
-    if let Some(last) = dir_search {
-        if let Ok(size) = imagesize::size(last.path()) {
-            state.image = Some(last.path());
-            state.ratio = size.width as f64 / size.height as f64;
-            state.update_size = true;
+    // Live previews. Once the prompt's image is saved ('executed'), show that straight away rather than waiting for the watcher.
+    let live_events: Vec<websocket::Event> = state.live_events.as_ref().map(|x| x.try_iter().collect()).unwrap_or_default();
+    for event in live_events {
+        match event {
+            websocket::Event::Preview { image } => {
+                if state.pinned { continue; }
+                if let Ok(size) = imagesize::blob_size(&image) {
+                    state.ratio = size.width as f64 / size.height as f64;
+                    state.update_size = true;
+                }
+                state.live_frame = Some(image);
+            }
+            websocket::Event::Progress { value, max, .. } => state.progress = Some((value, max)),
+            websocket::Event::Executed { output, .. } => {
+                let saved = output_images(&output, &state.comfyui_output_directory).pop().map(|(path, _)| path);
+                match saved {
+                    Some(path) if !state.pinned && path.exists() => {
+                        state.history.retain(|x| x != &path);
+                        state.history.push_back(path.clone());
+                        if state.history.len() > HISTORY_LENGTH { state.history.pop_front(); }
+                        end_live_preview(state);
+                        show_image(state, Some(path));
+                    }
+                    _ => end_live_preview(state),
+                }
+            }
+            // The prompt has finished (or failed) without saving anything
+            websocket::Event::Executing { node: None, .. } | websocket::Event::ExecutionError { .. } => end_live_preview(state),
+            _ => (),
+        }
+    }
+
+    handle_keys(app, state);
+
+    let title = match &state.image {
+        Some(image) => {
+            let name = image.file_name().unwrap_or_default().to_string_lossy();
+            let position = match state.history.iter().position(|x| x == image) {
+                Some(i) => format!(" ({}/{})", i + 1, state.history.len()),
+                None => String::new(),
+            };
+            format!("yara - {name}{position}{}", if state.pinned { " [pinned]" } else { "" })
+        }
+        None => "yara".to_string(),
+    };
+    if title != state.title {
+        app.window().set_title(&title);
+        state.title = title;
+    }
+}
+
+//  Left/Right      previous/next image in the history      Home/End    oldest/newest
+//  P               pin the image, so new outputs don't replace it
+//  +/-, wheel      zoom                                    Shift+arrows, drag     pan
+//  1               toggle 1:1 pixels                       0           fit to the window again
+//  C               copy the image's path                   R           copy the image to the regen folder
+//  I               show/hide the generation settings
+fn handle_keys(app: &mut App, state: &mut State) {
+    let keyboard = &app.keyboard;
+
+    if keyboard.shift() {
+        let pans = [(KeyCode::Left, (PAN_STEP, 0.)), (KeyCode::Right, (-PAN_STEP, 0.)), (KeyCode::Up, (0., PAN_STEP)), (KeyCode::Down, (0., -PAN_STEP))];
+        for (key, (x, y)) in pans {
+            if keyboard.was_pressed(key) { state.pan = (state.pan.0 + x, state.pan.1 + y); }
         }
     } else {
-        state.image = None;
-        state.ratio = 1.;
-        state.update_size = true;
+        let position = state.image.as_ref().and_then(|image| state.history.iter().position(|x| x == image));
+        let last = state.history.len().saturating_sub(1);
+        let step = if keyboard.was_pressed(KeyCode::Left) {
+            // (an image that has dropped out of the history steps back to the newest)
+            Some(position.map_or(last, |i| i.saturating_sub(1)))
+        } else if keyboard.was_pressed(KeyCode::Right) {
+            Some(position.map_or(last, |i| (i + 1).min(last)))
+        } else if keyboard.was_pressed(KeyCode::Home) {
+            Some(0)
+        } else if keyboard.was_pressed(KeyCode::End) {
+            Some(last)
+        } else {
+            None
+        };
+        if let Some(i) = step {
+            let image = state.history.get(i).cloned();
+            if image.is_some() && image != state.image {
+                show_image(state, image);
+            }
+        }
+    }
+
+    if keyboard.was_pressed(KeyCode::P) {
+        state.pinned = !state.pinned;
+    }
+    if keyboard.was_pressed(KeyCode::I) {
+        state.show_overlay = !state.show_overlay;
+    }
+
+    if keyboard.was_pressed(KeyCode::Plus) || keyboard.was_pressed(KeyCode::Equals) || keyboard.was_pressed(KeyCode::Add) {
+        zoom(state, ZOOM_STEP, (0., 0.));
+    }
+    if keyboard.was_pressed(KeyCode::Minus) || keyboard.was_pressed(KeyCode::Subtract) {
+        zoom(state, 1. / ZOOM_STEP, (0., 0.));
+    }
+    let (mouse_x, mouse_y) = app.mouse.position();
+    // From the middle of the fitted image, as the pan is
+    let cursor = (mouse_x - state.iw as f32 / 2., mouse_y - state.ih as f32 / 2.);
+    if app.mouse.is_scrolling() && app.mouse.wheel_delta.y != 0. {
+        zoom(state, if app.mouse.wheel_delta.y > 0. { ZOOM_STEP } else { 1. / ZOOM_STEP }, cursor);
+    }
+    if app.mouse.left_is_down() && !app.mouse.left_was_pressed() {
+        state.pan = (state.pan.0 + mouse_x - state.last_mouse.0, state.pan.1 + mouse_y - state.last_mouse.1);
+    }
+    state.last_mouse = (mouse_x, mouse_y);
+
+    if keyboard.was_pressed(KeyCode::Key1) {
+        state.pixel_view = !state.pixel_view;
+        state.zoom = 1.;
+        state.pan = (0., 0.);
+    }
+    if keyboard.was_pressed(KeyCode::Key0) {
+        state.pixel_view = false;
+        state.zoom = 1.;
+        state.pan = (0., 0.);
+    }
+
+    let Some(image) = state.image.clone() else { return; };
+    if keyboard.was_pressed(KeyCode::C) {
+        use clipboard::{ClipboardProvider, ClipboardContext};
+        let copied = ClipboardProvider::new().and_then(|mut ctx: ClipboardContext| ctx.set_contents(image.display().to_string()));
+        match copied {
+            Ok(()) => println!("Copied {} to the clipboard.", image.display()),
+            Err(e) => println!("\x1b[33mwarning\x1b[0m:// couldn't copy the path to the clipboard: {e}"),
+        }
+    }
+    if keyboard.was_pressed(KeyCode::R) {
+        send_to_regen(&image, &state.regen_directory);
+    }
+}
+
+// Back to the image that was shown before the live frames
+fn end_live_preview(state: &mut State) {
+    state.progress = None;
+    if state.showing_live_frame || state.live_frame.is_some() {
+        state.live_frame = None;
+        state.showing_live_frame = false;
+        let image = state.image.clone();
+        show_image(state, image);
     }
 }
 
+// Keeps the point under `around` (measured like the pan) where it is
+fn zoom(state: &mut State, factor: f32, around: (f32, f32)) {
+    let zoom = (state.zoom * factor).clamp(0.1, 32.);
+    let factor = zoom / state.zoom;
+    state.pan = (around.0 + (state.pan.0 - around.0) * factor, around.1 + (state.pan.1 - around.1) * factor);
+    state.zoom = zoom;
+}
+
+// Copy an image into the regen folder, ready for 'yara regen'. An image of the same name already there is left alone.
+fn send_to_regen(image: &Path, regen_directory: &Path) {
+    let Some(name) = image.file_name() else { return; };
+    let destination = regen_directory.join(name);
+    if destination.exists() {
+        println!("{} is already in the regen folder ({}).", name.to_string_lossy(), regen_directory.display());
+        return;
+    }
+    match fs::create_dir_all(regen_directory).and_then(|()| fs::copy(image, &destination)) {
+        Ok(_) => println!("Copied {} to the regen folder ({}).", name.to_string_lossy(), regen_directory.display()),
+        Err(e) => println!("\x1b[31merror\x1b[0m:// couldn't copy {} to the regen folder: {e}", image.display()),
+    }
+}
+
+fn show_image(state: &mut State, image: Option<PathBuf>) {
+    let size = image.as_ref().and_then(|x| imagesize::size(x).ok());
+    state.image_size = size.as_ref().map(|x| (x.width as u32, x.height as u32));
+    state.ratio = match size {
+        Some(size) => size.width as f64 / size.height as f64,
+        None => 1.,
+    };
+    state.overlay = match &image {
+        Some(image) => match read_image_info(image) {
+            Ok((_, summary)) => overlay_lines(&summary),
+            Err(e) => vec![e],
+        },
+        None => Vec::new(),
+    };
+    state.image = image;
+    // (reloaded even if it's the same file, which may have been saved over)
+    state.last_image = PathBuf::new();
+    state.update_size = true;
+}
+
+// Model, loras, each sampler's settings, and the start of the positive prompt
+fn overlay_lines(summary: &PromptSummary) -> Vec<String> {
+    let mut lines = Vec::new();
+    if !summary.checkpoints.is_empty() {
+        let models: Vec<String> = summary.checkpoints.iter().map(|x| short_model_name(x)).collect();
+        lines.push(format!("Model: {}", models.join(", ")));
+    }
+    for x in &summary.loras {
+        lines.push(format!("Lora: {}{}", short_model_name(&x.name), lora_strength(x)));
+    }
+    for x in &summary.samplers {
+        let mut settings: Vec<String> = Vec::new();
+        if let Some(seed) = x.seed { settings.push(format!("seed {seed}")); }
+        if let Some(steps) = x.steps { settings.push(format!("{steps} steps")); }
+        if let Some(cfg) = x.cfg { settings.push(format!("cfg {cfg}")); }
+        if !settings.is_empty() { lines.push(settings.join(", ")); }
+    }
+    if let Some(first_line) = summary.positive.first().and_then(|x| x.lines().find(|x| !x.trim().is_empty())) {
+        let first_line = first_line.trim();
+        let mut text: String = first_line.chars().take(OVERLAY_PROMPT_LENGTH).collect();
+        if first_line.chars().count() > OVERLAY_PROMPT_LENGTH { text.push_str("..."); }
+        lines.push(text);
+    }
+    if lines.is_empty() {
+        lines.push("No models or settings found in the prompt.".to_string());
+    }
+    lines
+}
+
+// ComfyUI only sends previews and progress to the client that queued the prompt, so prompts queued elsewhere (the browser,
+// or another yara) only show up once saved.
+// Keeps reconnecting if ComfyUI isn't running (or is restarted).
+fn watch_live_previews(ip_port: String, tx: mpsc::Sender<websocket::Event>) {
+    loop {
+        if let Ok(mut client) = EventClient::connect(&ip_port, client_id()) {
+            loop {
+                match client.next_event(Duration::from_secs(60)) {
+                    Ok(Some(websocket::Event::Status { .. })) | Ok(Some(websocket::Event::Other(_))) | Ok(None) => (),
+                    Ok(Some(event)) => {
+                        if tx.send(event).is_err() { return; }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortens_only_long_prompts() {
+        let overlay = |prompt: &str| {
+            let summary = PromptSummary { positive: vec![prompt.to_string()], ..Default::default() };
+            overlay_lines(&summary).pop().unwrap()
+        };
+        assert_eq!(overlay("\n  a café in Zürich, 東京  \nsecond line"), "a café in Zürich, 東京");
+        let long = "é".repeat(OVERLAY_PROMPT_LENGTH + 1);
+        assert_eq!(overlay(&long), format!("{}...", "é".repeat(OVERLAY_PROMPT_LENGTH)));
+        assert_eq!(overlay(&long[..OVERLAY_PROMPT_LENGTH * 2]), "é".repeat(OVERLAY_PROMPT_LENGTH));
+    }
+}
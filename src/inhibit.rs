@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use zbus::blocking::Connection;
+use zbus::zvariant::OwnedFd;
+
+use crate::get_appdata;
+
+// Linux sleep inhibition, through systemd-logind's D-Bus API (the same thing 'systemd-inhibit' uses).
+// logind hands back a file descriptor - the system won't sleep (or idle into sleep) until every copy of it is closed,
+// so the lock is released as soon as it's dropped, or the process holding it exits. No root needed for an active session.
+//
+// 'yara wait' (and so 'yara cwm') holds a lock for as long as it's waiting.
+// 'yara caffeine' can't hold one itself, since it exits straight away - it starts a background yara process that holds
+// the lock until 'yara melatonin' stops it. That process's pid is kept in a file, so melatonin knows what to stop.
+
+const LOGIND_DESTINATION: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+// The hidden argument the background process is started with: 'yara caffeine --hold'
+pub const HOLD_ARG: &str = "--hold";
+
+pub struct InhibitorLock {
+    _fd: OwnedFd,
+}
+
+// Take a sleep inhibitor lock from logind on the given connection (the system bus, outside of tests)
+pub fn inhibit_on(connection: &Connection, why: &str) -> zbus::Result<InhibitorLock> {
+    let reply = connection.call_method(
+        Some(LOGIND_DESTINATION),
+        LOGIND_PATH,
+        Some(LOGIND_MANAGER),
+        "Inhibit",
+        &("sleep:idle", "yara", why, "block"),
+    )?;
+    let fd: OwnedFd = reply.body().deserialize()?;
+    Ok(InhibitorLock { _fd: fd })
+}
+
+pub fn inhibit(why: &str) -> zbus::Result<InhibitorLock> {
+    inhibit_on(&Connection::system()?, why)
+}
+
+// Hold a lock while waiting on ComfyUI's queue. A missing lock isn't worth stopping for, so this only warns.
+pub fn inhibit_while_waiting() -> Option<InhibitorLock> {
+    match inhibit("Waiting for ComfyUI's queue to finish") {
+        Ok(x) => Some(x),
+        Err(e) => {
+            println!("\x1b[33mwarning\x1b[0m:// couldn't stop the computer from sleeping (logind: {e}).");
+            None
+        }
+    }
+}
+
+fn pid_file() -> PathBuf {
+    let mut path: PathBuf = get_appdata().into();
+    path.push("yara");
+    path.push("caffeine.pid");
+    path
+}
+// The pid of the background process holding the lock, if it's still running
+fn running_holder() -> Option<u32> {
+    let pid: u32 = fs::read_to_string(pid_file()).ok()?.trim().parse().ok()?;
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    // Make sure the pid hasn't been reused by something else
+    let is_yara_holder = cmdline.split(|x| *x == 0).any(|x| x == HOLD_ARG.as_bytes());
+    if is_yara_holder { Some(pid) } else { None }
+}
+
+pub fn caffeine() {
+    if let Some(pid) = running_holder() {
+        println!("Computer is already caffeinated (pid {pid}).");
+        return;
+    }
+    let exe = match std::env::current_exe() {
+        Ok(x) => x,
+        Err(e) => { println!("\x1b[31merror\x1b[0m:// couldn't find yara's executable to start the process holding the sleep lock: {e}"); return; }
+    };
+    let mut child = match Command::new(exe).arg("caffeine").arg(HOLD_ARG)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn() {
+        Ok(x) => x,
+        Err(e) => { println!("\x1b[31merror\x1b[0m:// failed to start the process holding the sleep lock: {e}"); return; }
+    };
+
+    // If it can't get a lock, it exits straight away
+    std::thread::sleep(Duration::from_millis(500));
+    if let Ok(Some(_)) = child.try_wait() {
+        println!("\x1b[31merror\x1b[0m:// couldn't stop the computer from sleeping (is systemd-logind running?). Run 'yara caffeine --hold' to see the error.");
+        return;
+    }
+    // Without its pid, melatonin couldn't stop it
+    if let Err(e) = fs::write(pid_file(), child.id().to_string()) {
+        println!("\x1b[31merror\x1b[0m:// couldn't save the pid of the process holding the sleep lock ({}): {e}", pid_file().display());
+        let _ = child.kill();
+        let _ = child.wait();
+        return;
+    }
+    println!("Computer is caffeinated.");
+}
+
+// Run by the background process started by caffeine()
+pub fn hold() {
+    let _lock = match inhibit("yara caffeine") {
+        Ok(x) => x,
+        Err(e) => { println!("\x1b[31merror\x1b[0m:// couldn't take a sleep inhibitor lock from logind: {e}"); return; }
+    };
+    loop {
+        std::thread::park();
+    }
+}
+
+pub fn melatonin() {
+    let Some(pid) = running_holder() else {
+        let _ = fs::remove_file(pid_file());
+        println!("Computer is already sleepy.");
+        return;
+    };
+    match Command::new("kill").arg(pid.to_string()).status() {
+        Ok(x) if x.success() => {
+            let _ = fs::remove_file(pid_file());
+            println!("Computer is sleepy.");
+        }
+        _ => println!("\x1b[31merror\x1b[0m:// failed to stop the process holding the sleep lock (pid {pid})."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // Stands in for logind: hands out the write end of a pipe as the lock, and reports what was asked for
+    struct StandIn {
+        requests: mpsc::Sender<(String, String, String, String)>,
+        lock: std::sync::Mutex<Option<std::os::fd::OwnedFd>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Manager")]
+    impl StandIn {
+        fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::fdo::Result<OwnedFd> {
+            self.requests.send((what.into(), who.into(), why.into(), mode.into())).unwrap();
+            let fd = self.lock.lock().unwrap().take().ok_or(zbus::fdo::Error::Failed("only one lock".into()))?;
+            Ok(fd.into())
+        }
+    }
+
+    fn pipe() -> (UnixStream, std::os::fd::OwnedFd) {
+        let (read, write) = UnixStream::pair().unwrap();
+        (read, std::os::fd::OwnedFd::from(write))
+    }
+
+    #[test]
+    fn takes_and_releases_lock() {
+        let (mut lock_reader, lock_writer) = pipe();
+        let (requests_tx, requests) = mpsc::channel();
+        let stand_in = StandIn { requests: requests_tx, lock: std::sync::Mutex::new(Some(lock_writer)) };
+
+        // A peer-to-peer connection stands in for the system bus
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            zbus::blocking::connection::Builder::unix_stream(server_stream)
+                .server(zbus::Guid::generate()).unwrap()
+                .p2p()
+                .serve_at(LOGIND_PATH, stand_in).unwrap()
+                .build().unwrap()
+        });
+        let client = zbus::blocking::connection::Builder::unix_stream(client_stream).p2p().build().unwrap();
+        let _server = server.join().unwrap();
+
+        let lock = inhibit_on(&client, "testing").unwrap();
+        assert_eq!(requests.recv().unwrap(), ("sleep:idle".into(), "yara".into(), "testing".into(), "block".into()));
+
+        // The stand-in gave away its copy, so the lock is only released (the other end sees EOF) once ours is dropped
+        lock_reader.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(lock_reader.read(&mut buf).unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        drop(lock);
+        lock_reader.set_nonblocking(false).unwrap();
+        assert_eq!(lock_reader.read(&mut buf).unwrap(), 0);
+
+        // Errors from logind are passed on
+        assert!(inhibit_on(&client, "again").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::io::{Read, BufReader, Write};
+use std::io::{self, BufReader, Write};
 use std::path::{PathBuf, Path};
 use std::fs;
 use std::process::Command;
@@ -7,6 +7,7 @@ use std::{thread, time::{Duration, Instant}};
 
 use serde_json::{Value, Map};
 use serde::{Serialize, Deserialize};
+use regex::Regex;
 
 mod config;
 mod image_preview;
@@ -14,19 +15,35 @@ mod civitai;
 mod regen;
 mod fix;
 mod data;
+mod png;
+mod metadata;
+mod websocket;
+mod schema;
+mod graph;
+mod glob;
+mod diff;
+mod watch;
+mod comfyui;
+mod storage;
+#[cfg(target_os = "linux")]
+mod inhibit;
+#[cfg(test)]
+mod test_util;
 
 use regen::regen_modified_workflows;
-use fix::{generate_yara_prompts, fix_workflows_in_folders, fix_workflow_in_file};
-use data::YaraPrompt;
+use fix::{find_image_files, generate_yara_prompts, queue_yara_prompts_on_servers, wait_for_yara_prompts, take_failed_prompts, FailedPrompt, fix_workflows, find_orphaned_workflows};
+use storage::WorkflowStorage;
+use data::{YaraPrompt, client_id};
+use websocket::{EventClient, Event};
+use comfyui::{ComfyError, QueueEntry};
 
 const STATUS: &str = "\x1b[36mstatus\x1b[0m:// ";
 
 use config::{
     get_appdata, 
     Config,
+    Server,
     create_new_config,
-    WorkflowStorage,
-    create_new_workflow_storage
 };
 
 #[derive(Debug)]
@@ -34,6 +51,8 @@ struct PromptInfo {
     id: i64,
     positive: String,
     models: Vec<String>,
+    loras: Vec<String>,
+    seed: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,10 +69,6 @@ enum ImageGenInteractive {
     Repeat,
     Finish,
 }
-#[derive(Serialize, Deserialize)]
-struct RemovePrompts {
-    delete: Vec<String>,
-}
 
 
 fn main() {
@@ -76,27 +91,48 @@ fn main() {
         Err(e) => panic!("Error while reading config file:\n{e}\nTry deleting your config file and running the program again.\n\n"),
     };
 
-    // Load the workflow file
-    let workflow_storage_file = get_appdata() + &"/yara/workflow_storage.json";
-    let file = match std::fs::File::open(&workflow_storage_file) {
-        Ok(x) => x,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { 
-            println!("Workflow storage file not found, creating new file...");
-            create_new_workflow_storage(); 
-            println!("Workflow storage file created!");
-            std::fs::File::open(&workflow_storage_file).unwrap()
+    let workflow_storage = WorkflowStorage::open();
+
+    // '--server NAME' and '--all-servers' can go anywhere, so take them out before reading the command
+    let mut server_name: Option<String> = None;
+    let mut all_servers = false;
+    let mut other_args: Vec<String> = Vec::new();
+    let mut raw_args = std::env::args().skip(1);
+    while let Some(arg) = raw_args.next() {
+        match arg.as_str() {
+            "--server" => {
+                let Some(name) = raw_args.next()
+                    else { println!("\x1b[31merror\x1b[0m:// --server needs the name of a server from the config."); return; };
+                server_name = Some(name);
+            }
+            "--all-servers" => { all_servers = true; }
+            _ => { other_args.push(arg); }
+        }
+    }
+    if all_servers {
+        if server_name.is_some() {
+            println!("\x1b[31merror\x1b[0m:// use either --server or --all-servers, not both."); return;
+        }
+        let cmd = other_args.first().map(|x| x.to_lowercase()).unwrap_or_default();
+        if !matches!(cmd.as_str(), "e" | "examine" | "w" | "wait" | "cwm" | "s" | "save") {
+            println!("\x1b[31merror\x1b[0m:// --all-servers only works with 'examine', 'wait' and 'save'."); return;
+        }
+    }
+
+    let servers: Vec<Server> = if all_servers {
+        reachable_servers(cfg.get_all_servers())
+    } else {
+        match cfg.get_server(server_name.as_deref()) {
+            Some(x) => vec![x],
+            None => return,
         }
-        Err(e) => { panic!("{e}"); }
-    };
-    let reader = BufReader::new(file);
-    let mut workflow_storage: WorkflowStorage = match serde_json::from_reader(reader) {
-        Ok(x) => x,
-        Err(e) => panic!("Error while reading workflow storage file:\n{e}\n\n"),
     };
+    let Some(server) = servers.first().cloned()
+        else { println!("\x1b[31merror\x1b[0m:// couldn't reach any of the ComfyUI servers."); return; };
+    let ip_port = server.ip_port.clone();
+    let ip_ports: Vec<String> = servers.iter().map(|x| x.ip_port.clone()).collect();
 
-    let ip_port = cfg.get_ip_port();
-    
-    let mut args = std::env::args().skip(1);
+    let mut args = other_args.into_iter();
     if let Some(cmd) = args.next() {
         match cmd.to_lowercase().as_str() {
             "list" => {
@@ -104,7 +140,7 @@ fn main() {
             }
             "l" | "load" => {
                 if let Some(arg) = args.next() {
-                    load_queue(arg, &mut workflow_storage, &workflow_storage_file, cfg.comfyui_output_directory, ip_port);
+                    exit_on_error(load_queue(arg, args.collect(), &workflow_storage, &mut cfg, server));
                 } 
                 else { print_help(); }
             }
@@ -113,12 +149,12 @@ fn main() {
                     match arg.as_str() {
                         "-wr" => {
                             if let Some(arg) = args.next() {
-                                save_queue(arg, SaveQueue::All, ip_port);
+                                exit_on_error(save_queue(arg, SaveQueue::All, &ip_ports));
                             } 
                             else { print_help(); }
                         }
                         _ => {
-                            save_queue(arg, SaveQueue::Pending, ip_port);
+                            exit_on_error(save_queue(arg, SaveQueue::Pending, &ip_ports));
                         }
                     }
                 } 
@@ -126,24 +162,83 @@ fn main() {
             }
             "d" | "delete" => {
                 if let Some(arg) = args.next() {
-                    delete_saved_queue(arg);
+                    if let Err(e) = delete_saved_queue(&arg) {
+                        println!("\x1b[31merror\x1b[0m:// couldn't delete saved queue '{arg}': {e}");
+                    }
+                } 
+                else { print_help(); }
+            }
+            "show" => {
+                if let Some(arg) = args.next() {
+                    show_saved_queue(arg);
+                } 
+                else { print_help(); }
+            }
+            "rename" => {
+                if let (Some(old_name), Some(new_name)) = (args.next(), args.next()) {
+                    rename_saved_queue(old_name, new_name);
+                } 
+                else { print_help(); }
+            }
+            "merge" => {
+                let target = args.next();
+                let sources: Vec<String> = args.collect();
+                if let (Some(target), false) = (target, sources.is_empty()) {
+                    merge_saved_queues(target, sources);
+                } 
+                else { print_help(); }
+            }
+            "split" => {
+                if let (Some(name), Some(range), Some(new_name)) = (args.next(), args.next(), args.next()) {
+                    split_saved_queue(name, range, new_name);
                 } 
                 else { print_help(); }
             }
-            "e" | "examine" => { examine_queue(ip_port); }
-            "w" | "wait" => { wait_to_end(ip_port); }
-            "c" | "caffeine" => { caffeine(); }
+            "e" | "examine" => {
+                if servers.len() == 1 {
+                    exit_on_error(examine_queue(ip_port));
+                } else {
+                    let mut total = 0;
+                    for server in servers {
+                        println!("\n\x1b[36m//// {} ////\x1b[0m\n", server.label());
+                        total += exit_on_error(examine_queue(server.ip_port));
+                    }
+                    println!("\n\x1b[36mTotal (all servers):\x1b[0m {total}");
+                }
+            }
+            "w" | "wait" => { exit_on_error(wait_to_end(ip_ports)); }
+            "c" | "caffeine" => {
+                #[cfg(target_os = "linux")]
+                if args.next().as_deref() == Some(inhibit::HOLD_ARG) {
+                    inhibit::hold();
+                    return;
+                }
+                caffeine();
+            }
             "m" | "melatonin" => { melatonin(); }
             "cwm" => {
-                caffeine();
-                wait_to_end(ip_port);
-                melatonin();
+                // On Linux, waiting holds its own sleep lock, and leaves one from 'yara caffeine' alone
+                #[cfg(target_os = "linux")]
+                exit_on_error(wait_to_end(ip_ports));
+                #[cfg(not(target_os = "linux"))]
+                {
+                    caffeine();
+                    let result = wait_to_end(ip_ports);
+                    melatonin();
+                    exit_on_error(result);
+                }
             }
             "p" | "preview" => {
-                let _ = image_preview::notan_main(&cfg);
+                let live = args.any(|x| x == "--live");
+                let _ = image_preview::notan_main(&cfg, server, live);
             }
             "i" | "image" => {
-                while image_generation_info() == ImageGenInteractive::Repeat {}
+                let args: Vec<String> = args.collect();
+                if args.is_empty() {
+                    while image_generation_info() == ImageGenInteractive::Repeat {}
+                } else if !image_info_for_files(args) {
+                    std::process::exit(1);
+                }
             }
             "h" | "help" => {
                 if let Some(arg) = args.next() {
@@ -158,44 +253,30 @@ fn main() {
 
             }
             "cancel" => {
-                let mut ids: Vec<i64> = Vec::new();
-                while let Some(mut arg) = args.next() {
-                    if arg.contains('+') {
-                        arg.retain(|c| c.is_digit(10));
-                        let start = arg.parse::<i64>().unwrap();
-                        for i in start..(start+100) {
-                            ids.push(i);
-                        }
-                    } else if arg.contains("-") {
-                        let nums: Vec<i64> = arg.split("-")
-                            .map(|x| {
-                                let mut x1 = x.to_string();
-                                x1.retain(|c| c.is_digit(10));
-                                x1.parse::<i64>().unwrap()
-                            })
-                            .collect();
-                        for i in nums[0]..(nums[1] + 1) {
-                            ids.push(i);
-                        }
-                    } else {
-                        ids.push(arg.parse::<i64>().unwrap());
-                    }
-                }
-                cancel_generations(ids, ip_port);
+                if let Some(filter) = parse_cancel_args(args.collect()) {
+                    exit_on_error(cancel_generations(filter, ip_port));
+                } 
+                else { print_help(); }
             }
             "config" => {
                 open_config_dir();
             }
             "rg" | "regen" => {
-                let args: Vec<String> = args.collect();
+                let mut args: Vec<String> = args.collect();
+                let mut save_failed: Option<String> = None;
+                if let Some(i) = args.iter().position(|x| x == "--save-failed") {
+                    if i + 1 >= args.len() { print_help_regen(); return; }
+                    save_failed = Some(args.remove(i + 1));
+                    args.remove(i);
+                }
                 let mut yara_prompts: Vec<YaraPrompt> = Vec::new();
                 let mut failures: Vec<PathBuf> = Vec::new();
                 if !args.is_empty() {
                     for path in args {
                         let path = PathBuf::from(path);
-                        if path_is_png_file(path.as_path()) {
-                            match regen_modified_workflows(&PathBuf::from(&path), cfg.get_input_dir(), &ip_port) {
-                                Some(yara_prompt) => yara_prompts.push(yara_prompt),
+                        if path_is_image_file(path.as_path()) {
+                            match regen_modified_workflows(&PathBuf::from(&path), server.input_directory.clone(), &ip_port) {
+                                Some(prompts) => yara_prompts.extend(prompts),
                                 None => failures.push(path),
                             }
                         } else { println!("Error - 'yara regen' doesn't currently support specifying folders to regenerate."); }
@@ -207,9 +288,9 @@ fn main() {
                             \rThis can be changed in the config (run 'yara config' and edit 'config.json').", cfg.get_regen_dir().display()); return; };
                     for entry in entries {
                         let path = entry.unwrap().path();
-                        if path_is_png_file(path.as_path()) {
-                            match regen_modified_workflows(&path, cfg.get_input_dir(), &ip_port) {
-                                Some(yara_prompt) => yara_prompts.push(yara_prompt),
+                        if path_is_image_file(path.as_path()) {
+                            match regen_modified_workflows(&path, server.input_directory.clone(), &ip_port) {
+                                Some(prompts) => yara_prompts.extend(prompts),
                                 None => failures.push(path),
                             }
                         }
@@ -219,29 +300,52 @@ fn main() {
                     println!("\x1b[31m{} images failed regen preparations:\x1b[0m {failures:#?}", failures.len());
                 }
                 if yara_prompts.is_empty() {
-                    println!("No images were detected with Yara regen keywords (!yara_unmute, !yara_mute, or !yara_load_here).");
+                    println!("No images were detected with Yara regen keywords (!yara_unmute, !yara_mute, !yara_load_here, !yara_set, or !yara_seed).");
                 } else {
-                    generate_yara_prompts(yara_prompts, &mut workflow_storage, &workflow_storage_file, cfg.comfyui_output_directory, ip_port);
+                    let failed = generate_yara_prompts(yara_prompts, &workflow_storage, &server);
+                    let saved_to = save_failed.filter(|name| !failed.is_empty() && save_failed_prompts(name, &failed));
+                    if let Some(code) = report_failed_prompts(failed, saved_to.as_deref(), None) {
+                        std::process::exit(code);
+                    }
+                }
+            }
+            "storage" => {
+                match args.next().as_deref() {
+                    None | Some("list") => { list_stored_workflows(&workflow_storage); }
+                    Some("prune") => {
+                        let Some(days) = args.next().and_then(|x| x.parse::<u64>().ok())
+                            else { println!("\x1b[31merror\x1b[0m:// 'yara storage prune' needs a number of days, e.g. 'yara storage prune 30'."); return; };
+                        prune_stored_workflows(&workflow_storage, days);
+                    }
+                    Some("orphans") => {
+                        let dirs = workflow_recovery_dirs(&mut cfg, &server);
+                        report_orphaned_workflows(&workflow_storage, &dirs);
+                    }
+                    Some(x) => { println!("\x1b[31merror\x1b[0m:// unrecognized storage command '{x}' (use list, prune or orphans)."); }
                 }
             }
             "f" | "fix" => {
-                let args: Vec<String> = args.collect();
-                let mut dirs: Vec<PathBuf> = Vec::new();
-                if !args.is_empty() {
-                    for arg in args {
-                        let path = PathBuf::from(arg);
-                        if path.is_dir() {
-                            dirs.push(path);
-                        } else {
-                            fix_workflow_in_file(&mut workflow_storage, &workflow_storage_file, path);
-                        }
-                    } 
-                    if !dirs.is_empty() {
-                        fix_workflows_in_folders(&mut workflow_storage, &workflow_storage_file, dirs);
+                let mut recursive = false;
+                let mut dry_run = false;
+                let mut paths: Vec<PathBuf> = Vec::new();
+                for arg in args {
+                    match arg.as_str() {
+                        "-r" | "--recursive" => { recursive = true; }
+                        "--dry-run" => { dry_run = true; }
+                        _ => { paths.push(PathBuf::from(arg)); }
                     }
-                } else {
-                    fix_workflows_in_folders(&mut workflow_storage, &workflow_storage_file, cfg.get_workflow_recovery_dirs());
                 }
+                if paths.is_empty() {
+                    paths = workflow_recovery_dirs(&mut cfg, &server);
+                }
+                let summary = fix_workflows(&workflow_storage, paths, recursive, dry_run);
+                summary.print(dry_run);
+            }
+            "diff" => {
+                let (Some(old), Some(new), None) = (args.next(), args.next(), args.next()) else { print_help(); return; };
+                let (Some(old_prompt), Some(new_prompt)) = (read_prompt_to_diff(&old), read_prompt_to_diff(&new)) else { std::process::exit(1); };
+                println!("\x1b[31m{old}\x1b[0m -> \x1b[32m{new}\x1b[0m");
+                diff::print_changes(&diff::diff_prompts(&old_prompt, &new_prompt));
             }
             _ => {
                 println!("Unrecognized command.");
@@ -251,270 +355,510 @@ fn main() {
     } else { print_help(); }
 }
 
+// The folders 'yara fix' looks in by default, including the output folder of the server given with --server
+fn workflow_recovery_dirs(cfg: &mut Config, server: &Server) -> Vec<PathBuf> {
+    let mut dirs = cfg.get_workflow_recovery_dirs();
+    if server.name.is_some() && !dirs.contains(&server.output_directory) {
+        dirs.push(server.output_directory.clone());
+    }
+    dirs
+}
 
+// Report an error from ComfyUI and exit, with a code that says what went wrong (see ComfyError::exit_code)
+fn exit_on_error<T>(result: Result<T, ComfyError>) -> T {
+    match result {
+        Ok(x) => x,
+        Err(e) => {
+            println!("\n\x1b[31merror\x1b[0m:// {e}");
+            std::process::exit(e.exit_code());
+        }
+    }
+}
 
-
-
-
-
-
-
-fn save_queue(arg: String, cmd: SaveQueue, ip_port: String) {
-    let queue_data = get_queue(&ip_port);
-
+// With several servers (--all-servers), their queues are saved one after another into the same file
+fn save_queue(arg: String, cmd: SaveQueue, ip_ports: &[String]) -> Result<(), ComfyError> {
     let mut prompts: Vec<YaraPrompt> = Vec::new();
     let mut successes = 0;
     let mut failures = 0;
 
-    if cmd == SaveQueue::All {
-        if let Some(x) = queue_data["queue_running"].as_array() {
-            for p in x {
-                let prompt = p[2].as_object().unwrap().clone();
-                let pnginfo = p[3].as_object().unwrap().get("extra_pnginfo");
-                match pnginfo {
-                    Some(wf) => {
-                        let workflow = wf.as_object().unwrap().get("workflow").unwrap().clone();
-                        prompts.push(YaraPrompt::new(prompt, workflow, &ip_port));
-                        successes += 1;
-                    }
-                    None => {
-                        println!("\x1b[31merror\x1b[0m:// failed to save prompt number {} (could not get workflow metadata).", p[0]);
-                        failures += 1;
-                    }
-                }
-            }
-        }
-    }
+    for ip_port in ip_ports {
+        let queue = comfyui::get_queue_entries(ip_port)?;
 
-    let mut ordered_prompts: Vec<(i64, YaraPrompt)> = Vec::new();
-    if let Some(x) = queue_data["queue_pending"].as_array() {
-        for p in x {
-            let prompt = p[2].as_object().unwrap().clone();
-            let pnginfo = p[3].as_object().unwrap().get("extra_pnginfo");
-            match pnginfo {
-                Some(wf) => {
-                    let workflow = wf.as_object().unwrap().get("workflow").unwrap().clone();
-                    ordered_prompts.push((
-                        p[0].as_i64().unwrap(), 
-                        YaraPrompt::new(prompt, workflow, &ip_port)
-                        ));
+        let mut entries: Vec<&QueueEntry> = Vec::new();
+        if cmd == SaveQueue::All {
+            entries.extend(&queue.running);
+        }
+        let mut pending: Vec<&QueueEntry> = queue.pending.iter().collect();
+        pending.sort_by_key(|x| x.number);
+        entries.extend(pending);
+
+        for entry in entries {
+            match entry.workflow() {
+                Some(workflow) => {
+                    prompts.push(YaraPrompt::new(entry.prompt.clone(), workflow.clone(), ip_port));
                     successes += 1;
                 }
                 None => {
-                    println!("\x1b[31merror\x1b[0m:// failed to save prompt number {} (could not get workflow metadata).", p[0]);
+                    println!("\x1b[31merror\x1b[0m:// failed to save prompt number {} (could not get workflow metadata).", entry.number);
                     failures += 1;
                 }
             }
         }
     }
-    ordered_prompts.sort_by(|a, b| a.0.cmp(&b.0));
-    let (_, x): (Vec<i64>, Vec<YaraPrompt>) = ordered_prompts.iter().cloned().unzip();
-    prompts.extend(x);
 
     println!("{successes} prompts saved. {failures} prompts attempted to save but failed due to no workflow metadata");
 
     if successes > 0 {
-        let path = get_saved_queue_path(arg);
-        serde_json::to_writer(&fs::File::create(path.clone()).unwrap(), &prompts).unwrap();
-        println!("Saved to {}", path.display());
+        match write_saved_queue(&arg, &prompts) {
+            Ok(()) => println!("Saved to {}", get_saved_queue_path(arg).display()),
+            Err(e) => { println!("\x1b[31merror\x1b[0m:// couldn't save queue '{arg}': {e}"); std::process::exit(1); }
+        }
     } else {
         println!("Did not save any prompts");
     }
+    Ok(())
 }
 
 
-fn cancel_generations(prompt_numbers: Vec<i64>, ip_port: String) {
-    let queue_data = get_queue(&ip_port);
+// Which queued prompts 'yara cancel' should remove. A prompt has to match every criterion given.
+struct CancelFilter {
+    // Inclusive ranges of queue numbers. "N+" is (N, i64::MAX).
+    numbers: Vec<(i64, i64)>,
+    model: Option<String>,
+    lora: Option<String>,
+    text: Option<Regex>,
+    skip_confirmation: bool,
+}
+impl CancelFilter {
+    fn uses_prompt_contents(&self) -> bool {
+        self.model.is_some() || self.lora.is_some() || self.text.is_some()
+    }
+    fn matches(&self, pinfo: &PromptInfo) -> bool {
+        let contains = |names: &Vec<String>, x: &str| names.iter().any(|name| name.to_lowercase().contains(&x.to_lowercase()));
+        (self.numbers.is_empty() || self.numbers.iter().any(|(start, end)| (*start..=*end).contains(&pinfo.id)))
+            && self.model.as_ref().is_none_or(|x| contains(&pinfo.models, x))
+            && self.lora.as_ref().is_none_or(|x| contains(&pinfo.loras, x))
+            && self.text.as_ref().is_none_or(|x| x.is_match(&pinfo.positive))
+    }
+}
+
+fn parse_cancel_args(args: Vec<String>) -> Option<CancelFilter> {
+    let mut filter = CancelFilter { numbers: Vec::new(), model: None, lora: None, text: None, skip_confirmation: false };
+    let mut args = args.into_iter();
+    while let Some(mut arg) = args.next() {
+        match arg.as_str() {
+            "--model" => { filter.model = Some(args.next()?); }
+            "--lora" => { filter.lora = Some(args.next()?); }
+            "--text" => {
+                let pattern = args.next()?;
+                match Regex::new(&pattern) {
+                    Ok(x) => { filter.text = Some(x); }
+                    Err(e) => { println!("\x1b[31merror\x1b[0m:// invalid --text regex: {e}"); return None; }
+                }
+            }
+            "-y" | "--yes" => { filter.skip_confirmation = true; }
+            _ if arg.contains('+') => {
+                arg.retain(|c| c.is_ascii_digit());
+                filter.numbers.push((arg.parse::<i64>().ok()?, i64::MAX));
+            }
+            _ if arg.contains('-') => {
+                let (start, end) = arg.split_once('-')?;
+                filter.numbers.push((start.trim().parse::<i64>().ok()?, end.trim().parse::<i64>().ok()?));
+            }
+            _ => {
+                let number = arg.parse::<i64>().ok()?;
+                filter.numbers.push((number, number));
+            }
+        }
+    }
+    if filter.numbers.is_empty() && !filter.uses_prompt_contents() { return None; }
+    Some(filter)
+}
+
+fn cancel_generations(filter: CancelFilter, ip_port: String) -> Result<(), ComfyError> {
+    let queue = comfyui::get_queue_entries(&ip_port)?;
 
     let mut ids: Vec<String> = Vec::new();
     let mut interrupt_active_gen = false;
 
-    if let Some(x) = queue_data["queue_running"].as_array() {
-        for p in x {
-            if prompt_numbers.contains(&p[0].as_i64().unwrap()) {
-                println!("  [\x1b[32m{}\x1b[0m] - {}", p[0].as_i64().unwrap(), p[1].to_string());
-                interrupt_active_gen = true;
-            }
+    for p in &queue.running {
+        if filter.matches(&get_prompt_info(p)) {
+            println!("  [\x1b[32m{}\x1b[0m] - {} (running)", p.number, p.prompt_id);
+            interrupt_active_gen = true;
         }
     }
 
-    if let Some(x) = queue_data["queue_pending"].as_array() {
-        for p in x {
-            if prompt_numbers.contains(&p[0].as_i64().unwrap()) {
-                println!("  [\x1b[32m{}\x1b[0m] - {}", p[0].as_i64().unwrap(), p[1].to_string());
-                ids.push(p[1].as_str().unwrap().to_string());
+    let mut pending: Vec<&QueueEntry> = queue.pending.iter().collect();
+    pending.sort_by_key(|p| p.number);
+    for p in pending {
+        let pinfo = get_prompt_info(p);
+        if filter.matches(&pinfo) {
+            if filter.uses_prompt_contents() {
+                print_prompt_info("", &pinfo);
+            } else {
+                println!("  [\x1b[32m{}\x1b[0m] - {}", p.number, p.prompt_id);
             }
+            ids.push(p.prompt_id.clone());
         }
     }
 
+    if ids.is_empty() && !interrupt_active_gen {
+        println!("No queued prompts matched.");
+        return Ok(());
+    }
 
+    // Filters can match far more than intended, so check first
+    if filter.uses_prompt_contents() && !filter.skip_confirmation {
+        print!("\nCancel {} prompts{}? [y/N] ", ids.len() + interrupt_active_gen as usize, if interrupt_active_gen { " (including the running one)" } else { "" });
+        std::io::stdout().flush().unwrap();
+        let mut buffer = String::new();
+        // (a closed stdin, e.g. in a script, reads as nothing, which is a no)
+        let _ = std::io::stdin().read_line(&mut buffer);
+        if !matches!(buffer.trim().to_lowercase().as_str(), "y" | "yes") {
+            println!("Nothing was cancelled.");
+            return Ok(());
+        }
+    }
 
-    let data = serde_json::to_string(&RemovePrompts{ delete: ids }).unwrap();
-    let response = isahc::post(ip_port.to_string() + &"queue", data).unwrap();
-
-    println!("Queue-Clearing Status: {:?}", response.status());
-    assert!(response.status() == 200);
+    let count = ids.len();
+    comfyui::delete_from_queue(&ip_port, ids)?;
+    println!("Removed {count} prompts from the queue.");
 
     if interrupt_active_gen {
-        let response = isahc::post(ip_port + &"interrupt", "x").unwrap();
-        println!("Active generation interrupted: {:?}", response.status());
+        comfyui::interrupt(&ip_port)?;
+        println!("Active generation interrupted.");
     }
+    Ok(())
 }
 
 
 
 
+// Loaded prompts are removed from the saved queue (unless --keep is given), so loading it again won't queue them twice.
+// With --servers, they're spread across several servers instead of going to just one.
+fn load_queue(arg: String, options: Vec<String>, storage: &WorkflowStorage, cfg: &mut Config, server: Server) -> Result<(), ComfyError> {
+    let Some(mut prompts) = read_saved_queue(&arg) else { return Ok(()); };
 
-fn load_queue(arg: String, storage: &mut WorkflowStorage, workflow_file: &str, output_dir: PathBuf, ip_port: String) {
-    let path = get_saved_queue_path(arg);
-    let file = fs::File::open(path).unwrap();
-    let reader = BufReader::new(file);
-    let yara_prompts: Vec<YaraPrompt> = serde_json::from_reader(reader).unwrap();
-    generate_yara_prompts(yara_prompts, storage, workflow_file, output_dir, ip_port);
+    let mut servers = vec![server];
+    let mut range = 0..prompts.len();
+    let mut reverse = false;
+    let mut front = false;
+    let mut keep = false;
+    let mut save_failed: Option<String> = None;
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--range" => {
+                let Some(r) = options.next().and_then(|x| parse_index_range(&x, prompts.len()))
+                    else { println!("\x1b[31merror\x1b[0m:// --range needs a valid range for '{arg}' (it has {} prompts, numbered from 0), e.g. '--range 10-40'.", prompts.len()); return Ok(()); };
+                range = r;
+            }
+            "--reverse" => { reverse = true; }
+            "--front" => { front = true; }
+            "--keep" => { keep = true; }
+            "--save-failed" => {
+                let Some(name) = options.next()
+                    else { println!("\x1b[31merror\x1b[0m:// --save-failed needs the name of a saved queue to put refused prompts in."); return Ok(()); };
+                save_failed = Some(name);
+            }
+            "--servers" => {
+                let Some(names) = options.next()
+                    else { println!("\x1b[31merror\x1b[0m:// --servers needs a comma-separated list of servers from the config, e.g. '--servers desktop,laptop'."); return Ok(()); };
+                let mut named = Vec::new();
+                for name in names.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                    // "default" is the server set up by the main address/port settings
+                    let Some(server) = cfg.get_server(if name == "default" { None } else { Some(name) }) else { return Ok(()); };
+                    named.push(server);
+                }
+                servers = reachable_servers(named);
+                if servers.is_empty() {
+                    println!("\x1b[31merror\x1b[0m:// couldn't reach any of the servers given to --servers."); return Ok(());
+                }
+            }
+            _ => { println!("\x1b[31merror\x1b[0m:// unrecognized option for 'yara load': {option}"); return Ok(()); }
+        }
+    }
+
+    let mut selected: Vec<YaraPrompt> = prompts[range.clone()].to_vec();
+    if reverse {
+        selected.reverse();
+    }
+    let mut queued = queue_yara_prompts_on_servers(&selected, storage, Some(&arg), &servers, front)?;
+    let failed = take_failed_prompts(&mut queued);
+
+    // Prompts that weren't queued stay in the saved queue, unless --save-failed moves them to another one.
+    // They're only taken out of this one once they're in the other.
+    let saved_to = save_failed.filter(|x| x != &arg && !failed.is_empty() && save_failed_prompts(x, &failed));
+    // Rewritten before waiting, which can take hours (or be cut short), so whatever happens, a prompt is never queued twice
+    if !keep {
+        let accepted = selected.len() - failed.len();
+        remove_queued_prompts(&mut prompts, range, if saved_to.is_some() { &[] } else { &failed });
+        let result = if prompts.is_empty() { delete_saved_queue(&arg) } else { write_saved_queue(&arg, &prompts) };
+        match result {
+            Ok(()) if prompts.is_empty() => println!("{accepted} prompts were queued, and nothing is left in '{arg}', so it was deleted."),
+            Ok(()) => println!("{accepted} prompts were queued and removed from '{arg}' ({} left).", prompts.len()),
+            Err(e) => println!("\x1b[31merror\x1b[0m:// {accepted} prompts were queued, but couldn't be removed from '{arg}' ({e}). Loading it again would queue them twice."),
+        }
+    }
+    // Listed before waiting, which can be cut short
+    let exit_code = report_failed_prompts(failed, saved_to.as_deref(), Some(&arg));
+    wait_for_yara_prompts(queued, storage);
+    if let Some(code) = exit_code {
+        std::process::exit(code);
+    }
+    Ok(())
 }
 
+// Take the prompts in `range` out of a saved queue's prompts, except those in `failed`
+fn remove_queued_prompts(prompts: &mut Vec<YaraPrompt>, range: std::ops::Range<usize>, failed: &[FailedPrompt]) {
+    // (the same prompt can be in a saved queue more than once, so it's by count)
+    let mut not_queued: Vec<&str> = failed.iter().map(|x| x.prompt.hash.as_str()).collect();
+    let mut i = 0;
+    prompts.retain(|x| {
+        i += 1;
+        if !range.contains(&(i - 1)) { return true; }
+        let Some(j) = not_queued.iter().position(|hash| *hash == x.hash) else { return false; };
+        not_queued.swap_remove(j);
+        true
+    });
+}
 
-fn delete_saved_queue(arg: String) {
-    let path = get_saved_queue_path(arg);
-    fs::remove_file(path).unwrap();
+// List the prompts ComfyUI refused, and those that never reached it. `saved_to` is the saved queue they were added to with
+// --save-failed, and `kept_in` the one they're still in, if they came from one.
+// Returns what yara should exit with, so scripts can tell: ComfyError's validation code if any were refused, otherwise the lowest code
+// of the errors that stopped prompts from being sent.
+fn report_failed_prompts(failed: Vec<FailedPrompt>, saved_to: Option<&str>, kept_in: Option<&str>) -> Option<i32> {
+    if failed.is_empty() { return None; }
+    let (refused, unsent): (Vec<&FailedPrompt>, Vec<&FailedPrompt>) = failed.iter().partition(|x| matches!(x.error, ComfyError::Validation { .. }));
+    for (list, header) in [(&refused, "were refused by ComfyUI"), (&unsent, "weren't sent, since their server stopped working")] {
+        if list.is_empty() { continue; }
+        println!("\n\x1b[31m{} prompts {header}:\x1b[0m", list.len());
+        for (i, failure) in list.iter().enumerate() {
+            if let Some(nodes) = failure.prompt.prompt.get("prompt").and_then(|x| x.as_object()) {
+                print_prompt_info("", &get_prompt_info_from_nodes(i as i64, nodes));
+            }
+            println!("{}\n", failure.error);
+        }
+    }
+
+    if let Some(name) = saved_to {
+        println!("Added the prompts that weren't queued to saved queue '{name}'. Load it again with 'yara load {name}' once they're fixed.");
+    } else if let Some(name) = kept_in {
+        println!("They're still in saved queue '{name}'. Use --save-failed [NAME] to move them to another one.");
+    } else {
+        println!("Use --save-failed [NAME] to keep prompts that weren't queued in a saved queue.");
+    }
+    if !refused.is_empty() {
+        return Some(refused[0].error.exit_code());
+    }
+    unsent.iter().map(|x| x.error.exit_code()).min()
 }
 
+// Add prompts that weren't queued to a saved queue (--save-failed), so they can be loaded again once fixed. Says why if it can't.
+fn save_failed_prompts(name: &str, failed: &[FailedPrompt]) -> bool {
+    let existing = if saved_queue_exists(name) { read_saved_queue(name) } else { Some(Vec::new()) };
+    let Some(mut prompts) = existing else { return false; };
+    prompts.extend(failed.iter().map(|x| x.prompt.clone()));
+    if let Err(e) = write_saved_queue(name, &prompts) {
+        println!("\x1b[31merror\x1b[0m:// couldn't add the prompts that weren't queued to saved queue '{name}': {e}");
+        return false;
+    }
+    true
+}
 
 
+fn delete_saved_queue(name: &str) -> io::Result<()> {
+    fs::remove_file(get_saved_queue_path(name.to_string()))
+}
 
+fn read_saved_queue(name: &str) -> Option<Vec<YaraPrompt>> {
+    let path = get_saved_queue_path(name.to_string());
+    let file = match fs::File::open(&path) {
+        Ok(x) => x,
+        Err(e) => { println!("\x1b[31merror\x1b[0m:// couldn't open saved queue '{name}' ({}): {e}", path.display()); return None; }
+    };
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(x) => Some(x),
+        Err(e) => { println!("\x1b[31merror\x1b[0m:// couldn't read saved queue '{name}': {e}"); None }
+    }
+}
+// Written to a temporary file that then replaces the queue, so a failed write never leaves half a queue behind
+fn write_saved_queue(name: &str, prompts: &[YaraPrompt]) -> io::Result<()> {
+    let path = get_saved_queue_path(name.to_string());
+    let temporary = path.with_file_name(format!(".{name}.json.{}.tmp", std::process::id()));
+    let result = fs::write(&temporary, serde_json::to_vec(prompts)?).and_then(|()| fs::rename(&temporary, &path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+fn saved_queue_exists(name: &str) -> bool {
+    get_saved_queue_path(name.to_string()).exists()
+}
 
-fn examine_queue(ip_port: String) {
-    let queue_date = get_queue(&ip_port);
+// Parse an inclusive range of saved queue indices: "10-40", "10+" (10 to the end), or a single "10"
+fn parse_index_range(arg: &str, len: usize) -> Option<std::ops::Range<usize>> {
+    let (start, end) = if let Some(start) = arg.strip_suffix('+') {
+        (start.parse::<usize>().ok()?, len)
+    } else if let Some((start, end)) = arg.split_once('-') {
+        (start.parse::<usize>().ok()?, end.parse::<usize>().ok()? + 1)
+    } else {
+        let i = arg.parse::<usize>().ok()?;
+        (i, i + 1)
+    };
+    if start >= end || end > len { return None; }
+    Some(start..end)
+}
 
-    let mut ordered_prompts: Vec<(i64, PromptInfo)> = Vec::new();
+fn show_saved_queue(name: String) {
+    let Some(prompts) = read_saved_queue(&name) else { return; };
+    for (i, yara_prompt) in prompts.iter().enumerate() {
+        let Some(nodes) = yara_prompt.prompt.get("prompt").and_then(|x| x.as_object())
+            else { println!("\x1b[31m{i}: couldn't read prompt\x1b[0m\n"); continue; };
+        print_prompt_info("", &get_prompt_info_from_nodes(i as i64, nodes));
+        println!();
+    }
+    println!("\x1b[36mTotal:\x1b[0m {}", prompts.len());
+}
 
-    if let Some(x) = queue_date["queue_pending"].as_array() {
-        for p in x {
-            let pinfo = get_prompt_info(p);
-            ordered_prompts.push((p[0].as_i64().unwrap(), pinfo));
-        }
+fn rename_saved_queue(old_name: String, new_name: String) {
+    if !saved_queue_exists(&old_name) {
+        println!("\x1b[31merror\x1b[0m:// there's no saved queue named '{old_name}'."); return;
     }
-    ordered_prompts.sort_by(|a, b| b.0.cmp(&a.0));
+    if saved_queue_exists(&new_name) {
+        println!("\x1b[31merror\x1b[0m:// a saved queue named '{new_name}' already exists."); return;
+    }
+    match fs::rename(get_saved_queue_path(old_name.clone()), get_saved_queue_path(new_name.clone())) {
+        Ok(()) => println!("Renamed '{old_name}' to '{new_name}'."),
+        Err(e) => println!("\x1b[31merror\x1b[0m:// couldn't rename '{old_name}' to '{new_name}': {e}"),
+    }
+}
 
-    for (_, pinfo) in &ordered_prompts {
-        print!("\x1b[32m{}: \x1b[0m", pinfo.id);
-        for model in &pinfo.models {
-            print!("\x1b[32m{model}, \x1b[0m");
-        }
-        println!("\n\x1b[32mPositive:\x1b[0m {}", pinfo.positive);
-        println!("\n");
+// Combine several saved queues into one, in the order given. The original queues are kept.
+fn merge_saved_queues(target: String, sources: Vec<String>) {
+    if saved_queue_exists(&target) && !sources.contains(&target) {
+        println!("\x1b[31merror\x1b[0m:// a saved queue named '{target}' already exists. Include it in the queues to merge if you want to add to it."); return;
     }
-    let mut count = ordered_prompts.len();
+    let mut merged: Vec<YaraPrompt> = Vec::new();
+    for source in &sources {
+        let Some(prompts) = read_saved_queue(source) else { return; };
+        println!("  {source}: {} prompts", prompts.len());
+        merged.extend(prompts);
+    }
+    if let Err(e) = write_saved_queue(&target, &merged) {
+        println!("\x1b[31merror\x1b[0m:// couldn't save the merged queue '{target}': {e}"); return;
+    }
+    println!("Merged {} queues into '{target}' ({} prompts).", sources.len(), merged.len());
+}
 
+// Move a range of prompts (by index, as listed by 'yara show') out of a saved queue, into a new one
+fn split_saved_queue(name: String, range: String, new_name: String) {
+    if saved_queue_exists(&new_name) {
+        println!("\x1b[31merror\x1b[0m:// a saved queue named '{new_name}' already exists."); return;
+    }
+    let Some(mut prompts) = read_saved_queue(&name) else { return; };
+    let Some(range) = parse_index_range(&range, prompts.len())
+        else { println!("\x1b[31merror\x1b[0m:// '{range}' isn't a valid range for '{name}' (it has {} prompts, numbered from 0).", prompts.len()); return; };
+    let split: Vec<YaraPrompt> = prompts.drain(range).collect();
+    if let Err(e) = write_saved_queue(&new_name, &split) {
+        println!("\x1b[31merror\x1b[0m:// couldn't save '{new_name}': {e}"); return;
+    }
+    // Without the prompts taken out of the original, they'd be in both
+    if let Err(e) = write_saved_queue(&name, &prompts) {
+        let _ = delete_saved_queue(&new_name);
+        println!("\x1b[31merror\x1b[0m:// couldn't save '{name}', so nothing was moved: {e}"); return;
+    }
+    println!("Moved {} prompts from '{name}' into '{new_name}' ({} left in '{name}').", split.len(), prompts.len());
+}
 
+// Prints the workflows kept in yara's storage, one line each
+fn list_stored_workflows(storage: &WorkflowStorage) {
+    let entries = storage.entries();
+    for entry in &entries {
+        print_stored_workflow(entry);
+    }
+    println!("\x1b[36mTotal:\x1b[0m {} stored workflows", entries.len());
+}
+fn print_stored_workflow(entry: &storage::StorageEntry) {
+    let queue = entry.queue.as_ref().map(|x| format!(" from '{x}'")).unwrap_or_default();
+    println!("  \x1b[32m{}\x1b[0m  {} old{queue}", entry.hash, storage::format_age(entry.created));
+}
 
-    if let Some(x) = queue_date["queue_running"].as_array() {
-        for p in x {
-            let pinfo = get_prompt_info(p);
-            print!("\x1b[32mRunning {}: \x1b[0m", pinfo.id);
-            for model in pinfo.models {
-                print!("\x1b[32m{model}, \x1b[0m");
+fn prune_stored_workflows(storage: &WorkflowStorage, days: u64) {
+    match storage.prune(days) {
+        Ok(pruned) => {
+            for entry in &pruned {
+                print_stored_workflow(entry);
             }
-            println!("\n\x1b[32mPositive:\x1b[0m {}", pinfo.positive);
-            count += 1;
+            println!("Removed {} stored workflows older than {days} days.", pruned.len());
         }
+        Err(e) => println!("\x1b[31merror\x1b[0m:// failed to remove stored workflows: {e}"),
     }
-
-    println!("\n\x1b[36mTotal:\x1b[0m {count}");
 }
 
+fn report_orphaned_workflows(storage: &WorkflowStorage, dirs: &[PathBuf]) {
+    let orphans = find_orphaned_workflows(storage, dirs);
+    for entry in &orphans {
+        print_stored_workflow(entry);
+    }
+    println!("{} of {} stored workflows aren't needed by any image in {dirs:?}.", orphans.len(), storage.entries().len());
+    if !orphans.is_empty() {
+        println!("Their images were never generated (or still haven't been), were moved, or already have their workflows. Remove old ones with 'yara storage prune [DAYS]'.");
+    }
+}
 
-fn get_prompt_info(p: &Value) -> PromptInfo {
-    let queue_id = p[0].as_i64().unwrap();
-    let nodes = p[2].as_object().unwrap();
+fn examine_queue(ip_port: String) -> Result<usize, ComfyError> {
+    let queue = comfyui::get_queue_entries(&ip_port)?;
 
-    // println!("Queue ID: {queue_id}");
+    let mut ordered_prompts: Vec<(i64, PromptInfo)> = queue.pending.iter().map(|p| (p.number, get_prompt_info(p))).collect();
+    ordered_prompts.sort_by(|a, b| b.0.cmp(&a.0));
 
-    let mut nodemap: HashMap<u64, Value> = HashMap::new();
-    let mut sampler_id: u64 = 0;
-    for n in nodes {
-        if (n.1["class_type"] == "KSampler") | (n.1["class_type"] == "KSamplerAdvanced") | (n.1["class_type"] == "SamplerCustom") {
-            sampler_id = n.0.to_string().parse::<u64>().unwrap();
-        }
-        nodemap.insert(n.0.to_string().parse::<u64>().unwrap(), n.1.clone());
+    for (_, pinfo) in &ordered_prompts {
+        print_prompt_info("", pinfo);
+        println!("\n");
     }
+    let mut count = ordered_prompts.len();
 
-    // Get models (lora -> ... -> lora -> model)
-    let mut curr_node_id: u64 = sampler_id;
-    let mut models: Vec<String> = Vec::new();
-    loop {
-        let curr_node = nodemap.get(&curr_node_id).unwrap();
-        if curr_node["class_type"] == "LoraLoader" {
-            let mut name = curr_node["inputs"]["lora_name"].to_string();
-            if name.contains(".safetensors") {
-                name = name.replace(".safetensors", "");
-            }
-            name = name.replace("\"", "");
-            models.push(name);
-        }
-        if curr_node["class_type"] == "CheckpointLoaderSimple" {
-            let mut name = curr_node["inputs"]["ckpt_name"].to_string();
-            if name.contains(".safetensors") {
-                name = name.replace(".safetensors", "");
-            }
-            name = name.replace("\"", "");
-            models.push(name);
-        }
-
-        if let Some(input_model_id) = curr_node["inputs"].get("model") {
-            curr_node_id = input_model_id[0].as_u64().unwrap();
-        } 
-        else {
-            break;
-        }
+    for p in &queue.running {
+        let pinfo = get_prompt_info(p);
+        print_prompt_info("Running ", &pinfo);
+        count += 1;
     }
-    // println!("models: {models:?}");
 
-    // Get positive prompt
-    let mut curr_node_id: u64 = sampler_id;
-    let mut p_prompt = String::new();
-    // TO-DO: Refactor this section
-    loop {
-        let curr_node = nodemap.get(&curr_node_id).unwrap();
-        if curr_node["class_type"] == "PromptText" {
-            p_prompt = curr_node["inputs"]["text"].to_string();
-            break;
-        }
-        if curr_node["class_type"] == "CLIPTextEncode" {
-            if let Some(input_node_id) = curr_node["inputs"].get("text") {
-                curr_node_id = match input_node_id[0].as_u64() {
-                    Some(x) => x,
-                    None => {
-                        p_prompt = curr_node["inputs"]["text"].to_string();
-                        break;
-                    }
-                }
-            } else {
-                p_prompt = curr_node["inputs"]["text"].to_string();
-                break;
-            }
-        }
+    println!("\n\x1b[36mTotal:\x1b[0m {count}");
+    Ok(count)
+}
 
-        else if let Some(input_node_id) = curr_node["inputs"].get("positive") {
-            curr_node_id = input_node_id[0].as_u64().unwrap();
-        } 
-        else if let Some(input_node_id) = curr_node["inputs"].get("conditioning") {
-            curr_node_id = input_node_id[0].as_u64().unwrap();
-        }
-        else {
-            break;
-        }
-    }
-    p_prompt = p_prompt.replace("\"", "");
-    p_prompt = p_prompt.replace("/n", " ");
-    // println!("positive prompt: {p_prompt:#?}");
 
+fn get_prompt_info(p: &QueueEntry) -> PromptInfo {
+    get_prompt_info_from_nodes(p.number, &p.prompt)
+}
+fn get_prompt_info_from_nodes(id: i64, nodes: &Map<String, Value>) -> PromptInfo {
+    let summary = graph::analyze(nodes);
     PromptInfo {
-        id: queue_id,
-        positive: p_prompt,
-        models: models,
+        id,
+        positive: summary.positive.join(" ").replace('\n', " "),
+        models: summary.checkpoints.iter().map(|x| short_model_name(x)).collect(),
+        loras: summary.loras.iter().map(|x| short_model_name(&x.name)).collect(),
+        seed: summary.samplers.iter().find_map(|x| x.seed),
     }
-
+}
+// "sdxl/base.safetensors" -> "sdxl/base"
+fn short_model_name(name: &str) -> String {
+    name.strip_suffix(".safetensors").unwrap_or(name).to_string()
+}
+fn print_prompt_info(label: &str, pinfo: &PromptInfo) {
+    print!("\x1b[32m{label}{}: \x1b[0m", pinfo.id);
+    for model in &pinfo.models {
+        print!("\x1b[32m{model}, \x1b[0m");
+    }
+    for lora in &pinfo.loras {
+        print!("\x1b[33m{lora}, \x1b[0m");
+    }
+    if let Some(seed) = pinfo.seed {
+        print!("\x1b[36mseed {seed}\x1b[0m");
+    }
+    println!("\n\x1b[32mPositive:\x1b[0m {}", pinfo.positive);
 }
 
 fn count_queue(queue_data: Value) -> usize {
@@ -527,53 +871,71 @@ fn count_queue(queue_data: Value) -> usize {
 }
 
 
-fn wait_to_end(ip_port: String) {
-    let count = count_queue(get_queue(&ip_port));
+// Waits until every given server's queue is empty (more than one with --all-servers)
+fn wait_to_end(ip_ports: Vec<String>) -> Result<(), ComfyError> {
+    // Don't let the computer sleep while ComfyUI is still working. The lock is released when this returns.
+    #[cfg(target_os = "linux")]
+    let _sleep_lock = inhibit::inhibit_while_waiting();
+
+    // Listen for each server's queue status messages. Servers without a websocket have their queue polled instead.
+    struct Waiting {
+        ip_port: String,
+        events: Option<EventClient>,
+        count: usize,
+    }
+    let mut waiting: Vec<Waiting> = Vec::new();
+    for ip_port in ip_ports {
+        waiting.push(Waiting {
+            count: count_queue(comfyui::get_queue(&ip_port)?),
+            events: EventClient::connect(&ip_port, client_id()).ok(),
+            ip_port,
+        });
+    }
+
     let now_total_time = Instant::now();
-    let elapsed = format_seconds(now_total_time.elapsed().as_secs());
-    print!("{STATUS}[{elapsed}] waiting until queue is empty... (\x1b[36m{count}\x1b[0m items remaining)");
-    std::io::stdout().flush().unwrap();
     loop {
-        let queue_data = get_queue(&ip_port);
-        if let (Some(x), Some(y)) = (queue_data["queue_running"].as_array(), queue_data["queue_pending"].as_array()) {
-            if x.is_empty() & y.is_empty() {
-                println!("\nQueue is empty.");
-                break;
-            }
-        }
-        thread::sleep(Duration::from_secs(1));
-
-        let count = count_queue(queue_data);
+        let count: usize = waiting.iter().map(|x| x.count).sum();
         let elapsed = format_seconds(now_total_time.elapsed().as_secs());
         print!("\r\r{STATUS}[{elapsed}] waiting until queue is empty... (\x1b[36m{count}\x1b[0m items remaining)");
         std::io::stdout().flush().unwrap();
+        if count == 0 {
+            println!("\nQueue is empty.");
+            break;
+        }
+
+        // Share the second between the servers that are listened to; only sleep if there are none
+        let connected = waiting.iter().filter(|x| x.events.is_some()).count();
+        if connected == 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        for server in &mut waiting {
+            match &mut server.events {
+                Some(client) => match client.next_event(Duration::from_millis(1000 / connected as u64)) {
+                    Ok(Some(Event::Status { queue_remaining })) => { server.count = queue_remaining as usize; }
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("\n\x1b[33mwarning\x1b[0m:// lost connection to ComfyUI's websocket at {} ({e}), polling the queue instead.", server.ip_port);
+                        server.events = None;
+                    }
+                },
+                None => { server.count = count_queue(comfyui::get_queue(&server.ip_port)?); }
+            }
+        }
     }
     println!("\nFinished waiting - took {}", format_seconds(now_total_time.elapsed().as_secs()));
+    Ok(())
 }
 
 
-// Return ID of the node going into this input field
-fn get_input_node_id(node: &Value, field: &str) -> String {
-    let x = &mut node["inputs"].as_object().unwrap()[field][0].to_string();
-    x.retain(|c| ['1', '2', '3', '4', '5', '6', '7', '8', '9', '0', '-'].contains(&c));
-    x.parse::<usize>().unwrap().to_string()
-}
-
-
-
-
-
-
-
-
 fn image_generation_info() -> ImageGenInteractive {
-    use clipboard::{ClipboardProvider, ClipboardContext};
-
     // get input
     println!("Enter image filepath, or 'q' to quit:");
 
     let mut buffer = String::new();
-    std::io::stdin().read_line(&mut buffer).unwrap();
+    // Stop at the end of stdin (e.g. when it's piped in), rather than asking forever
+    if !matches!(std::io::stdin().read_line(&mut buffer), Ok(x) if x > 0) {
+        return ImageGenInteractive::Finish;
+    }
     let input = buffer.trim_end();
     if (input.to_lowercase() == "q") | (input.to_lowercase() == "quit") {
         return ImageGenInteractive::Finish;
@@ -581,195 +943,232 @@ fn image_generation_info() -> ImageGenInteractive {
     let path: PathBuf = input.to_string().replace("\"", "").into();
     println!();
 
+    match read_image_info(&path) {
+        Ok((prompt_json, summary)) => {
+            print_image_info(&summary);
+            copy_to_clipboard(&prompt_json);
+        }
+        Err(e) => println!("{e}"),
+    }
+    println!("--------------\n");
+    ImageGenInteractive::Repeat
+}
 
+// The prompt embedded in an image, and what it does
+fn read_image_info(path: &Path) -> Result<(Value, graph::PromptSummary), String> {
+    let texts = metadata::read_texts_from_file(path).map_err(|e| format!("Failed to read image metadata: {e}"))?;
+    let Some(prompt_string) = metadata::find_text(&texts, "prompt")
+        else { return Err("Image has no embedded generation data.".to_string()); };
+    let prompt_json: Value = serde_json::from_str(prompt_string).map_err(|e| format!("Failed to parse embedded generation data: {e}"))?;
+    let Some(nodes) = prompt_json.as_object()
+        else { return Err("Embedded generation data is not a prompt.".to_string()); };
+    let summary = graph::analyze(nodes);
+    Ok((prompt_json, summary))
+}
 
-    // open image as bytes, turn to string
-    let mut buffer: Vec<u8> = Vec::new();
-    let mut file = match fs::File::open(path) {
-        Ok(x) => x,
-        Err(e) => { println!("Failed to open file: {e}");  return ImageGenInteractive::Repeat; }
-    };
-    file.read_to_end(&mut buffer).unwrap();
-    let text = String::from_utf8_lossy(&buffer);
-
-
-    // get prompt json
-    let text = &text.split("tEXtprompt\0{").map(|x| x.to_string()).collect::<Vec<String>>()[1];
-    let mut brackets = 1;
-    let mut prompt_string = String::from("{");
-    for c in text.chars() {
-        match c {
-            '{' => { brackets += 1; prompt_string.push(c); }
-            '}' => { brackets -= 1; prompt_string.push(c); }
-            _ => { prompt_string.push(c); }
-        }
-        if brackets == 0 { break; }
+// " (0.8)", or " (0.8 model, 0.5 clip)" when they differ
+fn lora_strength(lora: &graph::Lora) -> String {
+    match (lora.strength_model, lora.strength_clip) {
+        (Some(model), Some(clip)) if model != clip => format!(" ({model} model, {clip} clip)"),
+        (Some(x), _) | (None, Some(x)) => format!(" ({x})"),
+        (None, None) => String::new(),
     }
-    let prompt_json: Value = serde_json::from_str(&prompt_string).unwrap();
-    let nodes = prompt_json.as_object().unwrap();
+}
 
+fn print_image_info(summary: &graph::PromptSummary) {
+    for x in &summary.checkpoints { println!("\x1b[32mModel\x1b[0m: {x}"); }
+    for x in &summary.loras { println!("\x1b[32mLora\x1b[0m: {}{}", x.name, lora_strength(x)); }
+    for x in &summary.controlnets { println!("\x1b[32mControlnet\x1b[0m: {x}"); }
+    for x in &summary.vaes { println!("\x1b[32mVAE\x1b[0m: {x}"); }
+    for x in &summary.samplers {
+        let mut settings: Vec<String> = Vec::new();
+        if let Some(seed) = x.seed { settings.push(format!("seed {seed}")); }
+        if let Some(steps) = x.steps { settings.push(format!("{steps} steps")); }
+        if let Some(cfg) = x.cfg { settings.push(format!("cfg {cfg}")); }
+        settings.extend(x.sampler_name.clone());
+        settings.extend(x.scheduler.clone());
+        if let Some(denoise) = x.denoise.filter(|x| *x != 1.0) { settings.push(format!("denoise {denoise}")); }
+        println!("\x1b[32mSampler\x1b[0m: {} - {}", x.class_type, settings.join(", "));
+    }
+    if let Some((width, height)) = summary.size { println!("\x1b[32mSize\x1b[0m: {width}x{height}"); }
+    for x in &summary.positive { println!("\x1b[32mPositive\x1b[0m: {x}"); }
+    for x in &summary.negative { println!("\x1b[32mNegative\x1b[0m: {x}"); }
+}
 
-    // Follow conditioning from Sampler until you reach Text
-    fn push_preceding_text(nodes: &Map<String, Value>, mut id: String, prompts: &mut Vec<String>, controlnets: &mut Vec<String>) {
-        for _ in 0..nodes.len() {
-            let target = nodes.get(&id.to_string()).unwrap();
-            match target["class_type"].as_str() {
-                Some("PromptText") => {
-                    prompts.push("\n".to_string() + &target["inputs"].as_object().unwrap()["text"].as_str().unwrap().to_string());
-                    return;
-                }
-                Some("CLIPTextEncode")  => {
-                    match target["inputs"].as_object().unwrap()["text"].as_str() {
-                        Some(x) => {
-                            prompts.push("\n".to_string() + &x.to_string());
-                            return;
-                        }
-                        None => {
-                            target["inputs"].as_object().unwrap();
-                            id = get_input_node_id(target, "text");
-                        }
-                    }
-                }
-                Some("ControlNetApply") => {
-                    let cid = get_input_node_id(target, "control_net");
-                    let controlnet_target = nodes.get(&cid.to_string()).unwrap();
-                    controlnets.push(controlnet_target["inputs"].as_object().unwrap()["control_net_name"].as_str().unwrap().to_string());
-                    id = get_input_node_id(target, "conditioning");
-                }
-                _ => {
-                    id = get_input_node_id(target, "conditioning");
-                }
-            }
+// Copy the full generation data to the clipboard, including newlines
+fn copy_to_clipboard(prompt_json: &Value) {
+    use clipboard::{ClipboardProvider, ClipboardContext};
+    let copied = ClipboardProvider::new().and_then(|mut ctx: ClipboardContext| ctx.set_contents(serde_json::to_string_pretty(prompt_json).unwrap()));
+    match copied {
+        Ok(()) => println!("Full generation data copied to clipboard."),
+        Err(e) => println!("\x1b[33mwarning\x1b[0m:// couldn't copy the generation data to the clipboard: {e}"),
+    }
+}
+
+// What 'yara diff' compares: an image's embedded prompt, or an entry of a saved queue written as NAME:INDEX (as 'yara show' numbers them)
+fn read_prompt_to_diff(arg: &str) -> Option<Map<String, Value>> {
+    if let Some((name, index)) = arg.rsplit_once(':') {
+        if let (Ok(index), true) = (index.parse::<usize>(), !Path::new(arg).exists() && saved_queue_exists(name)) {
+            let prompts = read_saved_queue(name)?;
+            let Some(yara_prompt) = prompts.get(index)
+                else { println!("\x1b[31merror\x1b[0m:// saved queue '{name}' only has {} prompts (0 to {}).", prompts.len(), prompts.len().saturating_sub(1)); return None; };
+            let Some(nodes) = yara_prompt.prompt.get("prompt").and_then(|x| x.as_object())
+                else { println!("\x1b[31merror\x1b[0m:// couldn't read prompt {index} of saved queue '{name}'."); return None; };
+            return Some(nodes.clone());
         }
-        panic!("Failed to find the originating node for a sampler's input positive/negative text");
     }
+    match read_image_info(Path::new(arg)) {
+        Ok((Value::Object(nodes), _)) => Some(nodes),
+        Ok(_) => None,
+        Err(e) => { println!("\x1b[31merror\x1b[0m:// {arg}: {e}"); None }
+    }
+}
 
-    // Follow model from Sampler, accumulating loras and the model at the end
-    fn push_preceding_models(nodes: &Map<String, Value>, mut id: String, loras: &mut Vec<String>, models: &mut Vec<String>) {
-        for _ in 0..nodes.len() {
-            let target = nodes.get(&id.to_string()).unwrap();
-            match target["class_type"].as_str() {
-                Some("CheckpointLoaderSimple") | Some("CheckpointLoader") => {
-                    models.push(target["inputs"].as_object().unwrap()["ckpt_name"].as_str().unwrap().to_string());
-                    return;
-                }
-                Some("LoraLoader") => {
-                    loras.push(target["inputs"].as_object().unwrap()["lora_name"].as_str().unwrap().to_string());
-                    id = get_input_node_id(target, "model");
-                }
-                _ => {
-                    id = get_input_node_id(target, "model");
+// 'yara image FILES...': the generation info of every image given (or in the folders given, or matching the patterns given).
+// Returns whether every image could be read.
+fn image_info_for_files(args: Vec<String>) -> bool {
+    let mut json = false;
+    let mut clipboard = true;
+    let mut recursive = false;
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut failures: Vec<(PathBuf, String)> = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--json" => { json = true; }
+            "--no-clipboard" => { clipboard = false; }
+            "-r" | "--recursive" => { recursive = true; }
+            _ if glob::has_wildcards(&arg) => {
+                let matched = glob::expand(&arg);
+                if matched.is_empty() {
+                    failures.push((PathBuf::from(&arg), "no files match this pattern".to_string()));
                 }
+                paths.extend(matched.into_iter().filter(|x| x.is_dir() || path_is_image_file(x)));
             }
+            _ => paths.push(PathBuf::from(arg)),
         }
-        panic!("Failed to find the originating node for a sampler's input positive/negative text");
     }
-
-
-
-
-    let mut pprompts: Vec<String> = Vec::new();
-    let mut nprompts: Vec<String> = Vec::new();
-    let mut controlnets: Vec<String> = Vec::new();
-    let mut loras: Vec<String> = Vec::new();
-    let mut models: Vec<String> = Vec::new();
-
-
-    for node in nodes.values() {
-        if let Some("KSampler") | Some("KSamplerAdvanced") = node["class_type"].as_str() {
-            // Find positive prompt
-            let pid = get_input_node_id(node, "positive");
-            push_preceding_text(nodes, pid, &mut pprompts, &mut controlnets);
-
-            // Find negative prompt
-            let nid = get_input_node_id(node, "negative");
-            push_preceding_text(nodes, nid, &mut nprompts, &mut controlnets);
-
-            // Find loras and originating model
-            let mid = get_input_node_id(node, "model");
-            push_preceding_models(nodes, mid, &mut loras, &mut models,);
+    let mut images: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            find_image_files(&path, recursive, &mut images, &mut failures);
+        } else {
+            images.push(path);
         }
     }
 
-    models.dedup();
-    loras.dedup();
-    pprompts.dedup();
-    nprompts.dedup();
-    controlnets.dedup();
-
-    for text in pprompts.iter_mut() {
-        while text.contains("\n\n") {
-            *text = text.replace("\n\n", "\n");
+    let mut results: Vec<Value> = Vec::new();
+    let mut last_prompt: Option<Value> = None;
+    for path in &images {
+        match read_image_info(path) {
+            Ok((prompt_json, summary)) => {
+                if json {
+                    let mut entry = serde_json::to_value(&summary).unwrap();
+                    entry["file"] = Value::String(path.display().to_string());
+                    results.push(entry);
+                } else {
+                    println!("\x1b[36m{}\x1b[0m", path.display());
+                    print_image_info(&summary);
+                    println!();
+                }
+                last_prompt = Some(prompt_json);
+            }
+            Err(e) => failures.push((path.clone(), e)),
         }
     }
-    for text in nprompts.iter_mut() {
-        while text.contains("\n\n") {
-            *text = text.replace("\n\n", "\n");
+
+    if json {
+        results.extend(failures.iter().map(|(path, e)| serde_json::json!({ "file": path.display().to_string(), "error": e })));
+        println!("{}", serde_json::to_string_pretty(&results).unwrap());
+    } else {
+        for (path, e) in &failures {
+            println!("\x1b[31merror\x1b[0m:// {}: {e}", path.display());
+        }
+        if images.is_empty() && failures.is_empty() {
+            println!("No images found.");
         }
     }
-
-    for x in models { println!("\x1b[32mModel\x1b[0m: {x}"); }
-    for x in loras { println!("\x1b[32mLora\x1b[0m: {x}"); }
-    for x in controlnets { println!("\x1b[32mControlnet\x1b[0m: {x}"); }
-    for x in pprompts { println!("\x1b[32mPositive\x1b[0m: {x}"); }
-    for x in nprompts { println!("\x1b[32mNegative\x1b[0m: {x}"); }
-
-
-    // copy json string to clipboard, including newlines
-    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-    let x = serde_json::to_string_pretty(&prompt_json).unwrap();
-    ctx.set_contents(x).unwrap();
-    println!("Full generation data copied to clipboard.");
-    println!("--------------\n");
-    return ImageGenInteractive::Repeat;
+    // Only one image's data fits on the clipboard, so only copy it when there's one
+    match last_prompt {
+        Some(prompt_json) if clipboard && images.len() == 1 && !json => copy_to_clipboard(&prompt_json),
+        _ => (),
+    }
+    failures.is_empty()
 }
 
-
-
-
-
-fn get_queue(ip_port: &str) -> Value {
-    let mut response = isahc::get(ip_port.to_string() + &"queue").unwrap();
-    let mut buf = String::new();
-    response.body_mut().read_to_string(&mut buf).unwrap();
-    let json_data: Value = serde_json::from_str(&buf).unwrap();
-    json_data
+// With --all-servers, skip any server that isn't running instead of stopping
+fn reachable_servers(servers: Vec<Server>) -> Vec<Server> {
+    servers.into_iter().filter(|server| match comfyui::get_queue(&server.ip_port) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("\x1b[33mwarning\x1b[0m:// skipping ComfyUI server {}: {e}", server.label());
+            false
+        }
+    }).collect()
 }
 
 
-fn path_is_png_file(path: &Path) -> bool {
-    if path.is_dir() { return false; }
-    if let Some(ext) = path.extension() {
-        if ext == "png" { true }
-        else { false }
-    } else { false }
+// PNG, JPEG or WebP (see metadata.rs)
+fn path_is_image_file(path: &Path) -> bool {
+    metadata::is_image_file(path)
 }
 
-
-
-
-
-
-
 fn print_help() {
     println!("Usage:
         yara                       print saved queues
         yara save [NAME]           save a queue as [specified] name
         yara save -wr [NAME]       save [specified] queue with running prompt included
-        yara load [NAME]           load [specified] queue
+        yara load [NAME]           load [specified] queue (loaded prompts are removed from the saved queue)
+            --range [RANGE]            only load some prompts, by index (as listed by 'yara show'), e.g. '--range 10-40' or '10+'
+            --reverse                  load the prompts in reverse order
+            --front                    put the prompts at the front of ComfyUI's queue, ahead of anything already queued
+            --keep                     keep the loaded prompts in the saved queue
+            --save-failed [NAME]       move any prompts ComfyUI refuses to another saved queue (they stay in this one otherwise)
+            --servers [NAMES]          spread the prompts across several servers from the config, by how full their queues are
+                                           e.g. '--servers desktop,laptop' ('default' is the main server)
         yara delete [NAME]         delete [specified] queue
+        yara show [NAME]           list the prompts in a saved queue, with their models, loras, seed and positive prompt
+        yara rename [OLD] [NEW]    rename a saved queue
+        yara merge [NEW] [NAMES]   combine saved queues into one (the originals are kept)
+        yara split [NAME] [RANGE] [NEW]
+                                   move prompts from a saved queue into a new one, by index (as listed by 'yara show')
+                                       e.g. 'yara split overnight 10-40 tomorrow', or '10+' for 10 onward
         yara examine               list active queue, showing positive prompt and models
         yara caffeine              disable computer's sleep mode
         yara melatonin             enable computer's sleep mode
         yara wait                  wait until all prompts have finished
         yara preview               create a window previewing new files in the output directory
-        yara image                 check embedded generation data of images
-        yara clear [PROMPT_IDS]    delete queued generations by numerical ID
-                                       e.g. 'yara clear 250 251 252'
+                                       in the window: Left/Right step through recent images, P pins one, +/- or the wheel zoom,
+                                       1 shows pixels 1:1, C copies the image's path, R copies the image to the regen folder,
+                                       I shows the model, loras, seed and prompt over the image
+            --live                     also show ComfyUI's previews while an image is being sampled, with a progress bar
+        yara image [PATHS]         check embedded generation data of images (interactive without paths)
+                                       paths can be images, folders, or patterns like 'output/2024-*/*.png'
+            --json                     print the info as JSON, for scripts
+            --no-clipboard             don't copy the generation data to the clipboard
+            --recursive                also look in subfolders of the folders given
+        yara diff [A] [B]          show what's different between the prompts of two images, or saved queue entries as NAME:INDEX
+                                       e.g. 'yara diff ComfyUI_00021_.png ComfyUI_00022_.png', 'yara diff overnight:3 overnight:4'
+        yara cancel [PROMPT_IDS]   delete queued generations by numerical ID
+                                       e.g. 'yara cancel 250 251 252', 'yara cancel 250-260', or '250+' for 250 onward
+            --model [NAME]             only cancel prompts using a matching model
+            --lora [NAME]              only cancel prompts using a matching lora
+            --text [REGEX]             only cancel prompts whose positive prompt matches
+            --yes                      don't ask for confirmation when cancelling by model/lora/text
         yara config                open directory of config file
         yara cai [URLs]            download CivitAI models/loras/etc, copying relevant info to clipboard
         yara regen [FILEPATHS]     regenerate images, modifying marked nodes (more info: run 'yara help regen')
+            --save-failed [NAME]       add any prompts ComfyUI refuses to a saved queue, to retry later
         yara fix [PATHS]           search specified folders or files, try to embed missing workflows into images
+            --recursive                also search subfolders (e.g. ComfyUI's date folders)
+            --dry-run                  only list which images would get which stored workflow
+        yara storage               list stored workflows (kept until they're in their images), with their age and saved queue
+        yara storage prune [DAYS]  remove stored workflows older than [DAYS] days
+        yara storage orphans       list stored workflows that no image in the 'yara fix' folders is missing
+
+        Any command:
+            --server [NAME]            use a ComfyUI server from the config's 'servers' instead of the default one
+        examine, wait, save:
+            --all-servers              use the default server and every server in the config together
         ");
 }
 
@@ -781,14 +1180,20 @@ fn print_help_regen() {
 
         To mark a node, modify the node title in ComfyUI (right click -> Title) and add the keyword.
         Valid keywords:
-            !yum  or  !yara_unmute           Unmute this node. Only valid on KSampler nodes.
+            !yum  or  !yara_unmute           Unmute this node.
             !ym   or  !yara_mute             Mute this node.
             !ylh  or  !yara_load_here        Replace this node with a LoadImage node, to load the original image.
+            !yset or  !yara_set KEY=VALUE    Change input values on this node, e.g. '!yset denoise=0.45 steps=30'.
+            !yseed or !yara_seed [N]         Give this node (a sampler or seed primitive) a random seed. With N, make N prompts
+                                             with the N seeds after the image's seed.
 
         You can specify specific images to regenerate by adding their full filepaths as arguments (in Windows,
         you can simply drag/drop the files to the terminal window). Alternatively, with no additional arguments,
         'yara regen' will regenerate all images in the 'ComfyUI/output/regen' folder. The folder to get images from
         can be customized in the config file ('yara config' -> config.json).
+
+        If ComfyUI refuses any of the prompts (e.g. a custom node or model is missing), they're skipped and listed at
+        the end. Add '--save-failed [NAME]' to put them in a saved queue, to load again once the problem is fixed.
         ");
 }
 
@@ -865,9 +1270,9 @@ fn open_config_dir() {
 }
 #[cfg(any(target_os = "linux"))]
 fn caffeine() {
-    println!("Sleep mode toggles not currently implemented for linux (i'm lazy sorry)");
+    inhibit::caffeine();
 }
 #[cfg(any(target_os = "linux"))]
 fn melatonin() {
-    println!("Sleep mode toggles not currently implemented for linux (i'm lazy sorry)");
+    inhibit::melatonin();
 }
\ No newline at end of file
@@ -0,0 +1,874 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::png;
+pub use crate::png::{TextChunk, find_text};
+
+// Reading and writing the "prompt" and "workflow" an image was generated with, whatever format it's in.
+//
+// PNG keeps them in text chunks (see png.rs). JPEG and WebP have nothing like that, so save nodes put them in one of:
+//     EXIF - ComfyUI's own WebP saving writes "prompt:{...}" into the Model tag and "workflow:{...}" into Make. Custom save
+//            nodes use ImageDescription or UserComment, either the same way or with the bare JSON in them.
+//     XMP  - an XML packet, with the JSON as a property (element or attribute) named "prompt" or "workflow", in any namespace.
+// In a JPEG both are APP1 segments (plus the odd COM segment); in a WebP they're the "EXIF" and "XMP " chunks.
+//
+// Writing always goes to XMP for JPEG and WebP, replacing the property if it's already there. Everything else in the file
+// is copied over unchanged. A JPEG segment only holds 64KB, so in a JPEG, properties that don't fit move to "extended XMP":
+// a second packet split over as many segments as it needs, tied to the main one by the MD5 of its contents.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+// The keywords looked for in XMP and untagged EXIF values
+const KEYWORDS: [&str; 2] = ["prompt", "workflow"];
+
+const JPEG_SIGNATURE: [u8; 3] = [0xFF, 0xD8, 0xFF];
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+// Followed by the MD5 of the whole extended packet (as 32 hex digits), its length and where in it this segment's part goes
+const XMP_EXTENSION_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+const XMP_EXTENSION_PART: usize = MAX_JPEG_SEGMENT - XMP_EXTENSION_HEADER.len() - 32 - 8;
+// A JPEG segment holds at most 65535 bytes, including its own length
+const MAX_JPEG_SEGMENT: usize = 65535 - 2;
+
+// EXIF tags that can hold text
+const IMAGE_DESCRIPTION: u16 = 0x010E;
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const USER_COMMENT: u16 = 0x9286;
+
+const XMP_NAMESPACE: &str = "https://github.com/comfyanonymous/ComfyUI";
+const XMP_NOTE_NAMESPACE: &str = "http://ns.adobe.com/xmp/note/";
+const EMPTY_XMP: &str = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\"></rdf:RDF></x:xmpmeta>";
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "webp" => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+    // Going by the contents rather than the name, as some tools save e.g. JPEG data in a .png file
+    pub fn from_bytes(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(&png::PNG_SIGNATURE) {
+            Some(ImageFormat::Png)
+        } else if bytes.starts_with(&JPEG_SIGNATURE) {
+            Some(ImageFormat::Jpeg)
+        } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(ImageFormat::Webp)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_image_file(path: &Path) -> bool {
+    !path.is_dir() && ImageFormat::from_path(path).is_some()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub fn read_texts_from_file(path: &Path) -> io::Result<Vec<TextChunk>> {
+    let mut signature = [0u8; 12];
+    let length = io::Read::read(&mut fs::File::open(path)?, &mut signature)?;
+    match ImageFormat::from_bytes(&signature[..length]) {
+        // PNGs can be read without loading the image data
+        Some(ImageFormat::Png) => png::read_text_chunks_from_file(path),
+        _ => read_texts(&fs::read(path)?),
+    }
+}
+
+pub fn read_texts(bytes: &[u8]) -> io::Result<Vec<TextChunk>> {
+    match ImageFormat::from_bytes(bytes) {
+        Some(ImageFormat::Png) => png::read_text_chunks(&mut io::Cursor::new(bytes)),
+        Some(ImageFormat::Jpeg) => read_jpeg_texts(bytes),
+        Some(ImageFormat::Webp) => read_webp_texts(bytes),
+        None => Err(invalid_data("not a PNG, JPEG or WebP image")),
+    }
+}
+
+// Rewrite an image on disk with the given text replaced/added
+pub fn write_text_to_file(path: &Path, keyword: &str, text: &str) -> io::Result<()> {
+    let bytes = fs::read(path)?;
+    let new_bytes = match ImageFormat::from_bytes(&bytes) {
+        Some(ImageFormat::Png) => return png::write_text_to_file(path, keyword, text),
+        Some(ImageFormat::Jpeg) => write_jpeg_text(&bytes, keyword, text)?,
+        Some(ImageFormat::Webp) => write_webp_text(&bytes, keyword, text)?,
+        None => return Err(invalid_data("not a PNG, JPEG or WebP image")),
+    };
+    replace_file(path, &new_bytes)
+}
+
+// Write the new contents next to the file and move them over it, so a failed write (or a crash) never leaves half an image
+pub fn replace_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary = path.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+    let result = fs::write(&temporary, bytes)
+        .and_then(|()| fs::set_permissions(&temporary, fs::metadata(path)?.permissions()))
+        .and_then(|()| fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+// JPEG: [FF D8] then segments of [FF marker][length: u16, including itself][data], up to the start of scan (FF DA),
+// after which comes the image data.
+struct Segment {
+    marker: u8,
+    data: Range<usize>,
+}
+
+// The segments before the image data, and where the image data starts
+fn read_jpeg_segments(bytes: &[u8]) -> io::Result<(Vec<Segment>, usize)> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut pos = 2;
+    loop {
+        if pos + 2 > bytes.len() || bytes[pos] != 0xFF {
+            return Err(invalid_data("truncated or corrupt JPEG file"));
+        }
+        let marker = bytes[pos + 1];
+        match marker {
+            0xFF => { pos += 1; continue; } // fill byte
+            0xDA | 0xD9 => return Ok((segments, pos)), // start of scan, end of image
+            0x01 | 0xD0..=0xD7 => { pos += 2; continue; } // no length
+            _ => (),
+        }
+        if pos + 4 > bytes.len() {
+            return Err(invalid_data("truncated JPEG segment"));
+        }
+        let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        if length < 2 || pos + 2 + length > bytes.len() {
+            return Err(invalid_data("truncated JPEG segment"));
+        }
+        segments.push(Segment { marker, data: pos + 4..pos + 2 + length });
+        pos += 2 + length;
+    }
+}
+
+fn read_jpeg_texts(bytes: &[u8]) -> io::Result<Vec<TextChunk>> {
+    let (segments, _) = read_jpeg_segments(bytes)?;
+    let (standard, extended) = read_jpeg_xmp(bytes, &segments);
+    let mut xmp_texts = read_xmp(&extended);
+    xmp_texts.extend(read_xmp(&standard));
+    let mut other_texts = Vec::new();
+    for segment in segments {
+        let data = &bytes[segment.data];
+        match segment.marker {
+            0xE1 if data.starts_with(EXIF_HEADER) => other_texts.extend(read_exif(&data[EXIF_HEADER.len()..])),
+            0xFE => other_texts.extend(texts_from_value(&String::from_utf8_lossy(data))),
+            _ => (),
+        }
+    }
+    // XMP first, as that's where yara writes to
+    xmp_texts.extend(other_texts);
+    Ok(xmp_texts)
+}
+
+// The main XMP packet and the extended one (empty if there's none, or its segments don't add up)
+fn read_jpeg_xmp(bytes: &[u8], segments: &[Segment]) -> (String, String) {
+    let mut standard = String::new();
+    // Every extended part, by the MD5 it belongs to: (where it goes, its data)
+    let mut parts: HashMap<&[u8], Vec<(usize, &[u8])>> = HashMap::new();
+    for segment in segments.iter().filter(|x| x.marker == 0xE1) {
+        let data = &bytes[segment.data.clone()];
+        if let Some(xmp) = data.strip_prefix(XMP_HEADER) {
+            standard = String::from_utf8_lossy(xmp).to_string();
+        } else if let Some(part) = data.strip_prefix(XMP_EXTENSION_HEADER).filter(|x| x.len() >= 40) {
+            let offset = u32::from_be_bytes(part[36..40].try_into().unwrap()) as usize;
+            parts.entry(&part[..32]).or_default().push((offset, &part[40..]));
+        }
+    }
+    // Only the extended packet the main one names counts
+    let guid = find_xmp_property(&standard, "HasExtendedXMP").map(|(span, _)| standard[span].trim().to_string());
+    let Some(mut parts) = guid.and_then(|x| parts.remove(x.as_bytes())) else { return (standard, String::new()); };
+    parts.sort_by_key(|x| x.0);
+    let mut extended: Vec<u8> = Vec::new();
+    for (offset, data) in parts {
+        if offset != extended.len() { return (standard, String::new()); }
+        extended.extend(data);
+    }
+    (standard, String::from_utf8_lossy(&extended).to_string())
+}
+
+fn write_jpeg_text(bytes: &[u8], keyword: &str, text: &str) -> io::Result<Vec<u8>> {
+    let (segments, image_data) = read_jpeg_segments(bytes)?;
+    let (mut standard, mut extended) = read_jpeg_xmp(bytes, &segments);
+    if find_xmp_property(&extended, keyword).is_some() {
+        extended = set_xmp_property(&extended, keyword, text);
+    } else {
+        standard = set_xmp_property(&standard, keyword, text);
+    }
+    // Whatever doesn't fit in the main packet's segment goes into the extended packet, largest first
+    let mut moved: Vec<(&str, usize)> = KEYWORDS.iter()
+        .filter_map(|x| find_xmp_property(&standard, x).map(|(span, _)| (*x, span.len())))
+        .collect();
+    moved.sort_by_key(|x| std::cmp::Reverse(x.1));
+    for (keyword, _) in moved {
+        if XMP_HEADER.len() + standard.len() <= MAX_JPEG_SEGMENT { break; }
+        let Some((value, whole)) = find_xmp_property(&standard, keyword) else { continue; };
+        let text = xml_unescape(&strip_tags(&standard[value]));
+        standard.replace_range(whole, "");
+        extended = set_xmp_property(if extended.is_empty() { EMPTY_XMP } else { &extended }, keyword, &text);
+    }
+    let guid: String = md5(extended.as_bytes()).iter().map(|x| format!("{x:02X}")).collect();
+    if !extended.is_empty() {
+        standard = set_xmp_property_in(&standard, ("xmpNote", XMP_NOTE_NAMESPACE), "HasExtendedXMP", &guid);
+    }
+    let mut data = Vec::from(XMP_HEADER);
+    data.extend(standard.as_bytes());
+    if data.len() > MAX_JPEG_SEGMENT {
+        return Err(invalid_data(&format!("the XMP is too large to fit in a JPEG, even with extended XMP ({} bytes, at most {MAX_JPEG_SEGMENT})", data.len())));
+    }
+    let length: u32 = extended.len().try_into().map_err(|_| invalid_data("extended XMP too large"))?;
+    let mut new_segments: Vec<Vec<u8>> = vec![data];
+    for (i, part) in extended.as_bytes().chunks(XMP_EXTENSION_PART).enumerate() {
+        let mut data = Vec::from(XMP_EXTENSION_HEADER);
+        data.extend(guid.as_bytes());
+        data.extend(length.to_be_bytes());
+        data.extend(((i * XMP_EXTENSION_PART) as u32).to_be_bytes());
+        data.extend(part);
+        new_segments.push(data);
+    }
+
+    // The XMP goes where it was, or else after the other application segments (JFIF, EXIF) at the start
+    let is_xmp = |x: &Segment| x.marker == 0xE1 && (bytes[x.data.clone()].starts_with(XMP_HEADER) || bytes[x.data.clone()].starts_with(XMP_EXTENSION_HEADER));
+    let position = segments.iter().position(is_xmp)
+        .unwrap_or_else(|| segments.iter().take_while(|x| (0xE0..=0xEF).contains(&x.marker)).count());
+    let mut out: Vec<u8> = vec![0xFF, 0xD8];
+    for (i, segment) in segments.iter().enumerate() {
+        if i == position {
+            new_segments.iter().for_each(|x| push_jpeg_segment(&mut out, 0xE1, x));
+        }
+        if !is_xmp(segment) {
+            push_jpeg_segment(&mut out, segment.marker, &bytes[segment.data.clone()]);
+        }
+    }
+    if position == segments.len() {
+        new_segments.iter().for_each(|x| push_jpeg_segment(&mut out, 0xE1, x));
+    }
+    out.extend(&bytes[image_data..]);
+    Ok(out)
+}
+
+fn push_jpeg_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    out.extend([0xFF, marker]);
+    out.extend((data.len() as u16 + 2).to_be_bytes());
+    out.extend(data);
+}
+
+// WebP: "RIFF" [size: u32 LE] "WEBP", then chunks of [fourcc][size: u32 LE][data][a padding byte if size is odd].
+// Files with metadata start with a VP8X chunk, whose flags say which of the optional chunks are there.
+struct WebpChunk {
+    fourcc: [u8; 4],
+    data: Range<usize>,
+}
+
+const VP8X_XMP_FLAG: u8 = 0x04;
+const VP8X_ALPHA_FLAG: u8 = 0x10;
+
+fn read_webp_chunks(bytes: &[u8]) -> io::Result<Vec<WebpChunk>> {
+    let mut chunks: Vec<WebpChunk> = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let fourcc: [u8; 4] = bytes[pos..pos + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        if pos + 8 + size > bytes.len() {
+            return Err(invalid_data("truncated WebP chunk"));
+        }
+        chunks.push(WebpChunk { fourcc, data: pos + 8..pos + 8 + size });
+        pos += 8 + size + size % 2;
+    }
+    Ok(chunks)
+}
+
+fn read_webp_texts(bytes: &[u8]) -> io::Result<Vec<TextChunk>> {
+    let mut xmp_texts = Vec::new();
+    let mut exif_texts = Vec::new();
+    for chunk in read_webp_chunks(bytes)? {
+        let data = &bytes[chunk.data];
+        match &chunk.fourcc {
+            b"XMP " => xmp_texts.extend(read_xmp(&String::from_utf8_lossy(data))),
+            // Some tools keep the JPEG-style header in front of the TIFF data
+            b"EXIF" => exif_texts.extend(read_exif(data.strip_prefix(EXIF_HEADER).unwrap_or(data))),
+            _ => (),
+        }
+    }
+    xmp_texts.extend(exif_texts);
+    Ok(xmp_texts)
+}
+
+fn write_webp_text(bytes: &[u8], keyword: &str, text: &str) -> io::Result<Vec<u8>> {
+    let chunks = read_webp_chunks(bytes)?;
+    let xmp = chunks.iter().find(|x| &x.fourcc == b"XMP ").map(|x| String::from_utf8_lossy(&bytes[x.data.clone()]).to_string()).unwrap_or_default();
+    let xmp = set_xmp_property(&xmp, keyword, text);
+
+    // Simple (lossy/lossless only) files need a VP8X chunk to say there's metadata, with the canvas size copied from the image
+    let mut vp8x: Vec<u8> = match chunks.first() {
+        Some(x) if &x.fourcc == b"VP8X" => bytes[x.data.clone()].to_vec(),
+        Some(x) => new_vp8x(&x.fourcc, &bytes[x.data.clone()])?,
+        None => return Err(invalid_data("WebP file has no image data")),
+    };
+    if vp8x.is_empty() {
+        return Err(invalid_data("corrupt WebP VP8X chunk"));
+    }
+    vp8x[0] |= VP8X_XMP_FLAG;
+
+    let mut body: Vec<u8> = Vec::from(*b"WEBP");
+    push_webp_chunk(&mut body, b"VP8X", &vp8x);
+    for chunk in chunks.iter().filter(|x| &x.fourcc != b"VP8X") {
+        if &chunk.fourcc == b"XMP " { continue; }
+        push_webp_chunk(&mut body, &chunk.fourcc, &bytes[chunk.data.clone()]);
+    }
+    // XMP comes last
+    push_webp_chunk(&mut body, b"XMP ", xmp.as_bytes());
+
+    let size: u32 = body.len().try_into().map_err(|_| invalid_data("WebP file too large"))?;
+    let mut out: Vec<u8> = Vec::from(*b"RIFF");
+    out.extend(size.to_le_bytes());
+    out.extend(body);
+    Ok(out)
+}
+
+fn push_webp_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend(fourcc);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+// [flags][reserved: 3 bytes][canvas width - 1: 24 bits LE][canvas height - 1: 24 bits LE]
+fn new_vp8x(fourcc: &[u8; 4], data: &[u8]) -> io::Result<Vec<u8>> {
+    let (width, height, alpha) = match fourcc {
+        // Lossy: a frame tag, the start code 9D 01 2A, then 14 bits each of width and height
+        b"VP8 " if data.len() >= 10 && data[3..6] == [0x9D, 0x01, 0x2A] => {
+            let width = u16::from_le_bytes([data[6], data[7]]) & 0x3FFF;
+            let height = u16::from_le_bytes([data[8], data[9]]) & 0x3FFF;
+            (width as u32, height as u32, false)
+        }
+        // Lossless: the signature 2F, then 14 bits each of width - 1 and height - 1, and an alpha bit
+        b"VP8L" if data.len() >= 5 && data[0] == 0x2F => {
+            let bits = u32::from_le_bytes(data[1..5].try_into().unwrap());
+            ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, (bits >> 28) & 1 == 1)
+        }
+        _ => return Err(invalid_data("WebP file has no image data that yara understands")),
+    };
+    let mut vp8x = vec![if alpha { VP8X_ALPHA_FLAG } else { 0 }, 0, 0, 0];
+    vp8x.extend(&(width.saturating_sub(1)).to_le_bytes()[..3]);
+    vp8x.extend(&(height.saturating_sub(1)).to_le_bytes()[..3]);
+    Ok(vp8x)
+}
+
+// EXIF is TIFF: a byte order mark ("II" little endian, "MM" big endian), 42, then the offset of the first directory (IFD).
+// Each IFD is [count: u16] then count entries of [tag: u16][type: u16][count: u32][value, or its offset if over 4 bytes].
+// Anything malformed is skipped, rather than failing the whole image.
+fn read_exif(tiff: &[u8]) -> Vec<TextChunk> {
+    let mut texts = Vec::new();
+    let Some(exif) = Tiff::new(tiff) else { return texts; };
+    let Some(ifd0) = exif.u32(4) else { return texts; };
+    let mut values: Vec<Vec<u8>> = Vec::new();
+    let mut exif_ifd = None;
+    for (tag, kind, data) in exif.entries(ifd0 as usize) {
+        match tag {
+            IMAGE_DESCRIPTION | MAKE | MODEL => values.push(data.to_vec()),
+            EXIF_IFD_POINTER => exif_ifd = match kind { 4 => exif.u32_from(data), _ => None },
+            _ => (),
+        }
+    }
+    if let Some(offset) = exif_ifd {
+        for (tag, _, data) in exif.entries(offset as usize) {
+            if tag == USER_COMMENT {
+                values.push(exif.decode_user_comment(data).into_bytes());
+            }
+        }
+    }
+    for value in values {
+        let value = String::from_utf8_lossy(&value);
+        texts.extend(texts_from_value(value.trim_end_matches('\0')));
+    }
+    texts
+}
+
+struct Tiff<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(bytes: &'a [u8]) -> Option<Tiff<'a>> {
+        let little_endian = match bytes.get(0..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        Some(Tiff { bytes, little_endian })
+    }
+    fn u16(&self, pos: usize) -> Option<u16> {
+        let x: [u8; 2] = self.bytes.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.little_endian { u16::from_le_bytes(x) } else { u16::from_be_bytes(x) })
+    }
+    fn u32(&self, pos: usize) -> Option<u32> {
+        self.u32_from(self.bytes.get(pos..pos + 4)?)
+    }
+    fn u32_from(&self, data: &[u8]) -> Option<u32> {
+        let x: [u8; 4] = data.get(0..4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(x) } else { u32::from_be_bytes(x) })
+    }
+    // Every entry of the IFD at `offset`, with its value's bytes
+    fn entries(&self, offset: usize) -> Vec<(u16, u16, &'a [u8])> {
+        let mut entries = Vec::new();
+        let Some(count) = self.u16(offset) else { return entries; };
+        for i in 0..count as usize {
+            let pos = offset + 2 + i * 12;
+            let (Some(tag), Some(kind), Some(count)) = (self.u16(pos), self.u16(pos + 2), self.u32(pos + 4)) else { break; };
+            let size = match kind {
+                1 | 2 | 6 | 7 => 1, // BYTE, ASCII, SBYTE, UNDEFINED
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => continue,
+            } * count as usize;
+            let start = if size <= 4 { pos + 8 } else {
+                let Some(x) = self.u32(pos + 8) else { continue; };
+                x as usize
+            };
+            if let Some(data) = self.bytes.get(start..start + size) {
+                entries.push((tag, kind, data));
+            }
+        }
+        entries
+    }
+    // UserComment starts with 8 bytes naming its character set
+    fn decode_user_comment(&self, data: &[u8]) -> String {
+        if data.len() < 8 {
+            return String::new();
+        }
+        let (charset, text) = data.split_at(8);
+        if charset == b"UNICODE\0" {
+            // UTF-16, usually in the file's byte order. Trust a byte order mark over that.
+            let (little_endian, text) = match text {
+                [0xFF, 0xFE, rest @ ..] => (true, rest),
+                [0xFE, 0xFF, rest @ ..] => (false, rest),
+                _ => (self.little_endian, text),
+            };
+            let units: Vec<u16> = text.chunks_exact(2)
+                .map(|x| if little_endian { u16::from_le_bytes([x[0], x[1]]) } else { u16::from_be_bytes([x[0], x[1]]) })
+                .collect();
+            String::from_utf16_lossy(&units)
+        } else {
+            String::from_utf8_lossy(text).to_string()
+        }
+    }
+}
+
+// A text value from EXIF (or a JPEG comment) can be "keyword:text", like ComfyUI writes, or the JSON on its own.
+// On its own, a prompt is told apart from a workflow by its shape, and an object with "prompt"/"workflow" keys is both.
+fn texts_from_value(value: &str) -> Vec<TextChunk> {
+    let value = value.trim();
+    if let Some((keyword, text)) = value.split_once(':') {
+        if !keyword.is_empty() && keyword.len() <= 79 && keyword.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') {
+            return vec![TextChunk { keyword: keyword.to_string(), text: text.to_string() }];
+        }
+    }
+    let Ok(serde_json::Value::Object(json)) = serde_json::from_str(value) else { return Vec::new(); };
+    let named: Vec<TextChunk> = KEYWORDS.iter().filter_map(|keyword| {
+        let text = match json.get(*keyword)? {
+            serde_json::Value::String(x) => x.clone(),
+            x => x.to_string(),
+        };
+        Some(TextChunk { keyword: keyword.to_string(), text })
+    }).collect();
+    if !named.is_empty() {
+        named
+    } else if json.contains_key("nodes") && json.contains_key("links") {
+        vec![TextChunk { keyword: "workflow".to_string(), text: value.to_string() }]
+    } else if !json.is_empty() && json.values().all(|x| x.get("class_type").is_some()) {
+        vec![TextChunk { keyword: "prompt".to_string(), text: value.to_string() }]
+    } else {
+        Vec::new()
+    }
+}
+
+// XMP is RDF/XML. Rather than parse all of it, look for the properties we want by their local name, in either form:
+//     <rdf:Description comfyui:prompt="{...}"/>
+//     <comfyui:prompt>{...}</comfyui:prompt>     (possibly wrapped in <rdf:Alt><rdf:li>)
+fn read_xmp(xmp: &str) -> Vec<TextChunk> {
+    KEYWORDS.iter().filter_map(|keyword| {
+        let (span, _) = find_xmp_property(xmp, keyword)?;
+        Some(TextChunk { keyword: keyword.to_string(), text: xml_unescape(&strip_tags(&xmp[span])).trim().to_string() })
+    }).collect()
+}
+
+// Where the value of a property is (an attribute's value, or an element's content), and where the whole property is
+fn find_xmp_property(xmp: &str, keyword: &str) -> Option<(Range<usize>, Range<usize>)> {
+    let name = format!(":{keyword}");
+    let mut from = 0;
+    while let Some(found) = xmp[from..].find(&name) {
+        let start = from + found;
+        let end = start + name.len();
+        from = end;
+        // The prefix, and what comes before it
+        let prefix_start = xmp[..start].rfind(|x: char| !(x.is_alphanumeric() || x == '_' || x == '-' || x == '.')).map_or(0, |x| x + 1);
+        let before = xmp[..prefix_start].chars().last();
+        if &xmp[prefix_start..start] == "xmlns" { continue; }
+        let rest = &xmp[end..];
+        if before == Some('<') && rest.starts_with('>') {
+            let closing = format!("</{}>", &xmp[prefix_start..end]);
+            let content_end = end + rest.find(&closing)?;
+            return Some((end + 1..content_end, prefix_start - 1..content_end + closing.len()));
+        }
+        if let Some(space) = before.filter(|x| x.is_whitespace()) {
+            let rest_trimmed = rest.trim_start();
+            let Some(after_equals) = rest_trimmed.strip_prefix('=') else { continue; };
+            let after_equals = after_equals.trim_start();
+            let Some(quote) = after_equals.chars().next().filter(|x| *x == '"' || *x == '\'') else { continue; };
+            let value_start = xmp.len() - after_equals.len() + 1;
+            let value_end = value_start + xmp[value_start..].find(quote)?;
+            return Some((value_start..value_end, prefix_start - space.len_utf8()..value_end + 1));
+        }
+    }
+    None
+}
+
+// Set a property in an XMP packet, replacing it if it's there. A new one gets its own rdf:Description.
+fn set_xmp_property(xmp: &str, keyword: &str, text: &str) -> String {
+    set_xmp_property_in(xmp, ("comfyui", XMP_NAMESPACE), keyword, text)
+}
+fn set_xmp_property_in(xmp: &str, (prefix, namespace): (&str, &str), name: &str, text: &str) -> String {
+    let escaped = xml_escape(text);
+    if let Some((span, _)) = find_xmp_property(xmp, name) {
+        return format!("{}{escaped}{}", &xmp[..span.start], &xmp[span.end..]);
+    }
+    let description = format!("<rdf:Description rdf:about=\"\" xmlns:{prefix}=\"{namespace}\"><{prefix}:{name}>{escaped}</{prefix}:{name}></rdf:Description>");
+    match xmp.find("</rdf:RDF>") {
+        Some(i) => format!("{}{description}{}", &xmp[..i], &xmp[i..]),
+        None => format!("<?xpacket begin=\"\u{FEFF}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>{}<?xpacket end=\"w\"?>", set_xmp_property_in(EMPTY_XMP, (prefix, namespace), name, text)),
+    }
+}
+
+// Extended XMP names its packet by MD5, which nothing else here needs, so it's done by hand (RFC 1321)
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [[u32; 4]; 4] = [[7, 12, 17, 22], [5, 9, 14, 20], [4, 11, 16, 23], [6, 10, 15, 21]];
+    let constants: Vec<u32> = (1..=64).map(|i| ((i as f64).sin().abs() * 4294967296.0) as u32).collect();
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64).wrapping_mul(8).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block.chunks(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(constants[i]).wrapping_add(words[g]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16][i % 4]));
+        }
+        for (x, y) in state.iter_mut().zip([a, b, c, d]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+    let mut out = [0u8; 16];
+    for (i, x) in state.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&x.to_le_bytes());
+    }
+    out
+}
+
+fn strip_tags(text: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for x in text.chars() {
+        match x {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            x if !in_tag => out.push(x),
+            _ => (),
+        }
+    }
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for x in text.chars() {
+        match x {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            x => out.push(x),
+        }
+    }
+    out
+}
+
+fn xml_unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let Some(end) = rest.find(';') else { break; };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            x if x.starts_with("#x") => u32::from_str_radix(&x[2..], 16).ok().and_then(char::from_u32),
+            x if x.starts_with('#') => x[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(x) => { out.push(x); rest = &rest[end + 1..]; }
+            None => { out.push('&'); rest = &rest[1..]; }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    // A little-endian TIFF with the given tags in IFD0 and, if any, the EXIF IFD. All values are long enough to be at an offset.
+    fn tiff(ifd0: &[(u16, u16, Vec<u8>)], exif_ifd: &[(u16, u16, Vec<u8>)]) -> Vec<u8> {
+        fn ifd(entries: &[(u16, u16, Vec<u8>)], offset: usize, next: Option<(u16, usize)>) -> Vec<u8> {
+            let count = entries.len() + next.is_some() as usize;
+            let mut data_pos = offset + 2 + count * 12 + 4;
+            let mut table: Vec<u8> = (count as u16).to_le_bytes().to_vec();
+            let mut data: Vec<u8> = Vec::new();
+            for (tag, kind, value) in entries {
+                table.extend(tag.to_le_bytes());
+                table.extend(kind.to_le_bytes());
+                table.extend((value.len() as u32).to_le_bytes());
+                table.extend((data_pos as u32).to_le_bytes());
+                data.extend(value);
+                data_pos += value.len();
+            }
+            if let Some((tag, _)) = next {
+                table.extend(tag.to_le_bytes());
+                table.extend(4u16.to_le_bytes());
+                table.extend(1u32.to_le_bytes());
+                table.extend((data_pos as u32).to_le_bytes());
+            }
+            table.extend(0u32.to_le_bytes());
+            table.extend(data);
+            table
+        }
+        let mut bytes: Vec<u8> = b"II".to_vec();
+        bytes.extend(42u16.to_le_bytes());
+        bytes.extend(8u32.to_le_bytes());
+        let next = if exif_ifd.is_empty() { None } else { Some((EXIF_IFD_POINTER, 0)) };
+        let first = ifd(ifd0, 8, next);
+        let exif_offset = 8 + first.len();
+        bytes.extend(first);
+        if next.is_some() {
+            bytes.extend(ifd(exif_ifd, exif_offset, None));
+        }
+        bytes
+    }
+
+    fn ascii(text: &str) -> Vec<u8> {
+        let mut x = text.as_bytes().to_vec();
+        x.push(0);
+        x
+    }
+
+    // Just enough of a JPEG for the metadata: SOI, JFIF, the given segments, and a start of scan with a few bytes of "image"
+    fn jpeg(segments: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8];
+        push_jpeg_segment(&mut bytes, 0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        for (marker, data) in segments {
+            push_jpeg_segment(&mut bytes, *marker, data);
+        }
+        bytes.extend([0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        bytes
+    }
+
+    // A lossless WebP header for a 300x200 image, with alpha
+    fn webp() -> Vec<u8> {
+        let bits: u32 = 299 | (199 << 14) | (1 << 28);
+        let mut vp8l = vec![0x2F];
+        vp8l.extend(bits.to_le_bytes());
+        vp8l.extend([0xAA, 0xBB]);
+        let mut body = b"WEBP".to_vec();
+        push_webp_chunk(&mut body, b"VP8L", &vp8l);
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend((body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    const PROMPT: &str = r#"{"3": {"class_type": "KSampler", "inputs": {"seed": 5}}}"#;
+    const WORKFLOW: &str = r#"{"last_node_id": 3, "nodes": [], "links": []}"#;
+
+    #[test]
+    fn reads_exif_from_jpeg() {
+        // Bare JSON, as a custom save node writes it: the prompt in ImageDescription, the workflow in UserComment (UTF-16)
+        let mut user_comment = b"UNICODE\0".to_vec();
+        user_comment.extend(WORKFLOW.encode_utf16().flat_map(|x| x.to_le_bytes()));
+        let exif = tiff(&[(IMAGE_DESCRIPTION, 2, ascii(PROMPT)), (MAKE, 2, ascii("Some Camera"))], &[(USER_COMMENT, 7, user_comment)]);
+        let bytes = jpeg(&[(0xE1, [EXIF_HEADER, &exif].concat())]);
+
+        assert_eq!(ImageFormat::from_bytes(&bytes), Some(ImageFormat::Jpeg));
+        let texts = read_texts(&bytes).unwrap();
+        assert_eq!(find_text(&texts, "prompt"), Some(PROMPT));
+        assert_eq!(find_text(&texts, "workflow"), Some(WORKFLOW));
+        assert_eq!(texts.len(), 2);
+    }
+
+    #[test]
+    fn reads_comfyui_webp() {
+        // ComfyUI's own WebP saving: "prompt:" in Model, "workflow:" in Make
+        let exif = tiff(&[(MAKE, 2, ascii(&format!("workflow:{WORKFLOW}"))), (MODEL, 2, ascii(&format!("prompt:{PROMPT}")))], &[]);
+        let mut bytes = webp();
+        let mut chunk = Vec::new();
+        push_webp_chunk(&mut chunk, b"EXIF", &exif);
+        bytes.extend(chunk);
+        let size = bytes.len() as u32 - 8;
+        bytes[4..8].copy_from_slice(&size.to_le_bytes());
+
+        let texts = read_texts(&bytes).unwrap();
+        assert_eq!(find_text(&texts, "prompt"), Some(PROMPT));
+        assert_eq!(find_text(&texts, "workflow"), Some(WORKFLOW));
+    }
+
+    #[test]
+    fn writes_xmp_into_jpeg() {
+        let prompt_xmp = format!("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+            <rdf:Description rdf:about=\"\" xmlns:sd=\"https://example.com/sd/\" sd:prompt=\"{}\"/></rdf:RDF></x:xmpmeta>", xml_escape(PROMPT));
+        let bytes = jpeg(&[(0xE1, [XMP_HEADER, prompt_xmp.as_bytes()].concat())]);
+        assert_eq!(find_text(&read_texts(&bytes).unwrap(), "prompt"), Some(PROMPT));
+
+        // Added next to the prompt, then replaced, always in the one XMP segment
+        let bytes = write_jpeg_text(&bytes, "workflow", WORKFLOW).unwrap();
+        let bytes = write_jpeg_text(&bytes, "workflow", r#"{"new": "<&>"}"#).unwrap();
+        let texts = read_texts(&bytes).unwrap();
+        assert_eq!(find_text(&texts, "prompt"), Some(PROMPT));
+        assert_eq!(find_text(&texts, "workflow"), Some(r#"{"new": "<&>"}"#));
+        let (segments, image_data) = read_jpeg_segments(&bytes).unwrap();
+        assert_eq!(segments.iter().map(|x| x.marker).collect::<Vec<_>>(), [0xE0, 0xE1]);
+        assert_eq!(&bytes[image_data..], [0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+
+        // Without any XMP, a packet is made for it after the JFIF segment
+        let bytes = write_jpeg_text(&jpeg(&[(0xDB, vec![0; 4])]), "workflow", WORKFLOW).unwrap();
+        let (segments, _) = read_jpeg_segments(&bytes).unwrap();
+        assert_eq!(segments.iter().map(|x| x.marker).collect::<Vec<_>>(), [0xE0, 0xE1, 0xDB]);
+        assert_eq!(find_text(&read_texts(&bytes).unwrap(), "workflow"), Some(WORKFLOW));
+
+    }
+
+    #[test]
+    fn writes_large_workflows_into_extended_jpeg_xmp() {
+        let prompt = format!(r#"{{"text": "{}"}}"#, "a <cat> & ".repeat(8000));
+        let workflow = format!(r#"{{"nodes": "{}"}}"#, "ünïcode ".repeat(20000));
+        let bytes = write_jpeg_text(&jpeg(&[]), "prompt", &prompt).unwrap();
+        let bytes = write_jpeg_text(&bytes, "workflow", &workflow).unwrap();
+        let texts = read_texts(&bytes).unwrap();
+        assert_eq!(find_text(&texts, "prompt"), Some(prompt.as_str()));
+        assert_eq!(find_text(&texts, "workflow"), Some(workflow.as_str()));
+
+        // Both moved out of the main packet, which names the extended one by its MD5
+        let (segments, _) = read_jpeg_segments(&bytes).unwrap();
+        let (standard, extended) = read_jpeg_xmp(&bytes, &segments);
+        assert!(read_xmp(&standard).is_empty());
+        let (guid, _) = find_xmp_property(&standard, "HasExtendedXMP").unwrap();
+        let md5: String = md5(extended.as_bytes()).iter().map(|x| format!("{x:02X}")).collect();
+        assert_eq!(standard[guid], md5);
+        let parts = segments.iter().filter(|x| bytes[x.data.clone()].starts_with(XMP_EXTENSION_HEADER)).count();
+        assert_eq!(parts, extended.len().div_ceil(XMP_EXTENSION_PART));
+
+        // Replaced where they are, even once they're small again
+        let bytes = write_jpeg_text(&bytes, "workflow", WORKFLOW).unwrap();
+        let bytes = write_jpeg_text(&bytes, "prompt", PROMPT).unwrap();
+        let texts = read_texts(&bytes).unwrap();
+        assert_eq!(find_text(&texts, "prompt"), Some(PROMPT));
+        assert_eq!(find_text(&texts, "workflow"), Some(WORKFLOW));
+        let (segments, _) = read_jpeg_segments(&bytes).unwrap();
+        assert_eq!(segments.iter().map(|x| x.marker).collect::<Vec<_>>(), [0xE0, 0xE1, 0xE1]);
+
+        // Parts that don't add up to the packet are ignored
+        let mut broken = bytes.clone();
+        let last = segments[2].data.start + XMP_EXTENSION_HEADER.len() + 39;
+        broken[last] ^= 1;
+        assert_eq!(find_text(&read_texts(&broken).unwrap(), "workflow"), None);
+    }
+
+    #[test]
+    fn hashes_with_md5() {
+        let hex = |x: &[u8]| md5(x).iter().map(|x| format!("{x:02x}")).collect::<String>();
+        assert_eq!(hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(b"The quick brown fox jumps over the lazy dog"), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(hex(&[b'a'; 1000]), "cabe45dcc9ae5b66ba86600cca6b8ba8");
+    }
+
+    #[test]
+    fn replaces_files_whole() {
+        let dir = temp_dir("replace");
+        let path = dir.join("image.jpg");
+        fs::write(&path, jpeg(&[])).unwrap();
+        write_text_to_file(&path, "workflow", WORKFLOW).unwrap();
+        assert_eq!(find_text(&read_texts_from_file(&path).unwrap(), "workflow"), Some(WORKFLOW));
+        // Nothing is left behind next to it
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A write that fails leaves the file as it was
+        let truncated = &jpeg(&[])[..10];
+        fs::write(&path, truncated).unwrap();
+        assert!(write_text_to_file(&path, "workflow", WORKFLOW).is_err());
+        assert_eq!(fs::read(&path).unwrap(), truncated);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn writes_xmp_into_webp() {
+        let bytes = write_webp_text(&webp(), "workflow", WORKFLOW).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        let chunks = read_webp_chunks(&bytes).unwrap();
+        assert_eq!(chunks.iter().map(|x| &x.fourcc).collect::<Vec<_>>(), [b"VP8X", b"VP8L", b"XMP "]);
+        // Canvas size and alpha carried over from the image
+        assert_eq!(&bytes[chunks[0].data.clone()], [VP8X_XMP_FLAG | VP8X_ALPHA_FLAG, 0, 0, 0, 43, 1, 0, 199, 0, 0]);
+        assert_eq!(&bytes[chunks[1].data.clone()], &webp()[20..27]);
+
+        let bytes = write_webp_text(&bytes, "workflow", "ünïcode").unwrap();
+        assert_eq!(read_webp_chunks(&bytes).unwrap().len(), 3);
+        assert_eq!(find_text(&read_texts(&bytes).unwrap(), "workflow"), Some("ünïcode"));
+    }
+
+    #[test]
+    fn reads_xmp_elements() {
+        let xmp = "<rdf:RDF><rdf:Description><my-ns:workflow><rdf:Alt><rdf:li xml:lang=\"x-default\">{&quot;a&quot;: &#x31;}</rdf:li></rdf:Alt></my-ns:workflow>\
+            <xmp:CreatorTool>not a prompt</xmp:CreatorTool></rdf:Description></rdf:RDF>";
+        let texts = read_xmp(xmp);
+        assert_eq!(texts, [TextChunk { keyword: "workflow".to_string(), text: r#"{"a": 1}"#.to_string() }]);
+        assert!(read_texts(b"GIF89a").is_err());
+    }
+}
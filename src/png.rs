@@ -0,0 +1,364 @@
+use std::fs;
+use std::path::Path;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+use flate2::read::ZlibDecoder;
+
+use crate::metadata;
+
+// A PNG file is an 8 byte signature followed by a list of chunks.
+// Every chunk is laid out as: [length: u32][type: 4 bytes][data: length bytes][crc32 of type + data: u32]
+// ComfyUI stores the API prompt and the workflow as text chunks, keyworded "prompt" and "workflow".
+// Text chunks come in three flavours:
+//     tEXt - keyword\0text                                              (Latin-1, uncompressed)
+//     zTXt - keyword\0[compression method]zlib(text)                    (Latin-1, compressed)
+//     iTXt - keyword\0[compressed?][method]language\0translated\0text   (UTF-8, optionally compressed)
+
+pub const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+const TEXT_CHUNK_TYPES: [&[u8; 4]; 3] = [b"tEXt", b"zTXt", b"iTXt"];
+
+// The spec limits chunk lengths to 2^31-1, so anything longer means a corrupt file
+const MAX_CHUNK_LENGTH: u32 = 0x7FFF_FFFF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub kind: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextChunk {
+    pub keyword: String,
+    pub text: String,
+}
+
+impl Chunk {
+    pub fn new(kind: &[u8; 4], data: Vec<u8>) -> Chunk {
+        Chunk { kind: *kind, data }
+    }
+    pub fn is_text(&self) -> bool {
+        TEXT_CHUNK_TYPES.contains(&&self.kind)
+    }
+    fn crc(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.kind);
+        hasher.update(&self.data);
+        hasher.finalize()
+    }
+    // Decode a tEXt, zTXt or iTXt chunk. Returns None for any other chunk type.
+    pub fn text(&self) -> Option<io::Result<TextChunk>> {
+        match &self.kind {
+            b"tEXt" => Some(decode_text(&self.data)),
+            b"zTXt" => Some(decode_ztxt(&self.data)),
+            b"iTXt" => Some(decode_itxt(&self.data)),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Walk every chunk in a PNG file, checking the signature and every chunk's CRC.
+pub fn read_chunks(bytes: &[u8]) -> io::Result<Vec<Chunk>> {
+    if bytes.len() < 8 || bytes[0..8] != PNG_SIGNATURE {
+        return Err(invalid_data("not a PNG file (bad signature)"));
+    }
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut pos = 8;
+    while pos < bytes.len() {
+        if bytes.len() - pos < 12 {
+            return Err(invalid_data("truncated chunk header"));
+        }
+        let length = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        if length > MAX_CHUNK_LENGTH {
+            return Err(invalid_data("chunk length out of range"));
+        }
+        let kind: [u8; 4] = bytes[pos + 4..pos + 8].try_into().unwrap();
+        let data_end = pos + 8 + length as usize;
+        if data_end + 4 > bytes.len() {
+            return Err(invalid_data("truncated chunk data"));
+        }
+        let chunk = Chunk { kind, data: bytes[pos + 8..data_end].to_vec() };
+        let crc = u32::from_be_bytes(bytes[data_end..data_end + 4].try_into().unwrap());
+        if crc != chunk.crc() {
+            return Err(invalid_data(&format!("CRC mismatch in {} chunk", String::from_utf8_lossy(&kind))));
+        }
+        pos = data_end + 4;
+        let is_end = &chunk.kind == b"IEND";
+        chunks.push(chunk);
+        if is_end { break; }
+    }
+    Ok(chunks)
+}
+
+pub fn write_chunks(chunks: &[Chunk]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::from(PNG_SIGNATURE);
+    for chunk in chunks {
+        let length: u32 = chunk.data.len().try_into().expect("PNG chunk too large");
+        bytes.extend(length.to_be_bytes());
+        bytes.extend(chunk.kind);
+        bytes.extend(&chunk.data);
+        bytes.extend(chunk.crc().to_be_bytes());
+    }
+    bytes
+}
+
+// Read only the text chunks of a PNG file, seeking past image data rather than loading it.
+pub fn read_text_chunks<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<TextChunk>> {
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature)?;
+    if signature != PNG_SIGNATURE {
+        return Err(invalid_data("not a PNG file (bad signature)"));
+    }
+    let mut texts: Vec<TextChunk> = Vec::new();
+    let mut header = [0u8; 8];
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break, // no IEND, but we have what we need
+            Err(e) => return Err(e),
+        }
+        let length = u32::from_be_bytes(header[0..4].try_into().unwrap());
+        if length > MAX_CHUNK_LENGTH {
+            return Err(invalid_data("chunk length out of range"));
+        }
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        if &kind == b"IEND" { break; }
+
+        if TEXT_CHUNK_TYPES.contains(&&kind) {
+            // Only allocate as much as the file actually has, whatever the length says
+            let mut data = Vec::new();
+            reader.by_ref().take(length as u64).read_to_end(&mut data)?;
+            if data.len() != length as usize {
+                return Err(invalid_data("truncated chunk data"));
+            }
+            let mut crc = [0u8; 4];
+            reader.read_exact(&mut crc)?;
+            let chunk = Chunk { kind, data };
+            if u32::from_be_bytes(crc) != chunk.crc() {
+                return Err(invalid_data(&format!("CRC mismatch in {} chunk", String::from_utf8_lossy(&kind))));
+            }
+            if let Some(text) = chunk.text() {
+                texts.push(text?);
+            }
+        } else {
+            reader.seek(SeekFrom::Current(length as i64 + 4))?;
+        }
+    }
+    Ok(texts)
+}
+
+pub fn read_text_chunks_from_file(path: &Path) -> io::Result<Vec<TextChunk>> {
+    let file = fs::File::open(path)?;
+    read_text_chunks(&mut BufReader::new(file))
+}
+
+pub fn find_text<'a>(texts: &'a [TextChunk], keyword: &str) -> Option<&'a str> {
+    texts.iter().find(|x| x.keyword == keyword).map(|x| x.text.as_str())
+}
+
+// Replace any existing text chunk(s) with this keyword, or add a new one.
+// New chunks are placed after the last existing text chunk (so "workflow" sits next to "prompt", like ComfyUI does it),
+// or before the image data if the file has no text chunks.
+pub fn set_text(chunks: &mut Vec<Chunk>, keyword: &str, text: &str) -> io::Result<()> {
+    let new_chunk = encode_text(keyword, text)?;
+
+    let mut existing: Option<usize> = None;
+    let mut i = 0;
+    while i < chunks.len() {
+        if let Some(Ok(x)) = chunks[i].text() {
+            if x.keyword == keyword {
+                if existing.is_none() {
+                    existing = Some(i);
+                    i += 1;
+                } else {
+                    chunks.remove(i);
+                }
+                continue;
+            }
+        }
+        i += 1;
+    }
+    if let Some(i) = existing {
+        chunks[i] = new_chunk;
+        return Ok(());
+    }
+
+    let position = match chunks.iter().rposition(|x| x.is_text()) {
+        Some(i) => i + 1,
+        None => match chunks.iter().position(|x| &x.kind == b"IDAT") {
+            Some(i) => i,
+            None => return Err(invalid_data("PNG file has no image data")),
+        }
+    };
+    chunks.insert(position, new_chunk);
+    Ok(())
+}
+
+// Rewrite a PNG file on disk with the given text chunk replaced/added. Every other chunk is copied over unchanged.
+pub fn write_text_to_file(path: &Path, keyword: &str, text: &str) -> io::Result<()> {
+    let bytes = fs::read(path)?;
+    let mut chunks = read_chunks(&bytes)?;
+    set_text(&mut chunks, keyword, text)?;
+    metadata::replace_file(path, &write_chunks(&chunks))
+}
+
+// tEXt is specified as Latin-1, but older versions of yara (and some other tools) wrote UTF-8 into it. Accept both.
+fn decode_latin1_or_utf8(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(x) => x.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+fn split_keyword(data: &[u8]) -> io::Result<(String, &[u8])> {
+    let Some(null) = data.iter().position(|&b| b == 0)
+        else { return Err(invalid_data("text chunk has no keyword separator")); };
+    Ok((decode_latin1_or_utf8(&data[..null]), &data[null + 1..]))
+}
+
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+fn decode_text(data: &[u8]) -> io::Result<TextChunk> {
+    let (keyword, rest) = split_keyword(data)?;
+    Ok(TextChunk { keyword, text: decode_latin1_or_utf8(rest) })
+}
+
+fn decode_ztxt(data: &[u8]) -> io::Result<TextChunk> {
+    let (keyword, rest) = split_keyword(data)?;
+    match rest.first() {
+        Some(0) => (),
+        _ => return Err(invalid_data("zTXt chunk has unknown compression method")),
+    }
+    Ok(TextChunk { keyword, text: decode_latin1_or_utf8(&inflate(&rest[1..])?) })
+}
+
+fn decode_itxt(data: &[u8]) -> io::Result<TextChunk> {
+    let (keyword, rest) = split_keyword(data)?;
+    if rest.len() < 2 {
+        return Err(invalid_data("truncated iTXt chunk"));
+    }
+    let compressed = rest[0] == 1;
+    let (_language, rest) = split_keyword(&rest[2..])?;
+    let (_translated_keyword, text) = split_keyword(rest)?;
+    let text = if compressed { inflate(text)? } else { text.to_vec() };
+    match String::from_utf8(text) {
+        Ok(text) => Ok(TextChunk { keyword, text }),
+        Err(_) => Err(invalid_data("iTXt chunk is not valid UTF-8")),
+    }
+}
+
+// Plain ASCII goes in a tEXt chunk (what ComfyUI/PIL writes), anything else in an uncompressed iTXt chunk.
+fn encode_text(keyword: &str, text: &str) -> io::Result<Chunk> {
+    if keyword.is_empty() || keyword.len() > 79 || !keyword.is_ascii() || keyword.contains('\0') {
+        return Err(invalid_data("PNG text keywords must be 1-79 ASCII characters"));
+    }
+    let mut data: Vec<u8> = Vec::from(keyword.as_bytes());
+    data.push(0);
+    if text.is_ascii() {
+        data.extend(text.as_bytes());
+        Ok(Chunk::new(b"tEXt", data))
+    } else {
+        data.extend([0, 0, 0, 0]); // not compressed, compression method, empty language tag, empty translated keyword
+        data.extend(text.as_bytes());
+        Ok(Chunk::new(b"iTXt", data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::fixture;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_every_text_chunk_type() {
+        let texts = read_text_chunks(&mut Cursor::new(fixture("text_chunks.png"))).unwrap();
+        let keywords: Vec<&str> = texts.iter().map(|x| x.keyword.as_str()).collect();
+        assert_eq!(keywords, ["prompt", "workflow", "comment", "parameters"]);
+
+        // Unbalanced braces inside a JSON string must not confuse the reader
+        let prompt: serde_json::Value = serde_json::from_str(find_text(&texts, "prompt").unwrap()).unwrap();
+        assert_eq!(prompt["3"]["inputs"]["text"], "a {weird} prompt with an unbalanced { brace");
+
+        let workflow: serde_json::Value = serde_json::from_str(find_text(&texts, "workflow").unwrap()).unwrap();
+        assert_eq!(workflow["last_node_id"], 4);
+
+        assert_eq!(find_text(&texts, "comment"), Some("café ☕"));
+        assert_eq!(find_text(&texts, "parameters"), Some("steps: 20, négatif"));
+        assert_eq!(find_text(&texts, "missing"), None);
+    }
+
+    #[test]
+    fn chunk_walk_matches_streaming_reader() {
+        let bytes = fixture("text_chunks.png");
+        let chunks = read_chunks(&bytes).unwrap();
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|x| &x.kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"tEXt", b"zTXt", b"iTXt", b"iTXt", b"IDAT", b"IEND"]);
+        assert_eq!(write_chunks(&chunks), bytes);
+
+        let texts: Vec<TextChunk> = chunks.iter().filter_map(|x| x.text()).map(|x| x.unwrap()).collect();
+        assert_eq!(texts, read_text_chunks(&mut Cursor::new(bytes)).unwrap());
+    }
+
+    #[test]
+    fn replaces_existing_text_chunk() {
+        let mut chunks = read_chunks(&fixture("text_chunks.png")).unwrap();
+        set_text(&mut chunks, "workflow", r#"{"new": true}"#).unwrap();
+        assert_eq!(chunks.len(), 7);
+        assert_eq!(&chunks[2].kind, b"tEXt"); // zTXt replaced in place
+
+        let bytes = write_chunks(&chunks);
+        let texts = read_text_chunks(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(find_text(&texts, "workflow"), Some(r#"{"new": true}"#));
+        assert_eq!(texts.len(), 4);
+    }
+
+    #[test]
+    fn adds_text_chunk_before_image_data() {
+        let mut chunks = read_chunks(&fixture("no_text.png")).unwrap();
+        set_text(&mut chunks, "workflow", "ünïcode").unwrap();
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|x| &x.kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"iTXt", b"IDAT", b"IEND"]);
+
+        let bytes = write_chunks(&chunks);
+        assert!(read_chunks(&bytes).is_ok());
+        let texts = read_text_chunks(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(find_text(&texts, "workflow"), Some("ünïcode"));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bad_crc = fixture("bad_crc.png");
+        assert_eq!(read_chunks(&bad_crc).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_text_chunks(&mut Cursor::new(&bad_crc)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let not_png = b"GIF89a not a png".to_vec();
+        assert!(read_chunks(&not_png).is_err());
+        assert!(read_text_chunks(&mut Cursor::new(not_png)).is_err());
+
+        let mut truncated = fixture("text_chunks.png");
+        truncated.truncate(100);
+        assert!(read_chunks(&truncated).is_err());
+        assert!(read_text_chunks(&mut Cursor::new(&truncated)).is_err());
+    }
+
+    #[test]
+    fn rejects_impossible_chunk_lengths() {
+        // A text chunk claiming to be (nearly) 4 GiB, and one longer than the rest of the file
+        for length in [0xFFFF_FFF0u32, 1000] {
+            let mut bytes = fixture("no_text.png")[..33].to_vec(); // signature and IHDR
+            bytes.extend(length.to_be_bytes());
+            bytes.extend(b"tEXtprompt\0{}");
+            assert_eq!(read_chunks(&bytes).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(read_text_chunks(&mut Cursor::new(&bytes)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
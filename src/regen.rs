@@ -1,52 +1,11 @@
 use std::fs;
 use std::path::PathBuf;
-use std::io::{BufReader, Read};
+use std::io::Read;
 use serde_json::Value;
 
 use crate::data::YaraPrompt;
-
-#[derive(PartialEq)]
-enum WidgetField {
-    String,
-    U64,
-    F64,
-    Skip,
-}
-
-const EXPECTED_WIDGETS_KSAMPLER: [(&str, WidgetField); 7] = [
-        ("seed", WidgetField::U64),
-        ("control_after_generate", WidgetField::Skip), 
-        ("steps", WidgetField::U64),
-        ("cfg", WidgetField::F64),
-        ("sampler_name", WidgetField::String), 
-        ("scheduler", WidgetField::String),
-        ("denoise", WidgetField::F64),
-    ];
-
-const EXPECTED_WIDGETS_KSAMPLER_ADVANCED: [(&str, WidgetField); 10] = [
-        ("add_noise", WidgetField::String), 
-        ("noise_seed", WidgetField::U64),
-        ("control_after_generate", WidgetField::Skip), 
-        ("steps", WidgetField::U64),
-        ("cfg", WidgetField::F64),
-        ("sampler_name", WidgetField::String), 
-        ("scheduler", WidgetField::String),
-        ("start_at_step", WidgetField::U64),
-        ("end_at_step", WidgetField::U64),
-        ("return_with_leftover_noise", WidgetField::String), 
-    ];
-// Every node has a number of input fields. 
-    // Some are mandatory "inputs" (e.g. "MODEL" on a KSampler node - they must be sourced from another node)
-    // Some are widgets (e.g. "seed" on a KSampler node - you can change them in ComfyUI, right on the node)
-    // Some widgets may be converted to inputs.
-// Widget fields in the workflow data are not named. We cannot automatically tell which field the data in "widgets" belongs to.
-            // https://github.com/comfyanonymous/ComfyUI/issues/2275
-// Some fields (e.g. "control_after_generate" on a KSampler node) are only a part of ComfyUI's frontend, and aren't included in API prompts. 
-// 
-// To reconstruct a node for !yara_unmute, we need widget field data.
-// The structure of the data needs to be hard-coded.
-// I don't foresee !yum being useful on anything outside of KSampler nodes, so I'm only hardcoding those.
-// If ComfyUI issue #2275 is resolved, it should be easier to let any arbitrary node be unmuted with !yara_unmute.
+use crate::metadata;
+use crate::schema::{self, NodeSchema, ObjectInfo};
 
 #[derive(Debug)]
 struct FlowNodeData {
@@ -81,34 +40,33 @@ struct Node {
 
 
 
-pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory: PathBuf, ip_port: &str) -> Option<YaraPrompt> {
-    let filename = filepath.file_stem()?.to_string_lossy();
-    let fail_str = "\x1b[31mfailure\x1b[0m:// \x1b[31m".to_string() + &filename + &".png\x1b[0m // failed to";
+pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory: PathBuf, ip_port: &str) -> Option<Vec<YaraPrompt>> {
+    let filename = filepath.file_name()?.to_string_lossy();
+    let fail_str = format!("\x1b[31mfailure\x1b[0m:// \x1b[31m{filename}\x1b[0m // failed to");
     let mut yara_unmute_counter = 0;
     let mut yara_mute_counter = 0;
     let mut yara_load_here_counter = 0;
+    let mut yara_set_counter = 0;
 
-    // Read embedded data from .png file
-    let Ok(file) = fs::File::open(filepath.as_path())
-        else { println!("{fail_str} open file"); return None; };
-
-    let mut reader = BufReader::new(file);
+    // Read embedded data from the image (PNG, JPEG or WebP)
+    let texts = match metadata::read_texts_from_file(filepath.as_path()) {
+        Ok(x) => x,
+        Err(e) => { println!("{fail_str} read image metadata: {e}"); return None; }
+    };
 
-    let api_data_marker: [u8; 10] = [116, 69, 88, 116, 112, 114, 111, 109, 112, 116]; // "tEXtprompt"
-    let Ok(bytes) = match_header_string_and_read_data(&mut reader, api_data_marker)
+    let Some(text) = metadata::find_text(&texts, "prompt")
         else { println!("{fail_str} read embedded API JSON data"); return None; };
-    let api_data: serde_json::Map<String, Value> = match serde_json::from_slice(&bytes) {
+    let api_data: serde_json::Map<String, Value> = match serde_json::from_str(text) {
         Ok(x) => x,
-        Err(_) => match json5::from_str(&String::from_utf8(bytes).ok()?) {
+        Err(_) => match json5::from_str(text) {
             Ok(x) => x,
             Err(_) => { println!("{fail_str} deserialize embedded API JSON data"); return None; }
         }
     };
 
-    let flow_data_marker: [u8; 10] = [116, 69, 88, 116, 119, 111, 114, 107, 102, 108]; // "tEXtworkfl"
-    let Ok(bytes) = match_header_string_and_read_data(&mut reader, flow_data_marker)
+    let Some(text) = metadata::find_text(&texts, "workflow")
         else { println!("{fail_str} read embedded workflow data"); return None; };
-    let Ok(mut flow_data): Result<Value, serde_json::Error> = serde_json::from_slice(&bytes) 
+    let Ok(mut flow_data): Result<Value, serde_json::Error> = serde_json::from_str(text)
         else { println!("{fail_str} deserialize embedded workflow data"); return None; };
 
     // Get node info from workflow metadata
@@ -126,10 +84,8 @@ pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory:
                     Some(n) => Some(n.as_str()?.to_string()),
                     None => None,
                 },
-                widgets: match obj.get("widgets_values") {
-                    Some(n) => Some(n.as_array()?.to_vec()),
-                    None => None,
-                },
+                // Some custom nodes store widgets_values as an object instead
+                widgets: obj.get("widgets_values").and_then(|x| x.as_array()).map(|x| x.to_vec()),
                 inputs: match obj.get("inputs") {
                     Some(n) => {
                         let mut inputs: Vec<ApiInput> = Vec::new();
@@ -208,23 +164,15 @@ pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory:
 
 
     // Find the node(s) to unmute
-    let mut yara_unmute_nodes: Vec<FlowNodeData> = Vec::new();
+    let mut yara_unmute_nodes: Vec<&FlowNodeData> = Vec::new();
     for node in &flow_nodes {
         if let Some(ref title) = node.custom_title {
             let title = title.to_lowercase();
             if title.contains("!yara_unmute") | title.contains("!yum") {
                 if !node.muted {
-                    println!("\x1b[33mwarning\x1b[0m:// \x1b[33m{filename}.png\x1b[0m // detected !yara_unmute keyword, but node is not muted.");
+                    println!("\x1b[33mwarning\x1b[0m:// \x1b[33m{filename}\x1b[0m // detected !yara_unmute keyword, but node is not muted.");
                 } else {
-                    yara_unmute_nodes.push(FlowNodeData {
-                        id: node.id,
-                        muted: node.muted,
-                        custom_title: Some(node.custom_title.as_ref()?.clone()),
-                        widgets: Some(node.widgets.as_ref()?.clone()),
-                        inputs: Some(node.inputs.as_ref()?.clone()),
-                        output_types: node.output_types.clone(),
-                        kind: node.kind.clone(),
-                    });
+                    yara_unmute_nodes.push(node);
                 }
             }
         }
@@ -236,9 +184,10 @@ pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory:
         // Begin creating the muted node using the workflow metadata
         let mut inputs = serde_json::Map::new();
 
-        // Create input fields
-        for input_widget in new_node_flowdata.inputs? {
-            let Some((input_node_id, input_node_slot)) = get_input_source(&flow_links, &flow_nodes, input_widget.link_id?)
+        // Create input fields (including widgets that were converted to inputs)
+        for input_widget in new_node_flowdata.inputs.iter().flatten() {
+            let Some(link_id) = input_widget.link_id else { continue; }; // unconnected optional input
+            let Some((input_node_id, input_node_slot)) = get_input_source(&flow_links, &flow_nodes, link_id)
                 else { println!("{fail_str} get an input source for !yara_unmute node"); return None; };
             inputs.insert(
                 input_widget.name.to_string(), 
@@ -246,37 +195,22 @@ pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory:
             );
         }
 
-        // Create non-input fields
-        let expected_widgets = match new_node_flowdata.kind.as_str() {
-            "KSampler" => EXPECTED_WIDGETS_KSAMPLER.iter(),
-            "KSamplerAdvanced" => EXPECTED_WIDGETS_KSAMPLER_ADVANCED.iter(),
-            // "SamplerCustom" => ,
-            _ => { println!("{fail_str} process widgets (!ym can only be used on KSampler, KSamplerAdvanced, and SamplerCustom nodes.)"); return None; }
-        };
-
-        let mut widgets = new_node_flowdata.widgets.as_ref()?.iter();
-        for (name, need) in expected_widgets {
-            let name = name.to_string();
-            let w = widgets.next()?;
-            match need {
-                WidgetField::String => {
-                    let data = w.as_str()?;
-                    inputs.insert(name, Value::String(data.into()));
-                }
-                WidgetField::U64 => {
-                    let data = w.as_u64()?;
-                    inputs.insert(name, Value::Number(data.into()));
-                }
-                WidgetField::F64 => {
-                    let data = w.as_f64()?;
-                    inputs.insert(name, Value::Number(serde_json::Number::from_f64(data)?));
-                }
-                WidgetField::Skip => (),
+        // Create non-input fields, using ComfyUI's node definitions to name the widget values
+        let Some(object_info) = schema::object_info(ip_port)
+            else { println!("{fail_str} get node definitions from ComfyUI (/object_info) for !yara_unmute, and there's no cached copy"); return None; };
+        let Some(node_schema) = object_info.get(&new_node_flowdata.kind)
+            else { println!("{fail_str} find '{}' in ComfyUI's node definitions for !yara_unmute (is the custom node installed?)", new_node_flowdata.kind); return None; };
+        let Some(widgets) = node_schema.name_widgets(new_node_flowdata.widgets.as_deref().unwrap_or_default())
+            else { println!("{fail_str} match the widget values of node {} ({}) to its inputs for !yara_unmute", new_node_flowdata.id, new_node_flowdata.kind); return None; };
+        for (name, value) in widgets {
+            // A converted widget still has a value here, but the link takes priority
+            if !inputs.contains_key(&name) {
+                inputs.insert(name, value);
             }
         }
 
         let mut node_contents = serde_json::Map::new();
-        node_contents.insert("class_type".to_string(), Value::String(new_node_flowdata.kind));
+        node_contents.insert("class_type".to_string(), Value::String(new_node_flowdata.kind.clone()));
         node_contents.insert("inputs".to_string(), Value::Object(inputs));
 
         // Create the muted node and add it into our new prompt
@@ -322,6 +256,40 @@ pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory:
         }
     }
 
+    // Override input values on any nodes marked with !yset
+    for node in &flow_nodes {
+        let Some(ref title) = node.custom_title else { continue; };
+        let Some(pairs) = parse_yara_set(title) else { continue; };
+        if pairs.is_empty() {
+            println!("\x1b[33mwarning\x1b[0m:// \x1b[33m{filename}\x1b[0m // detected !yara_set keyword on node {}, but no key=value pairs followed it.", node.id);
+            continue;
+        }
+        let Some(api_node) = new_api_nodes.iter_mut().find(|x| x.id == node.id)
+            else { println!("\x1b[33mwarning\x1b[0m:// \x1b[33m{filename}\x1b[0m // detected !yara_set keyword on node {}, but the node isn't part of the prompt (is it muted?).", node.id); continue; };
+        let Some(api_inputs) = api_node.contents.get_mut("inputs").and_then(|x| x.as_object_mut())
+            else { println!("{fail_str} read inputs of node {} for !yara_set", node.id); return None; };
+
+        for (key, raw) in pairs {
+            let Some(old_value) = api_inputs.get(&key)
+                else { println!("{fail_str} set '{key}' on node {} ({}) - it has no input with that name. Available: {:?}", node.id, node.kind, api_inputs.keys().collect::<Vec<_>>()); return None; };
+            if old_value.is_array() {
+                println!("{fail_str} set '{key}' on node {} ({}) - that input is connected to another node", node.id, node.kind); return None;
+            }
+            let Some(new_value) = convert_yara_set_value(old_value, &raw)
+                else { println!("{fail_str} set '{key}' on node {} ({}) - '{raw}' isn't a valid value (current value is {old_value})", node.id, node.kind); return None; };
+
+            // Keep the embedded workflow in sync with the prompt
+            let node_schema = schema::object_info(ip_port).and_then(|x| x.get(&node.kind));
+            let Some(index) = find_widget_index(node, &key, old_value, node_schema)
+                else { println!("{fail_str} find the widget for '{key}' in the workflow of node {} ({}) for !yara_set", node.id, node.kind); return None; };
+            if set_widget_value_in_workflow_json(&mut flow_data, node.id, index, new_value.clone()).is_none() {
+                println!("{fail_str} set widget value in workflow for !yara_set"); return None;
+            }
+            api_inputs.insert(key, new_value);
+        }
+        yara_set_counter += 1;
+    }
+
 
     // Search for node(s) marked !ylh
     let mut node_ids_to_replace: Vec<u64> = Vec::new();
@@ -412,7 +380,15 @@ pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory:
     }
 
 
-    // Add marker to randomize seed?
+    // Find the seed(s) marked with !yseed
+    let mut seed_targets: Vec<SeedTarget> = Vec::new();
+    for node in &flow_nodes {
+        let Some(ref title) = node.custom_title else { continue; };
+        let Some(mode) = parse_yara_seed(title) else { continue; };
+        let Some(target) = get_seed_target(node, mode, &new_api_nodes, &flow_nodes, &flow_links, schema::object_info(ip_port))
+            else { println!("{fail_str} find a seed to change on node {} ({}) for !yara_seed", node.id, node.kind); return None; };
+        seed_targets.push(target);
+    }
 
 
     let mut json_prompt = serde_json::Map::new();
@@ -420,52 +396,171 @@ pub fn regen_modified_workflows(filepath: &PathBuf, mut comfyui_input_directory:
         json_prompt.insert(node.id.to_string(), Value::Object(node.contents));
     }
 
-    if yara_unmute_counter + yara_mute_counter + yara_load_here_counter == 0 {
-        println!("\x1b[31mwarning\x1b[0m:// \x1b[33m{filename}.png\x1b[0m // no nodes in this image's workflow had active keywords (!yara_unmute, !yara_mute, !yara_load_here) in their titles. Skipping.");
+    if yara_unmute_counter + yara_mute_counter + yara_load_here_counter + yara_set_counter + seed_targets.len() == 0 {
+        println!("\x1b[31mwarning\x1b[0m:// \x1b[33m{filename}\x1b[0m // no nodes in this image's workflow had active keywords (!yara_unmute, !yara_mute, !yara_load_here, !yara_set, !yara_seed) in their titles. Skipping.");
         return None;
     }
 
-    let succ_str = "\x1b[32mprepped\x1b[0m:// \x1b[32m".to_string() + &filename + &".png\x1b[0m // ";
-    println!("{succ_str}{yara_unmute_counter} nodes unmuted, {yara_mute_counter} nodes muted, {yara_load_here_counter} nodes replaced with LoadImage node.");
-    Some(YaraPrompt::new(json_prompt, flow_data, &ip_port))
+    // One prompt per seed in the sweep (or just one, if nothing is swept), each with its own seeds written into both the prompt and the workflow
+    let prompt_count = seed_targets.iter().map(|x| match x.mode {
+        SeedMode::Random => 1,
+        SeedMode::Sweep(n) => n,
+    }).max().unwrap_or(1);
+    let mut yara_prompts = Vec::new();
+    for i in 0..prompt_count {
+        let mut json_prompt = json_prompt.clone();
+        let mut flow_data = flow_data.clone();
+        for target in &seed_targets {
+            if set_seed(target, target.seed(i), &mut json_prompt, &mut flow_data).is_none() {
+                println!("{fail_str} set seed for !yara_seed"); return None;
+            }
+        }
+        yara_prompts.push(YaraPrompt::new(json_prompt, flow_data, ip_port));
+    }
+
+    let succ_str = format!("\x1b[32mprepped\x1b[0m:// \x1b[32m{filename}\x1b[0m // ");
+    println!("{succ_str}{yara_unmute_counter} nodes unmuted, {yara_mute_counter} nodes muted, {yara_load_here_counter} nodes replaced with LoadImage node, {yara_set_counter} nodes with values set, {} seeds changed ({prompt_count} prompts).", seed_targets.len());
+    Some(yara_prompts)
 }
 
 
 
 
-pub fn match_header_string_and_read_data<R: Read>(reader: &mut BufReader<R>, header: [u8; 10]) -> Result<Vec<u8>, std::io::Error> {
-    let mut buf = [0u8; 5];
-    'search_for_marker: loop {
-        reader.read_exact(&mut buf)?; // UnexpectedEof: no header found
-        for i in 0..(header.len()-4) {
-            if header[i..(i+5)] == buf {
-                break 'search_for_marker;
+// Parse the key=value pairs following a !yara_set (or !yset) keyword in a node title, e.g. "!yset denoise=0.45 steps=30".
+// Returns None if the title doesn't contain the keyword. Pairs end at the next keyword, or at anything that isn't key=value.
+fn parse_yara_set(title: &str) -> Option<Vec<(String, String)>> {
+    let mut words = title.split_whitespace();
+    words.find(|x| matches!(x.to_lowercase().as_str(), "!yara_set" | "!yset"))?;
+    let mut pairs = Vec::new();
+    for word in words {
+        if word.starts_with('!') { break; }
+        let Some((key, value)) = word.split_once('=') else { break; };
+        if key.is_empty() || value.is_empty() { break; }
+        pairs.push((key.to_string(), value.to_string()));
+    }
+    Some(pairs)
+}
+// The same range ComfyUI's frontend picks from when randomizing a seed
+const MAX_RANDOM_SEED: u64 = 1125899906842624;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SeedMode {
+    Random,
+    // N prompts, with the N seeds that follow the image's seed (which was already generated)
+    Sweep(u64),
+}
+#[derive(Debug)]
+struct SeedTarget {
+    mode: SeedMode,
+    current_seed: u64,
+    // (node id, input name) in the API prompt
+    api_inputs: Vec<(u64, String)>,
+    // (node id, index into widgets_values) in the workflow
+    widgets: Vec<(u64, usize)>,
+}
+impl SeedTarget {
+    // The seed for the i-th prompt (from 0)
+    fn seed(&self, i: u64) -> u64 {
+        match self.mode {
+            SeedMode::Random => fastrand::u64(0..MAX_RANDOM_SEED),
+            SeedMode::Sweep(_) => self.current_seed.wrapping_add(i + 1),
+        }
+    }
+}
+// "!yseed" on its own randomizes the seed. "!yseed 8" makes 8 prompts with the 8 seeds after the image's one.
+fn parse_yara_seed(title: &str) -> Option<SeedMode> {
+    let mut words = title.split_whitespace();
+    words.find(|x| matches!(x.to_lowercase().as_str(), "!yara_seed" | "!yseed"))?;
+    match words.next().and_then(|x| x.parse::<u64>().ok()) {
+        Some(n) if n > 0 => Some(SeedMode::Sweep(n)),
+        _ => Some(SeedMode::Random),
+    }
+}
+// Work out where a marked node's seed lives.
+// Samplers (or any other node with a seed widget) hold it in their own inputs. A seed primitive only exists in the workflow -
+// in the API prompt, its value is copied into the input of every node it's connected to.
+fn get_seed_target(node: &FlowNodeData, mode: SeedMode, api_nodes: &[Node], flow_nodes: &Vec<FlowNodeData>, flow_links: &Vec<LinkData>, object_info: Option<&ObjectInfo>) -> Option<SeedTarget> {
+    let mut target = SeedTarget { mode, current_seed: 0, api_inputs: Vec::new(), widgets: Vec::new() };
+
+    if node.kind == "PrimitiveNode" {
+        target.current_seed = node.widgets.as_ref()?.first()?.as_u64()?;
+        target.widgets.push((node.id, 0));
+        for linkdata in flow_links.iter().filter(|x| x.from_node_id == node.id) {
+            let (to_node_id, _, input_name) = get_output_info(linkdata, flow_links, flow_nodes)?;
+            let Some(api_node) = api_nodes.iter().find(|x| x.id == to_node_id) else { continue; }; // muted
+            let api_value = api_node.contents.get("inputs")?.get(&input_name)?;
+            let to_node = flow_nodes.iter().find(|x| x.id == to_node_id)?;
+            // Converted widgets keep their value in widgets_values
+            let node_schema = object_info.and_then(|x| x.get(&to_node.kind));
+            if let Some(index) = find_widget_index(to_node, &input_name, api_value, node_schema) {
+                target.widgets.push((to_node_id, index));
             }
+            target.api_inputs.push((to_node_id, input_name));
         }
+        if target.api_inputs.is_empty() { return None; }
+        return Some(target);
     }
 
-    let mut byte = [0u8; 1];
-    let mut data: Vec<u8> = Vec::new();
-    loop {
-        reader.read_exact(&mut byte)?;
-        if byte == [b'{'] {
-            data.push(byte[0]);
-            break;
+    let api_inputs = api_nodes.iter().find(|x| x.id == node.id)?.contents.get("inputs")?.as_object()?;
+    let (name, value) = ["seed", "noise_seed", "value"].iter().find_map(|x| Some((x.to_string(), api_inputs.get(*x)?)))?;
+    // A seed that comes from another node has to be changed on that node instead
+    target.current_seed = value.as_u64()?;
+    let node_schema = object_info.and_then(|x| x.get(&node.kind));
+    target.widgets.push((node.id, find_widget_index(node, &name, value, node_schema)?));
+    target.api_inputs.push((node.id, name));
+    Some(target)
+}
+fn set_seed(target: &SeedTarget, seed: u64, json_prompt: &mut serde_json::Map<String, Value>, flow_data: &mut Value) -> Option<()> {
+    for (node_id, name) in &target.api_inputs {
+        let inputs = json_prompt.get_mut(&node_id.to_string())?.get_mut("inputs")?.as_object_mut()?;
+        inputs.insert(name.clone(), Value::Number(seed.into()));
+    }
+    for (node_id, index) in &target.widgets {
+        set_widget_value_in_workflow_json(flow_data, *node_id, *index, Value::Number(seed.into()))?;
+    }
+    Some(())
+}
+// Convert a !yara_set value to the same kind of value the input currently holds
+fn convert_yara_set_value(old_value: &Value, raw: &str) -> Option<Value> {
+    match old_value {
+        Value::String(_) => Some(Value::String(raw.trim_matches('"').to_string())),
+        Value::Bool(_) => Some(Value::Bool(raw.to_lowercase().parse().ok()?)),
+        // ComfyUI writes whole floats without a decimal point (denoise 1.0 => 1), so any number is accepted for a number input
+        Value::Number(_) => {
+            if let Ok(n) = raw.parse::<u64>() {
+                Some(Value::Number(n.into()))
+            } else if let Ok(n) = raw.parse::<i64>() {
+                Some(Value::Number(n.into()))
+            } else {
+                Some(Value::Number(serde_json::Number::from_f64(raw.parse::<f64>().ok()?)?))
+            }
         }
+        _ => None,
     }
-    let mut opening_bracket_count = 1;
-    while opening_bracket_count > 0 {
-        reader.read_exact(&mut byte)?;
-        match byte {
-            [b'{'] => opening_bracket_count += 1,
-            [b'}'] => opening_bracket_count -= 1,
-            _ => (),
+}
+// Find the position of a widget in a workflow node's widgets_values.
+// Widgets aren't named in the workflow, so this uses ComfyUI's node definitions (see schema.rs) when they're available.
+// Otherwise (or if the definition doesn't match the workflow's version of the node), it falls back to the only widget holding
+// the input's current value.
+fn find_widget_index(node: &FlowNodeData, name: &str, current_value: &Value, node_schema: Option<&NodeSchema>) -> Option<usize> {
+    let widget_matches = |x: &Value| match (x, current_value) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (a, b) => a == b,
+    };
+    let widgets = node.widgets.as_ref()?;
+    if let Some(index) = node_schema.and_then(|x| x.widget_index(name)) {
+        if widgets.get(index).is_some_and(widget_matches) {
+            return Some(index);
         }
-        data.push(byte[0]);
     }
-
-    Ok(data)
+    let mut matching = widgets.iter().enumerate().filter(|x| widget_matches(x.1)).map(|x| x.0);
+    let index = matching.next()?;
+    if matching.next().is_some() {
+        return None; // ambiguous
+    }
+    Some(index)
 }
+
 fn link_input_is_reroute(flow_nodes: &Vec<FlowNodeData>, linkdata: &LinkData) -> Option<bool> {
     if "Reroute" == flow_nodes.get(flow_nodes.iter().position(|x| x.id == linkdata.from_node_id)?)?.kind {
         Some(true)
@@ -530,6 +625,16 @@ fn unmute_node_in_workflow_json(mut flow_data: &mut Value, unmute_node_id: u64)
 fn mute_node_in_workflow_json(mut flow_data: &mut Value, mute_node_id: u64) -> Option<()> {
     change_node_mode_in_workflow_json(&mut flow_data, mute_node_id, 2)
 }
+fn set_widget_value_in_workflow_json(flow_data: &mut Value, node_id: u64, index: usize, value: Value) -> Option<()> {
+    for x in flow_data.as_object_mut()?.get_mut("nodes")?.as_array_mut()?.iter_mut() {
+        if x.as_object()?.get("id")?.as_u64()? == node_id {
+            let widget = x.as_object_mut()?.get_mut("widgets_values")?.as_array_mut()?.get_mut(index)?;
+            *widget = value;
+            return Some(());
+        }
+    }
+    None
+}
 fn replace_node_loadimage_in_workflow_json(flow_data: &mut Value, replace_node_id: u64, filename: &str) -> Option<()> {
     fn get_original_node_data(flow_data: &Value, replace_node_id: u64) -> Option<(f64, f64, f64, f64, u64, u64)> {
         for node in flow_data.as_object()?.get("nodes")?.as_array()? {
@@ -619,3 +724,111 @@ fn replace_node_loadimage_in_workflow_json(flow_data: &mut Value, replace_node_i
     }
     Some(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn flow_node(id: u64, kind: &str, widgets: Value) -> FlowNodeData {
+        FlowNodeData {
+            id, muted: false, custom_title: None, widgets: widgets.as_array().cloned(), inputs: None, kind: kind.to_string(), output_types: None,
+        }
+    }
+
+    #[test]
+    fn parses_yara_set() {
+        let pairs = |title: &str| parse_yara_set(title).map(|x| x.into_iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>());
+        assert_eq!(pairs("Sampler !yset denoise=0.45 steps=30"), Some(vec!["denoise=0.45".to_string(), "steps=30".to_string()]));
+        assert_eq!(pairs("!YARA_SET sampler_name=\"euler\" !yseed"), Some(vec!["sampler_name=\"euler\"".to_string()]));
+        // Pairs end at the first word that isn't one
+        assert_eq!(pairs("!yset cfg=7 oops steps=30"), Some(vec!["cfg=7".to_string()]));
+        assert_eq!(pairs("!yset =7 cfg="), Some(vec![]));
+        assert_eq!(pairs("KSampler"), None);
+    }
+
+    #[test]
+    fn converts_yara_set_values() {
+        assert_eq!(convert_yara_set_value(&json!(20), "30"), Some(json!(30)));
+        assert_eq!(convert_yara_set_value(&json!(20), "-3"), Some(json!(-3)));
+        // Whole floats are saved as integers, so any number goes
+        assert_eq!(convert_yara_set_value(&json!(1), "0.45"), Some(json!(0.45)));
+        assert_eq!(convert_yara_set_value(&json!(1.0), "abc"), None);
+        assert_eq!(convert_yara_set_value(&json!(true), "False"), Some(json!(false)));
+        assert_eq!(convert_yara_set_value(&json!(true), "no"), None);
+        assert_eq!(convert_yara_set_value(&json!("euler"), "\"dpmpp_2m\""), Some(json!("dpmpp_2m")));
+        // Links can't be set
+        assert_eq!(convert_yara_set_value(&json!(["4", 0]), "1"), None);
+    }
+
+    fn api_node(id: u64, inputs: Value) -> Node {
+        Node { id, contents: json!({ "inputs": inputs }).as_object().unwrap().clone() }
+    }
+
+    #[test]
+    fn parses_yara_seed() {
+        assert_eq!(parse_yara_seed("Sampler !yseed"), Some(SeedMode::Random));
+        assert_eq!(parse_yara_seed("!YARA_SEED 8 !ym"), Some(SeedMode::Sweep(8)));
+        assert_eq!(parse_yara_seed("!yseed 0"), Some(SeedMode::Random));
+        assert_eq!(parse_yara_seed("!yseed -3"), Some(SeedMode::Random));
+        assert_eq!(parse_yara_seed("!yseedy"), None);
+
+        // A sweep starts after the image's own seed
+        let target = SeedTarget { mode: SeedMode::Sweep(3), current_seed: 100, api_inputs: Vec::new(), widgets: Vec::new() };
+        assert_eq!((0..3).map(|i| target.seed(i)).collect::<Vec<_>>(), [101, 102, 103]);
+    }
+
+    #[test]
+    fn finds_seeds() {
+        // A seed primitive, connected to a sampler and to a muted one that isn't in the prompt
+        let mut sampler = flow_node(3, "KSampler", json!([123, "fixed", 20]));
+        sampler.inputs = Some(vec![ApiInput { link_id: Some(1), name: "seed".to_string() }]);
+        let mut muted = flow_node(5, "KSampler", json!([123, "fixed", 20]));
+        muted.inputs = Some(vec![ApiInput { link_id: Some(2), name: "seed".to_string() }]);
+        let primitive = flow_node(10, "PrimitiveNode", json!([123, "fixed"]));
+        let flow_nodes = vec![sampler, muted, primitive];
+        let flow_links = vec![
+            LinkData { link_id: 1, from_node_id: 10, from_node_slot: 0, to_node_id: 3, to_node_slot: 0 },
+            LinkData { link_id: 2, from_node_id: 10, from_node_slot: 0, to_node_id: 5, to_node_slot: 0 },
+        ];
+        let api_nodes = vec![api_node(3, json!({ "seed": 123, "steps": 20 }))];
+        let target = get_seed_target(&flow_nodes[2], SeedMode::Random, &api_nodes, &flow_nodes, &flow_links, None).unwrap();
+        assert_eq!(target.current_seed, 123);
+        assert_eq!(target.api_inputs, [(3, "seed".to_string())]);
+        assert_eq!(target.widgets, [(10, 0), (3, 0)]);
+
+        // noise_seed (KSamplerAdvanced) and value (seed nodes) on the marked node itself
+        let advanced = flow_node(4, "KSamplerAdvanced", json!(["enable", 77, "fixed", 20]));
+        let api_nodes = vec![api_node(4, json!({ "add_noise": "enable", "noise_seed": 77, "steps": 20 }))];
+        let target = get_seed_target(&advanced, SeedMode::Sweep(2), &api_nodes, &flow_nodes, &flow_links, None).unwrap();
+        assert_eq!((target.current_seed, target.api_inputs, target.widgets), (77, vec![(4, "noise_seed".to_string())], vec![(4, 1)]));
+        let seed_node = flow_node(6, "Seed (rgthree)", json!([9, null]));
+        let api_nodes = vec![api_node(6, json!({ "value": 9 }))];
+        let target = get_seed_target(&seed_node, SeedMode::Random, &api_nodes, &flow_nodes, &flow_links, None).unwrap();
+        assert_eq!((target.api_inputs, target.widgets), (vec![(6, "value".to_string())], vec![(6, 0)]));
+
+        // A seed that comes from another node isn't the marked node's to change
+        let api_nodes = vec![api_node(4, json!({ "noise_seed": ["10", 0] }))];
+        assert!(get_seed_target(&advanced, SeedMode::Random, &api_nodes, &flow_nodes, &flow_links, None).is_none());
+    }
+
+    #[test]
+    fn finds_widgets() {
+        let info = schema::parse_object_info(r#"{"KSampler": {"input": {"required": {
+            "seed": ["INT", {}], "steps": ["INT", {}], "cfg": ["FLOAT", {}], "denoise": ["FLOAT", {}]}}}}"#).unwrap();
+        let sampler = info.get("KSampler");
+        let node = flow_node(3, "KSampler", json!([5, "randomize", 20, 8, 1]));
+        assert_eq!(find_widget_index(&node, "steps", &json!(20), sampler), Some(2));
+        assert_eq!(find_widget_index(&node, "denoise", &json!(1.0), sampler), Some(4));
+
+        // Without definitions, by value alone, which can be ambiguous
+        let node = flow_node(3, "KSampler", json!([5, "randomize", 20, 20, 1]));
+        assert_eq!(find_widget_index(&node, "steps", &json!(20), None), None);
+        assert_eq!(find_widget_index(&node, "seed", &json!(5), None), Some(0));
+
+        // A definition that doesn't match the workflow's version of the node isn't trusted
+        let node = flow_node(3, "KSampler", json!([5, "randomize", 8, 20, 1]));
+        assert_eq!(find_widget_index(&node, "steps", &json!(20), sampler), Some(3));
+        assert_eq!(find_widget_index(&node, "steps", &json!(30), sampler), None);
+    }
+}
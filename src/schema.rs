@@ -0,0 +1,239 @@
+use std::fs;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde_json::Value;
+
+use crate::get_appdata;
+use crate::comfyui;
+
+// Node definitions from ComfyUI's /object_info endpoint.
+//
+// Widget values in workflow metadata aren't named - widgets_values is just a list, in the order the node defines its inputs.
+// (https://github.com/comfyanonymous/ComfyUI/issues/2275)
+// /object_info lists every node's inputs, so we can work out which value belongs to which input.
+// Some widgets (control_after_generate on seeds, the upload button on LoadImage) are only part of ComfyUI's frontend.
+// They have a value in widgets_values, but no input in the API prompt.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WidgetSlot {
+    Input(String),
+    FrontendOnly,
+}
+
+#[derive(Debug)]
+pub struct NodeSchema {
+    // Every entry in widgets_values, in order
+    pub widgets: Vec<WidgetSlot>,
+}
+
+pub struct ObjectInfo {
+    nodes: HashMap<String, NodeSchema>,
+}
+
+impl ObjectInfo {
+    pub fn get(&self, kind: &str) -> Option<&NodeSchema> {
+        self.nodes.get(kind)
+    }
+}
+
+impl NodeSchema {
+    // Pair up a node's widgets_values with the names of the inputs they belong to, dropping frontend-only widgets.
+    // Returns None if there are fewer values than the node definition expects (e.g. the node has changed since the image was made).
+    pub fn name_widgets(&self, widgets_values: &[Value]) -> Option<Vec<(String, Value)>> {
+        if widgets_values.len() < self.widgets.len() { return None; }
+        let mut named = Vec::new();
+        for (slot, value) in self.widgets.iter().zip(widgets_values) {
+            if let WidgetSlot::Input(name) = slot {
+                named.push((name.clone(), value.clone()));
+            }
+        }
+        Some(named)
+    }
+    pub fn widget_index(&self, name: &str) -> Option<usize> {
+        self.widgets.iter().position(|x| x == &WidgetSlot::Input(name.to_string()))
+    }
+}
+
+// Fetched (or read from the cache) the first time each server's are needed. Kept for the rest of the run.
+static OBJECT_INFO: OnceLock<Mutex<HashMap<String, Option<&'static ObjectInfo>>>> = OnceLock::new();
+
+// Node definitions from the server, falling back to the copy cached the last time they were fetched
+pub fn object_info(ip_port: &str) -> Option<&'static ObjectInfo> {
+    let mut servers = OBJECT_INFO.get_or_init(Default::default).lock().unwrap();
+    *servers.entry(ip_port.to_string()).or_insert_with(|| {
+        let info = load_object_info(ip_port)?;
+        Some(Box::leak(Box::new(info)))
+    })
+}
+fn load_object_info(ip_port: &str) -> Option<ObjectInfo> {
+    // Each server has its own cache, as they can have different custom nodes installed
+    let cache_file = format!("{}/yara/object_info_{}.json", get_appdata(), ip_port.replace(|x: char| !x.is_ascii_alphanumeric(), "_"));
+    let text = match comfyui::get_object_info(ip_port) {
+        Ok(text) => {
+            if let Err(e) = fs::write(&cache_file, &text) {
+                println!("\x1b[33mwarning\x1b[0m:// failed to cache node definitions to {cache_file}: {e}");
+            }
+            text
+        }
+        Err(e) => {
+            println!("\x1b[33mwarning\x1b[0m:// couldn't get node definitions from {ip_port} ({e}), using the cached copy.");
+            fs::read_to_string(&cache_file).ok()?
+        }
+    };
+    parse_object_info(&text)
+}
+pub fn parse_object_info(text: &str) -> Option<ObjectInfo> {
+    let json: HashMap<String, MaybeNodeDefinition> = serde_json::from_str(text).ok()?;
+    let nodes = json.into_iter().filter_map(|(kind, definition)| match definition {
+        MaybeNodeDefinition::Valid(x) => Some((kind, x.schema())),
+        MaybeNodeDefinition::Invalid(_) => None,
+    }).collect();
+    Some(ObjectInfo { nodes })
+}
+
+// Custom nodes can define their inputs in odd ways - skip any we can't read, rather than failing on all of them
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum MaybeNodeDefinition {
+    Valid(NodeDefinition),
+    Invalid(serde::de::IgnoredAny),
+}
+#[derive(serde::Deserialize)]
+struct NodeDefinition {
+    input: Inputs,
+    // Newer ComfyUI versions list the input order separately
+    input_order: Option<InputOrder>,
+}
+#[derive(serde::Deserialize)]
+struct Inputs {
+    #[serde(default, deserialize_with = "ordered_map_or_null")]
+    required: OrderedMap,
+    #[serde(default, deserialize_with = "ordered_map_or_null")]
+    optional: OrderedMap,
+}
+fn ordered_map_or_null<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OrderedMap, D::Error> {
+    Ok(Option::<OrderedMap>::deserialize(deserializer)?.unwrap_or_default())
+}
+#[derive(serde::Deserialize)]
+struct InputOrder {
+    #[serde(default)]
+    required: Vec<String>,
+    #[serde(default)]
+    optional: Vec<String>,
+}
+
+impl NodeDefinition {
+    fn schema(&self) -> NodeSchema {
+        let inputs: Vec<&(String, Value)> = match &self.input_order {
+            Some(order) => order.required.iter().chain(&order.optional)
+                .filter_map(|name| self.input.required.get(name).or(self.input.optional.get(name)))
+                .collect(),
+            None => self.input.required.0.iter().chain(&self.input.optional.0).collect(),
+        };
+
+        let mut widgets = Vec::new();
+        for (name, spec) in inputs {
+            let kind = spec.get(0);
+            let options = spec.get(1);
+            let option = |key: &str| options.and_then(|x| x.get(key)).and_then(|x| x.as_bool()).unwrap_or(false);
+
+            // Combos are either a list of options, or "COMBO" in newer versions. Everything else that isn't a primitive type is a link.
+            let is_widget = match kind {
+                Some(Value::Array(_)) => true,
+                Some(Value::String(x)) => matches!(x.as_str(), "INT" | "FLOAT" | "STRING" | "BOOLEAN" | "COMBO"),
+                _ => false,
+            };
+            if !is_widget || option("forceInput") { continue; }
+            widgets.push(WidgetSlot::Input(name.clone()));
+
+            let is_seed = kind == Some(&Value::String("INT".to_string())) && matches!(name.as_str(), "seed" | "noise_seed");
+            if option("control_after_generate") || is_seed {
+                widgets.push(WidgetSlot::FrontendOnly);
+            }
+            if options.and_then(|x| x.as_object()).is_some_and(|x| x.iter().any(|(k, v)| k.ends_with("_upload") && v == &Value::Bool(true))) {
+                widgets.push(WidgetSlot::FrontendOnly);
+            }
+        }
+        NodeSchema { widgets }
+    }
+}
+
+// A JSON object that keeps its keys in order (serde_json's Map sorts them, and input order matters here)
+#[derive(Default)]
+struct OrderedMap(Vec<(String, Value)>);
+
+impl OrderedMap {
+    fn get(&self, key: &str) -> Option<&(String, Value)> {
+        self.0.iter().find(|x| x.0 == key)
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderedMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OrderedMapVisitor;
+        impl<'de> Visitor<'de> for OrderedMapVisitor {
+            type Value = OrderedMap;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a JSON object")
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<OrderedMap, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry::<String, Value>()? {
+                    entries.push(entry);
+                }
+                Ok(OrderedMap(entries))
+            }
+        }
+        deserializer.deserialize_map(OrderedMapVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn input(name: &str) -> WidgetSlot {
+        WidgetSlot::Input(name.to_string())
+    }
+
+    #[test]
+    fn reads_widgets_from_node_definitions() {
+        let info = parse_object_info(r#"{
+            "KSampler": { "input": { "required": {
+                "model": ["MODEL"], "seed": ["INT", {"default": 0}], "steps": ["INT", {}],
+                "sampler_name": [["euler", "dpmpp_2m"]], "scheduler": ["COMBO", {"options": ["normal"]}]
+            } } },
+            "LoadImage": { "input": { "required": { "image": [["a.png"], {"image_upload": true}] } } },
+            "Ordered": {
+                "input": { "required": { "b": ["FLOAT"], "a": ["STRING", {"multiline": true}] },
+                           "optional": { "c": ["BOOLEAN"], "linked": ["INT", {"forceInput": true}], "count": ["INT", {"control_after_generate": true}] } },
+                "input_order": { "required": ["a", "b"], "optional": ["count", "c", "linked"] }
+            },
+            "NoOptional": { "input": { "required": { "x": ["INT"] }, "optional": null } },
+            "Broken": { "input": "not an object" }
+        }"#).unwrap();
+
+        // Seeds get a control_after_generate widget, and links aren't widgets
+        assert_eq!(info.get("KSampler").unwrap().widgets, [input("seed"), WidgetSlot::FrontendOnly, input("steps"), input("sampler_name"), input("scheduler")]);
+        // The upload button
+        assert_eq!(info.get("LoadImage").unwrap().widgets, [input("image"), WidgetSlot::FrontendOnly]);
+        // input_order wins over the order in the map, and forced inputs are links
+        assert_eq!(info.get("Ordered").unwrap().widgets, [input("a"), input("b"), input("count"), WidgetSlot::FrontendOnly, input("c")]);
+        assert_eq!(info.get("NoOptional").unwrap().widgets, [input("x")]);
+        // A custom node that can't be read is left out, without losing the rest
+        assert!(info.get("Broken").is_none());
+        assert!(parse_object_info("[]").is_none());
+    }
+
+    #[test]
+    fn names_widget_values() {
+        let schema = NodeSchema { widgets: vec![input("seed"), WidgetSlot::FrontendOnly, input("steps")] };
+        let named = schema.name_widgets(&[json!(5), json!("randomize"), json!(20), json!("extra")]).unwrap();
+        assert_eq!(named, [("seed".to_string(), json!(5)), ("steps".to_string(), json!(20))]);
+        assert_eq!(schema.name_widgets(&[json!(5), json!("randomize")]), None);
+        assert_eq!(schema.widget_index("steps"), Some(2));
+        assert_eq!(schema.widget_index("cfg"), None);
+    }
+}
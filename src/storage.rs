@@ -0,0 +1,222 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::get_appdata;
+
+// Workflows of prompts that yara has queued, kept until the workflow is in the generated image (see fix.rs).
+// Each one is its own file, named after the prompt's hash (see data::hash_nodemap), so adding or removing one doesn't
+// rewrite the others. Files are written to a temporary name first and then renamed, so a crash can't leave half a file.
+//
+// Older versions kept everything in one workflow_storage.json - that's moved into the folder the first time it's opened.
+
+const DIRECTORY_NAME: &str = "workflow_storage";
+const OLD_FILE_NAME: &str = "workflow_storage.json";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredWorkflow {
+    // Unix time, in seconds
+    pub created: u64,
+    // The saved queue it was loaded from, if any
+    pub queue: Option<String>,
+    pub workflow: Value,
+}
+
+// An entry, without the workflow itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageEntry {
+    pub hash: String,
+    pub created: u64,
+    pub queue: Option<String>,
+}
+
+pub struct WorkflowStorage {
+    dir: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct OldWorkflowStorage {
+    workflows: HashMap<String, Value>,
+}
+
+impl WorkflowStorage {
+    pub fn open() -> WorkflowStorage {
+        let root = PathBuf::from(get_appdata()).join("yara");
+        let storage = WorkflowStorage::open_in(&root.join(DIRECTORY_NAME));
+        storage.migrate(&root.join(OLD_FILE_NAME));
+        storage
+    }
+    pub fn open_in(dir: &Path) -> WorkflowStorage {
+        if let Err(e) = fs::create_dir_all(dir) {
+            println!("\x1b[31merror\x1b[0m:// couldn't create the workflow storage folder {}: {e}", dir.display());
+        }
+        WorkflowStorage { dir: dir.to_path_buf() }
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(hash.to_string() + ".json")
+    }
+
+    pub fn insert(&self, hash: &str, workflow: &Value, queue: Option<&str>) -> io::Result<()> {
+        let entry = StoredWorkflow { created: now(), queue: queue.map(|x| x.to_string()), workflow: workflow.clone() };
+        self.write(hash, &entry)
+    }
+    fn write(&self, hash: &str, entry: &StoredWorkflow) -> io::Result<()> {
+        let temporary = self.dir.join(format!(".{hash}.{}.tmp", std::process::id()));
+        fs::write(&temporary, serde_json::to_vec(entry)?)?;
+        fs::rename(&temporary, self.path(hash))
+    }
+
+    pub fn get(&self, hash: &str) -> Option<StoredWorkflow> {
+        let text = fs::read(self.path(hash)).ok()?;
+        match serde_json::from_slice(&text) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                println!("\x1b[33mwarning\x1b[0m:// stored workflow {hash} is unreadable: {e}");
+                None
+            }
+        }
+    }
+    pub fn get_workflow(&self, hash: &str) -> Option<Value> {
+        self.get(hash).map(|x| x.workflow)
+    }
+
+    // Removing something that isn't there isn't an error
+    pub fn remove(&self, hash: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(hash)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // Every entry, oldest first. Unreadable files are skipped.
+    pub fn entries(&self) -> Vec<StorageEntry> {
+        let Ok(dir) = fs::read_dir(&self.dir) else { return Vec::new(); };
+        let mut entries: Vec<StorageEntry> = dir.filter_map(|x| {
+            let path = x.ok()?.path();
+            if path.extension()? != "json" { return None; }
+            let hash = path.file_stem()?.to_str()?.to_string();
+            let entry = self.get(&hash)?;
+            Some(StorageEntry { hash, created: entry.created, queue: entry.queue })
+        }).collect();
+        entries.sort_by_key(|x| (x.created, x.hash.clone()));
+        entries
+    }
+
+    // Remove entries created more than `days` days ago, returning them
+    pub fn prune(&self, days: u64) -> io::Result<Vec<StorageEntry>> {
+        let cutoff = now().saturating_sub(days * SECONDS_PER_DAY);
+        let old: Vec<StorageEntry> = self.entries().into_iter().filter(|x| x.created < cutoff).collect();
+        for entry in &old {
+            self.remove(&entry.hash)?;
+        }
+        Ok(old)
+    }
+
+    // Move the entries of an old workflow_storage.json in, dated from when that file was last changed
+    fn migrate(&self, old_file: &Path) {
+        let Ok(text) = fs::read(old_file) else { return; };
+        let old: OldWorkflowStorage = match serde_json::from_slice(&text) {
+            Ok(x) => x,
+            Err(e) => { println!("\x1b[33mwarning\x1b[0m:// couldn't read the old workflow storage file {}: {e}", old_file.display()); return; }
+        };
+        let created = fs::metadata(old_file).and_then(|x| x.modified()).ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map_or(now(), |x| x.as_secs());
+        for (hash, workflow) in &old.workflows {
+            if let Err(e) = self.write(hash, &StoredWorkflow { created, queue: None, workflow: workflow.clone() }) {
+                println!("\x1b[31merror\x1b[0m:// couldn't move stored workflows out of {}: {e}", old_file.display());
+                return;
+            }
+        }
+        if let Err(e) = fs::remove_file(old_file) {
+            println!("\x1b[33mwarning\x1b[0m:// couldn't remove the old workflow storage file {}: {e}", old_file.display());
+        }
+        if !old.workflows.is_empty() {
+            println!("Moved {} stored workflows from {} into {}.", old.workflows.len(), old_file.display(), self.dir.display());
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs())
+}
+
+// e.g. "3 days", "5 hours"
+pub fn format_age(created: u64) -> String {
+    let secs = now().saturating_sub(created);
+    let (amount, unit) = match secs {
+        x if x >= SECONDS_PER_DAY => (x / SECONDS_PER_DAY, "day"),
+        x if x >= 60 * 60 => (x / (60 * 60), "hour"),
+        x => (x / 60, "minute"),
+    };
+    format!("{amount} {unit}{}", if amount == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn stores_one_file_per_workflow() {
+        let dir = temp_dir("storage");
+        let storage = WorkflowStorage::open_in(&dir.join(DIRECTORY_NAME));
+        storage.insert("aaaa", &serde_json::json!({ "last_node_id": 1 }), Some("overnight")).unwrap();
+        storage.insert("bbbb", &serde_json::json!({ "last_node_id": 2 }), None).unwrap();
+
+        let entry = storage.get("aaaa").unwrap();
+        assert_eq!(entry.queue.as_deref(), Some("overnight"));
+        assert_eq!(entry.workflow["last_node_id"], 1);
+        assert!(now() - entry.created < 60);
+        assert_eq!(storage.get_workflow("bbbb").unwrap()["last_node_id"], 2);
+        assert!(storage.get("cccc").is_none());
+
+        // Nothing but the entries themselves (no temporary files left behind)
+        let mut files: Vec<String> = fs::read_dir(dir.join(DIRECTORY_NAME)).unwrap().map(|x| x.unwrap().file_name().to_string_lossy().to_string()).collect();
+        files.sort();
+        assert_eq!(files, ["aaaa.json", "bbbb.json"]);
+
+        storage.remove("aaaa").unwrap();
+        storage.remove("aaaa").unwrap();
+        assert_eq!(storage.entries().iter().map(|x| x.hash.as_str()).collect::<Vec<_>>(), ["bbbb"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prunes_old_entries() {
+        let dir = temp_dir("prune");
+        let storage = WorkflowStorage::open_in(&dir);
+        let workflow = serde_json::json!({});
+        storage.write("old", &StoredWorkflow { created: now() - 10 * SECONDS_PER_DAY, queue: None, workflow: workflow.clone() }).unwrap();
+        storage.write("week", &StoredWorkflow { created: now() - 6 * SECONDS_PER_DAY, queue: None, workflow: workflow.clone() }).unwrap();
+        storage.insert("new", &workflow, None).unwrap();
+        fs::write(dir.join("broken.json"), "{").unwrap();
+
+        assert_eq!(storage.entries().iter().map(|x| x.hash.as_str()).collect::<Vec<_>>(), ["old", "week", "new"]);
+        let pruned = storage.prune(7).unwrap();
+        assert_eq!(pruned.iter().map(|x| x.hash.as_str()).collect::<Vec<_>>(), ["old"]);
+        assert_eq!(storage.entries().iter().map(|x| x.hash.as_str()).collect::<Vec<_>>(), ["week", "new"]);
+        assert_eq!(format_age(now() - 6 * SECONDS_PER_DAY), "6 days");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn migrates_old_storage_file() {
+        let dir = temp_dir("migrate");
+        let old_file = dir.join(OLD_FILE_NAME);
+        fs::write(&old_file, r#"{ "workflows": { "aaaa": { "last_node_id": 1 }, "bbbb": { "last_node_id": 2 } } }"#).unwrap();
+
+        let storage = WorkflowStorage::open_in(&dir.join(DIRECTORY_NAME));
+        storage.migrate(&old_file);
+        assert!(!old_file.exists());
+        assert_eq!(storage.entries().len(), 2);
+        assert_eq!(storage.get_workflow("bbbb").unwrap()["last_node_id"], 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,21 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Helpers shared by the test modules
+
+// An empty folder for one test, named so tests running at the same time don't share it
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("yara-test-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// A file from tests/fixtures
+pub fn fixture_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+pub fn fixture(name: &str) -> Vec<u8> {
+    fs::read(fixture_path(name)).unwrap()
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(target_os = "linux")]
+use inotify::{Inotify, WatchMask, EventMask, WatchDescriptor};
+
+use crate::metadata::{self, ImageFormat};
+
+// Watches the output folder (and every folder under it) for new images, for the preview window.
+// On Linux the kernel tells us about changes (inotify); elsewhere the folders are scanned every POLL_INTERVAL.
+//
+// ComfyUI writes an image in several steps, and yara may add a workflow to it afterwards, so an image is only reported once
+// nothing has happened to it for SETTLE_TIME and the file is complete (it ends the way its format ends).
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputEvent {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+// An image that still isn't complete after this long is given up on (e.g. it's not really an image)
+const GIVE_UP_TIME: Duration = Duration::from_secs(30);
+#[cfg(target_os = "linux")]
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Keeps watching until the receiver is dropped
+pub fn watch_output(dir: PathBuf) -> mpsc::Receiver<OutputEvent> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        #[cfg(target_os = "linux")]
+        match watch_with_inotify(&dir, &tx) {
+            Ok(()) => return,
+            Err(e) => println!("\x1b[33mwarning\x1b[0m:// couldn't watch {} for new images ({e}), checking it every second instead.", dir.display()),
+        }
+        watch_by_polling(&dir, &tx, POLL_INTERVAL);
+    });
+    rx
+}
+
+// The most recently changed image in the folder and its subfolders
+pub fn latest_image(dir: &Path) -> Option<PathBuf> {
+    recent_images(dir, 1).pop()
+}
+
+// The `count` most recently changed images in the folder and its subfolders, oldest first
+pub fn recent_images(dir: &Path, count: usize) -> Vec<PathBuf> {
+    let mut found = HashMap::new();
+    scan(dir, &mut found);
+    let mut files: Vec<(PathBuf, (SystemTime, u64))> = found.into_iter().collect();
+    files.sort_by_key(|(_, (modified, _))| *modified);
+    files.into_iter().rev().take(count).rev().map(|(path, _)| path).collect()
+}
+
+// Images waiting to settle, with when they last changed
+struct Pending {
+    images: HashMap<PathBuf, Instant>,
+}
+
+impl Pending {
+    fn touch(&mut self, path: PathBuf) {
+        self.images.insert(path, Instant::now());
+    }
+    // Report the images that have settled. Returns false once nobody is listening.
+    fn release(&mut self, tx: &mpsc::Sender<OutputEvent>) -> bool {
+        let mut settled: Vec<PathBuf> = Vec::new();
+        self.images.retain(|path, changed| {
+            let age = changed.elapsed();
+            if age < SETTLE_TIME { return true; }
+            if is_complete(path) { settled.push(path.clone()); return false; }
+            age < GIVE_UP_TIME
+        });
+        // Oldest first, so the newest image is the last one shown
+        settled.sort_by_key(|x| fs::metadata(x).and_then(|x| x.modified()).ok());
+        settled.into_iter().all(|path| tx.send(OutputEvent::Added(path)).is_ok())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn watch_with_inotify(dir: &Path, tx: &mpsc::Sender<OutputEvent>) -> io::Result<()> {
+    let mut inotify = Inotify::init()?;
+    let mut folders: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    let mut pending = Pending { images: HashMap::new() };
+    add_watches(&inotify, dir, &mut folders, None)?;
+    // Every change before this has been read
+    let mut caught_up = SystemTime::now();
+
+    let mut buffer = [0u8; 4096];
+    loop {
+        let events = match inotify.read_events(&mut buffer) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                caught_up = SystemTime::now();
+                if !pending.release(tx) { return Ok(()); }
+                thread::sleep(CHECK_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                // Too much happened at once and the kernel dropped events, so look for what was missed
+                add_watches(&inotify, dir, &mut folders, None)?;
+                let mut found = HashMap::new();
+                scan(dir, &mut found);
+                for (path, (modified, _)) in found {
+                    if modified >= caught_up { pending.touch(path); }
+                }
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                folders.remove(&event.wd);
+                continue;
+            }
+            let (Some(folder), Some(name)) = (folders.get(&event.wd), event.name) else { continue; };
+            let path = folder.join(name);
+            if event.mask.contains(EventMask::ISDIR) {
+                // A new folder (e.g. today's date folder): watch it too, and pick up anything already saved into it
+                // (one removed or renamed again before it's watched isn't an error)
+                if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    match add_watches(&inotify, &path, &mut folders, Some(&mut pending)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => (),
+                    }
+                }
+            } else if metadata::is_image_file(&path) {
+                if event.mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                    pending.images.remove(&path);
+                    if tx.send(OutputEvent::Removed(path)).is_err() { return Ok(()); }
+                } else {
+                    pending.touch(path);
+                }
+            }
+        }
+    }
+}
+
+// Watch a folder and every folder under it (symlinked folders aren't followed, so there's no going round in circles).
+// With `pending`, images already in them are waited on as if they'd just been saved.
+#[cfg(target_os = "linux")]
+fn add_watches(inotify: &Inotify, dir: &Path, folders: &mut HashMap<WatchDescriptor, PathBuf>, mut pending: Option<&mut Pending>) -> io::Result<()> {
+    let mask = WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO
+        | WatchMask::MOVED_FROM | WatchMask::DELETE | WatchMask::ONLYDIR | WatchMask::DONT_FOLLOW;
+    let wd = inotify.watches().add(dir, mask)?;
+    folders.insert(wd, dir.to_path_buf());
+    let Ok(entries) = fs::read_dir(dir) else { return Ok(()); };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|x| x.is_dir()) {
+            // (a folder removed in the meantime isn't an error)
+            let _ = add_watches(inotify, &path, folders, pending.as_deref_mut());
+        } else if let Some(pending) = pending.as_deref_mut() {
+            if metadata::is_image_file(&path) {
+                pending.touch(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn watch_by_polling(dir: &Path, tx: &mpsc::Sender<OutputEvent>, interval: Duration) {
+    let mut known: HashMap<PathBuf, (SystemTime, u64)> = HashMap::new();
+    scan(dir, &mut known);
+    let mut pending = Pending { images: HashMap::new() };
+    loop {
+        thread::sleep(interval);
+        let mut current = HashMap::new();
+        scan(dir, &mut current);
+        for (path, state) in &current {
+            if known.get(path) != Some(state) {
+                pending.touch(path.clone());
+            }
+        }
+        for path in known.keys().filter(|x| !current.contains_key(*x)) {
+            pending.images.remove(path);
+            if tx.send(OutputEvent::Removed(path.clone())).is_err() { return; }
+        }
+        known = current;
+        if !pending.release(tx) { return; }
+    }
+}
+
+// Every image in a folder and its subfolders, with when it was last changed and its size
+fn scan(dir: &Path, files: &mut HashMap<PathBuf, (SystemTime, u64)>) {
+    let Ok(entries) = fs::read_dir(dir) else { return; };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else { continue; };
+        if file_type.is_dir() {
+            scan(&path, files);
+        } else if metadata::is_image_file(&path) {
+            if let Ok(x) = entry.metadata() {
+                files.insert(path, (x.modified().unwrap_or(SystemTime::UNIX_EPOCH), x.len()));
+            }
+        }
+    }
+}
+
+// Whether an image has been written out in full: PNGs end with an IEND chunk, JPEGs with an end of image marker,
+// and a WebP's RIFF header says how long it is.
+fn is_complete(path: &Path) -> bool {
+    let Ok(mut file) = fs::File::open(path) else { return false; };
+    let Ok(length) = file.metadata().map(|x| x.len()) else { return false; };
+    let mut start = [0u8; 12];
+    if length < 12 || file.read_exact(&mut start).is_err() {
+        return false;
+    }
+    let mut end = [0u8; 12];
+    if file.seek(SeekFrom::End(-12)).is_err() || file.read_exact(&mut end).is_err() {
+        return false;
+    }
+    match ImageFormat::from_bytes(&start) {
+        Some(ImageFormat::Png) => end[4..12] == *b"IEND\xAE\x42\x60\x82",
+        // (some encoders pad the end)
+        Some(ImageFormat::Jpeg) => end.windows(2).any(|x| x == [0xFF, 0xD9]),
+        Some(ImageFormat::Webp) => u32::from_le_bytes(start[4..8].try_into().unwrap()) as u64 + 8 <= length,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, fixture};
+
+    // Save an image the way a slow writer would, in two halves
+    fn write_slowly(path: &Path) {
+        let bytes = fixture("no_text.png");
+        fs::write(path, &bytes[..bytes.len() / 2]).unwrap();
+        thread::sleep(SETTLE_TIME * 2);
+        fs::write(path, &bytes).unwrap();
+    }
+
+    fn check_watcher(rx: &mpsc::Receiver<OutputEvent>, dir: &Path) {
+        // Nothing is reported for a half written image, nor for files that aren't images
+        fs::write(dir.join("notes.txt"), "hi").unwrap();
+        write_slowly(&dir.join("a.png"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), OutputEvent::Added(dir.join("a.png")));
+
+        // Folders made after watching started, e.g. a new date folder
+        fs::create_dir_all(dir.join("2024-10-02/upscaled")).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        write_slowly(&dir.join("2024-10-02/upscaled/b.png"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), OutputEvent::Added(dir.join("2024-10-02/upscaled/b.png")));
+
+        fs::remove_file(dir.join("a.png")).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), OutputEvent::Removed(dir.join("a.png")));
+        assert!(rx.recv_timeout(SETTLE_TIME * 2).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watches_with_inotify() {
+        let dir = temp_dir("watch-inotify");
+        let rx = watch_output(dir.clone());
+        thread::sleep(Duration::from_millis(100));
+        check_watcher(&rx, &dir);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn watches_by_polling() {
+        let dir = temp_dir("watch-poll");
+        let (tx, rx) = mpsc::channel();
+        let watched = dir.clone();
+        thread::spawn(move || watch_by_polling(&watched, &tx, Duration::from_millis(100)));
+        thread::sleep(Duration::from_millis(200));
+        check_watcher(&rx, &dir);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn finds_the_latest_image() {
+        let dir = temp_dir("watch-latest");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("old.png"), fixture("no_text.png")).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        fs::write(dir.join("sub/new.png"), fixture("no_text.png")).unwrap();
+        fs::write(dir.join("sub/newer.txt"), "not an image").unwrap();
+        assert_eq!(latest_image(&dir), Some(dir.join("sub/new.png")));
+        assert_eq!(recent_images(&dir, 5), [dir.join("old.png"), dir.join("sub/new.png")]);
+        assert!(is_complete(&dir.join("old.png")));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,253 @@
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+use serde_json::Value;
+use tungstenite::{Message, WebSocket};
+
+// ComfyUI pushes JSON messages over its /ws endpoint: {"type": "...", "data": {...}}
+// 'status' is broadcast to every client whenever the queue changes.
+// The execution messages ('executing', 'progress', 'executed', 'execution_success', 'execution_error') are only sent
+// to the client whose client_id queued the prompt (or to everybody, if it was queued without one).
+// So are the binary preview frames of the image being sampled, which start with a 4 byte (big endian) frame type:
+//     1    PREVIEW_IMAGE                   a 4 byte image type (1 JPEG, 2 PNG), then the image
+//     4    PREVIEW_IMAGE_WITH_METADATA     a 4 byte length, that many bytes of JSON (prompt_id, node_id, ...), then the image
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Status { queue_remaining: u64 },
+    // node is None once the whole prompt has finished executing
+    Executing { prompt_id: Option<String>, node: Option<String> },
+    Progress { prompt_id: Option<String>, node: Option<String>, value: u64, max: u64 },
+    Executed { prompt_id: String, node: String, output: Value },
+    ExecutionSuccess { prompt_id: String },
+    ExecutionError { prompt_id: String, node_id: Option<String>, node_type: Option<String>, message: String },
+    // An encoded (JPEG or PNG) preview of the image being sampled
+    Preview { image: Vec<u8> },
+    // Any message type yara doesn't use (execution_start, execution_cached, ...)
+    Other(String),
+}
+
+pub struct EventClient {
+    socket: WebSocket<TcpStream>,
+}
+
+impl EventClient {
+    // ip_port is in the same form as everywhere else in yara, e.g. "http://localhost:8188/"
+    pub fn connect(ip_port: &str, client_id: &str) -> io::Result<EventClient> {
+        let Some(host) = ip_port.strip_prefix("http://")
+            else { return Err(io::Error::new(io::ErrorKind::Unsupported, format!("can't open a websocket to {ip_port} (only http:// is supported)"))); };
+        let host = host.trim_end_matches('/');
+
+        let stream = TcpStream::connect(host)?;
+        let url = format!("ws://{host}/ws?clientId={client_id}");
+        let (socket, _) = tungstenite::client(url.as_str(), stream)
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, format!("websocket handshake failed: {e}")))?;
+        Ok(EventClient { socket })
+    }
+
+    // Wait up to `timeout` for the next event. Returns Ok(None) if nothing arrived in time.
+    pub fn next_event(&mut self, timeout: Duration) -> io::Result<Option<Event>> {
+        self.socket.get_ref().set_read_timeout(Some(timeout))?;
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => {
+                    if let Some(event) = parse_event(&text) {
+                        return Ok(Some(event));
+                    }
+                }
+                Ok(Message::Binary(bytes)) => {
+                    if let Some(image) = parse_preview(&bytes) {
+                        return Ok(Some(Event::Preview { image }));
+                    }
+                }
+                Ok(Message::Close(_)) => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "ComfyUI closed the websocket"));
+                }
+                Ok(_) => (), // ping/pong
+                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(tungstenite::Error::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string())),
+            }
+        }
+    }
+}
+
+fn get_string(data: &Value, key: &str) -> Option<String> {
+    data.get(key)?.as_str().map(|x| x.to_string())
+}
+
+pub fn parse_event(text: &str) -> Option<Event> {
+    let json: Value = serde_json::from_str(text).ok()?;
+    let kind = json.get("type")?.as_str()?;
+    let data = json.get("data")?;
+    let event = match kind {
+        "status" => Event::Status {
+            queue_remaining: data.get("status")?.get("exec_info")?.get("queue_remaining")?.as_u64()?,
+        },
+        "executing" => Event::Executing {
+            prompt_id: get_string(data, "prompt_id"),
+            node: get_string(data, "node"),
+        },
+        "progress" => Event::Progress {
+            prompt_id: get_string(data, "prompt_id"),
+            node: get_string(data, "node"),
+            value: data.get("value")?.as_u64()?,
+            max: data.get("max")?.as_u64()?,
+        },
+        "executed" => Event::Executed {
+            prompt_id: get_string(data, "prompt_id")?,
+            node: get_string(data, "node")?,
+            output: data.get("output").cloned().unwrap_or(Value::Null),
+        },
+        "execution_success" => Event::ExecutionSuccess {
+            prompt_id: get_string(data, "prompt_id")?,
+        },
+        "execution_error" => Event::ExecutionError {
+            prompt_id: get_string(data, "prompt_id")?,
+            node_id: get_string(data, "node_id"),
+            node_type: get_string(data, "node_type"),
+            message: get_string(data, "exception_message").unwrap_or_default().trim().to_string(),
+        },
+        "execution_interrupted" => Event::ExecutionError {
+            prompt_id: get_string(data, "prompt_id")?,
+            node_id: get_string(data, "node_id"),
+            node_type: get_string(data, "node_type"),
+            message: "execution was interrupted".to_string(),
+        },
+        other => Event::Other(other.to_string()),
+    };
+    Some(event)
+}
+
+pub fn parse_preview(bytes: &[u8]) -> Option<Vec<u8>> {
+    let read_u32 = |at: usize| -> Option<usize> { Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?) as usize) };
+    let image = match read_u32(0)? {
+        1 => bytes.get(8..)?,
+        4 => bytes.get(8 + read_u32(4)?..)?,
+        _ => return None,
+    };
+    if image.is_empty() { None } else { Some(image.to_vec()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // Accept a single websocket connection, report the requested URI, send `messages` and close.
+    fn mock_server(messages: Vec<Message>) -> (String, mpsc::Receiver<String>, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ip_port = format!("http://{}/", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            #[allow(clippy::result_large_err)] // the error type is tungstenite's
+            let callback = |request: &tungstenite::handshake::server::Request, response| {
+                tx.send(request.uri().to_string()).unwrap();
+                Ok(response)
+            };
+            let mut socket = tungstenite::accept_hdr(stream, callback).unwrap();
+            for message in messages {
+                socket.send(message).unwrap();
+            }
+            socket.close(None).unwrap();
+            socket.flush().unwrap();
+        });
+        (ip_port, rx, handle)
+    }
+
+    fn text(json: &str) -> Message {
+        Message::Text(json.to_string())
+    }
+
+    #[test]
+    fn receives_typed_events() {
+        let (ip_port, uri, handle) = mock_server(vec![
+            text(r#"{"type": "status", "data": {"status": {"exec_info": {"queue_remaining": 2}}, "sid": "abc"}}"#),
+            text(r#"{"type": "execution_start", "data": {"prompt_id": "p1"}}"#),
+            text(r#"{"type": "executing", "data": {"node": "3", "prompt_id": "p1"}}"#),
+            Message::Binary(vec![0, 0, 0, 1, 0, 0, 0, 1, 255, 216]),
+            text(r#"{"type": "progress", "data": {"value": 5, "max": 20, "prompt_id": "p1", "node": "3"}}"#),
+            text(r#"{"type": "executed", "data": {"node": "9", "output": {"images": [{"filename": "a.png", "subfolder": "", "type": "output"}]}, "prompt_id": "p1"}}"#),
+            text(r#"{"type": "executing", "data": {"node": null, "prompt_id": "p1"}}"#),
+            text(r#"{"type": "execution_success", "data": {"prompt_id": "p1", "timestamp": 1700000000}}"#),
+            text(r#"{"type": "execution_error", "data": {"prompt_id": "p2", "node_id": "4", "node_type": "CheckpointLoaderSimple", "exception_message": "model not found\n"}}"#),
+        ]);
+
+        let mut client = EventClient::connect(&ip_port, "test-client").unwrap();
+        assert_eq!(uri.recv().unwrap(), "/ws?clientId=test-client");
+
+        let mut events = Vec::new();
+        loop {
+            match client.next_event(Duration::from_secs(5)) {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => panic!("timed out waiting for mock server"),
+                Err(_) => break,
+            }
+        }
+        handle.join().unwrap();
+
+        assert_eq!(events, vec![
+            Event::Status { queue_remaining: 2 },
+            Event::Other("execution_start".to_string()),
+            Event::Executing { prompt_id: Some("p1".to_string()), node: Some("3".to_string()) },
+            Event::Preview { image: vec![255, 216] },
+            Event::Progress { prompt_id: Some("p1".to_string()), node: Some("3".to_string()), value: 5, max: 20 },
+            Event::Executed {
+                prompt_id: "p1".to_string(),
+                node: "9".to_string(),
+                output: serde_json::json!({"images": [{"filename": "a.png", "subfolder": "", "type": "output"}]}),
+            },
+            Event::Executing { prompt_id: Some("p1".to_string()), node: None },
+            Event::ExecutionSuccess { prompt_id: "p1".to_string() },
+            Event::ExecutionError {
+                prompt_id: "p2".to_string(),
+                node_id: Some("4".to_string()),
+                node_type: Some("CheckpointLoaderSimple".to_string()),
+                message: "model not found".to_string(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn times_out_without_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ip_port = format!("http://{}/", listener.local_addr().unwrap());
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _socket = tungstenite::accept(stream).unwrap();
+            let _ = done_rx.recv(); // keep the connection open until the client is done
+        });
+
+        let mut client = EventClient::connect(&ip_port, "test-client").unwrap();
+        assert_eq!(client.next_event(Duration::from_millis(50)).unwrap(), None);
+        done_tx.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn parses_preview_frames() {
+        assert_eq!(parse_preview(&[0, 0, 0, 1, 0, 0, 0, 2, 137, 80]), Some(vec![137, 80]));
+        let metadata = br#"{"node_id": "3", "prompt_id": "p1"}"#;
+        let mut frame = vec![0, 0, 0, 4, 0, 0, 0, metadata.len() as u8];
+        frame.extend_from_slice(metadata);
+        frame.extend_from_slice(&[255, 216]);
+        assert_eq!(parse_preview(&frame), Some(vec![255, 216]));
+        // Text frames (type 3), and frames cut short
+        assert_eq!(parse_preview(&[0, 0, 0, 3, 0, 0, 0, 1, 104, 105]), None);
+        assert_eq!(parse_preview(&[0, 0, 0, 4, 0, 0, 0, 9, 123]), None);
+        assert_eq!(parse_preview(&[0, 0, 0, 1, 0, 0, 0, 1]), None);
+    }
+
+    #[test]
+    fn rejects_unusable_addresses() {
+        assert!(EventClient::connect("https://example.com/", "x").is_err());
+        assert_eq!(parse_event("not json"), None);
+        assert_eq!(parse_event(r#"{"type": "progress", "data": {}}"#), None);
+    }
+}